@@ -0,0 +1,149 @@
+use crate::codeblock::CodeBlock;
+use crate::config::PresetConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, log};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tempfile::TempDir;
+
+/// File written by the command of an `artifact` preset to `{artifact}`.
+pub struct Artifact {
+    // Deleted on drop, once the artifact is copied
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl Artifact {
+    pub fn new(preset_cfg: &PresetConfig) -> Result<Self> {
+        let dir = TempDir::new()?;
+        let extension = preset_cfg.artifact_extension.trim_start_matches('.');
+        let path = dir.path().join(format!("artifact.{extension}"));
+
+        Ok(Self { _dir: dir, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies the artifact next to the Markdown file of `block`, or only
+    /// ensures the command wrote it when `write` is false. Returns whether
+    /// the asset already held the same bytes.
+    pub fn store(&self, block: &CodeBlock, preset_cfg: &PresetConfig, write: bool) -> Result<bool> {
+        if !self.path.is_file() {
+            bail!("The command did not write its artifact to `{{artifact}}`");
+        }
+
+        let asset = asset_path(block, &asset_reference(block, preset_cfg));
+        let artifact = fs::read(&self.path)?;
+        let fresh = fs::read(&asset).is_ok_and(|stored| stored == artifact);
+        if fresh || !write {
+            return Ok(fresh);
+        }
+
+        if let Some(parent) = asset.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&self.path, &asset)
+            .with_context(|| format!("Failed to copy the artifact to `{}`", asset.display()))?;
+        debug!("Artifact written to `{}`", asset.display());
+
+        Ok(fresh)
+    }
+}
+
+/// Reference of the asset of `block`, relative to its Markdown file, e.g.
+/// `assets/README-12.png` for a block starting on line 12 of `README.md`.
+pub fn asset_reference(block: &CodeBlock, preset_cfg: &PresetConfig) -> String {
+    let stem = block
+        .path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let name = format!(
+        "{stem}-{}.{}",
+        block.start_line + 1,
+        preset_cfg.artifact_extension.trim_start_matches('.')
+    );
+
+    preset_cfg
+        .artifact_dir
+        .components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .chain(std::iter::once(name))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn asset_path(block: &CodeBlock, reference: &str) -> PathBuf {
+    block.path.parent().unwrap_or(Path::new("")).join(reference)
+}
+
+/// Returns the block followed by a reference to its asset when the reference
+/// is missing or stale, or an error in check mode. `fresh` tells whether the
+/// stored asset matches the artifact the command just produced.
+pub fn reference_block(
+    preset: &str,
+    preset_cfg: &PresetConfig,
+    block: &CodeBlock,
+    fresh: bool,
+    check_only: bool,
+) -> Result<Option<CodeBlock>> {
+    let reference = asset_reference(block, preset_cfg);
+    if block.image.as_deref() == Some(reference.as_str()) {
+        if check_only && !fresh {
+            let problem = if asset_path(block, &reference).is_file() {
+                "Stale"
+            } else {
+                "Missing"
+            };
+            let msg = format!(
+                "{problem} artifact `{reference}` for `{}:{}-{}` (preset: `{preset}`)",
+                block.path.display(),
+                block.start_line,
+                block.end_line
+            );
+            log!(preset_cfg.severity.log_level(), "{msg}");
+            return Err(anyhow!(msg));
+        }
+        return Ok(None);
+    }
+
+    if check_only {
+        let msg = format!(
+            "Missing reference to the artifact `{reference}` after `{}:{}-{}` (preset: `{preset}`)",
+            block.path.display(),
+            block.start_line,
+            block.end_line
+        );
+        log!(preset_cfg.severity.log_level(), "{msg}");
+        return Err(anyhow!(msg));
+    }
+
+    info!(
+        "Reference to the artifact `{reference}` will be updated in `{}`",
+        block.path.display()
+    );
+
+    // A previous reference to the assets directory is replaced, e.g. after the
+    // block moved to another line
+    let directory = reference.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let stale = block
+        .image
+        .as_deref()
+        .is_some_and(|image| image.rsplit_once('/').map_or("", |(dir, _)| dir) == directory);
+
+    let code = format!(
+        "{}\n\n{:indent$}![{preset}]({reference})",
+        block.fenced(&block.code),
+        "",
+        indent = block.indent
+    );
+
+    Ok(Some(CodeBlock {
+        code,
+        end_line: block.end_line + if stale { 2 } else { 0 },
+        ..block.clone()
+    }))
+}
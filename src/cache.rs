@@ -0,0 +1,90 @@
+use crate::environment::EnvSnapshot;
+use crate::report::Timing;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const TIMINGS_FILE: &str = "timings.json";
+const ENVIRONMENTS_FILE: &str = "environments.json";
+
+fn load_json<T: DeserializeOwned + Default>(dir: &Path, file: &str) -> Result<T> {
+    let path = dir.join(file);
+    if !path.exists() {
+        return Ok(T::default());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse cache file: {}", path.display()))
+}
+
+fn save_json<T: Serialize>(dir: &Path, file: &str, value: &T) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    // Write atomically, concurrent runs may share the same cache directory
+    let tmp = tempfile::NamedTempFile::new_in(dir)?;
+    fs::write(tmp.path(), serde_json::to_string_pretty(value)?)?;
+    tmp.persist(dir.join(file))?;
+
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PresetTiming {
+    pub runs: u64,
+    pub total_micros: u64,
+}
+
+impl PresetTiming {
+    pub fn average(&self) -> Option<Duration> {
+        (self.runs > 0).then(|| Duration::from_micros(self.total_micros / self.runs))
+    }
+}
+
+/// Command durations recorded during previous runs, aggregated per preset.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TimingCache {
+    pub presets: BTreeMap<String, PresetTiming>,
+}
+
+impl TimingCache {
+    pub fn load(dir: &Path) -> Result<Self> {
+        load_json(dir, TIMINGS_FILE)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        save_json(dir, TIMINGS_FILE, self)
+    }
+
+    pub fn record(&mut self, timings: &[Timing]) {
+        for timing in timings {
+            let entry = self.presets.entry(timing.preset.clone()).or_default();
+            entry.runs += 1;
+            entry.total_micros += timing.duration.as_micros() as u64;
+        }
+    }
+
+    pub fn average(&self, preset: &str) -> Option<Duration> {
+        self.presets.get(preset).and_then(PresetTiming::average)
+    }
+}
+
+/// Environment of the commands of each preset during its last successful run.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct EnvCache {
+    pub presets: BTreeMap<String, EnvSnapshot>,
+}
+
+impl EnvCache {
+    pub fn load(dir: &Path) -> Result<Self> {
+        load_json(dir, ENVIRONMENTS_FILE)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        save_json(dir, ENVIRONMENTS_FILE, self)
+    }
+}
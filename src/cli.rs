@@ -1,32 +1,506 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(
-    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true,
     name = "Markdown Code Runner",
     version = clap::crate_version!(),
     author = clap::crate_authors!(),
     about = clap::crate_description!()
 )]
 pub struct Cli {
-    /// Path to the Markdown file or directory
-    #[arg(required = true)]
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    /// Run in check mode (do not modify the Markdown file), as `mdcr check`
+    #[arg(long, conflicts_with = "interactive")]
+    pub check: bool,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Log level (error, warn, info, debug, trace), `warn` unless the config sets it
+    #[arg(long, global = true, value_parser = ["error", "warn", "info", "debug", "trace"])]
+    pub log: Option<String>,
+
+    /// Format of the log lines (text, json), `json` writing one object per line
+    #[arg(long, global = true, default_value = "text", value_parser = ["text", "json"])]
+    pub log_format: String,
+
+    /// Also write the `trace` log to this file, whatever the log level of stderr
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// When to color the output (auto, always, never), `auto` honoring `NO_COLOR`
+    #[arg(long, global = true, default_value = "auto", value_parser = ["auto", "always", "never"])]
+    pub color: String,
+
+    /// More verbose logs: `-v` for `info`, `-vv` for `debug`, `-vvv` for `trace`
+    #[arg(long, short = 'v', global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only log errors
+    #[arg(long, short = 'q', global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Apply all presets to a Markdown document and print the result, or apply
+    /// a previously generated patch
+    Apply(ApplyArgs),
+    /// Check Markdown files without modifying them
+    Check(Box<CheckArgs>),
+    /// Print the completion script of a shell
+    Completions(CompletionsArgs),
+    /// Keep the config loaded and process the documents sent on a socket, for editors and build systems
+    #[cfg(unix)]
+    Daemon(DaemonArgs),
+    /// Inspect the config, the programs of the presets, the target paths and the cache, printing actionable findings
+    Doctor(DoctorArgs),
+    /// Print the presets each code block would run, with their expanded commands, without running any command
+    Explain(ExplainArgs),
+    /// Manage the code blocks exempted from presets in the config
+    Ignore(IgnoreArgs),
+    /// Run the blocks of a single preset and list the files that would change, e.g. before upgrading its tool
+    Impact(ImpactArgs),
+    /// List the fenced code blocks of Markdown files, as a table or JSON
+    ListBlocks(ListBlocksArgs),
+    /// Print the manual page, or write the pages of all the subcommands to a directory
+    Man(ManArgs),
+    /// Run a language server on stdin and stdout, publishing mismatching blocks as diagnostics
+    Lsp(LspArgs),
+    /// Review the mismatching blocks in a terminal UI, applying or skipping each of them
+    Review(ReviewArgs),
+    /// Run the presets on Markdown files and update them, the default without subcommand
+    Run(Box<RunArgs>),
+    /// Print the JSON Schema of the config files, for editors to validate and complete them
+    Schema,
+    /// Validate the config and list the code blocks the presets would run on, without running any command
+    Validate(ValidateArgs),
+    /// Check the presets of the config: their programs, placeholders and languages, without reading any Markdown file
+    ValidateConfig(ValidateConfigArgs),
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// Path to the Markdown file or directory, `-` to read a document from stdin
+    #[arg(default_value = ".")]
     pub paths: Vec<std::path::PathBuf>,
 
-    /// Path to the config JSON file
+    /// Path to the config file, repeatable with the later files overriding or adding presets,
+    /// looked for upward from each path when omitted
+    #[arg(long)]
+    pub config: Vec<PathBuf>,
+
+    /// Profile of the config overriding its settings and presets, e.g. `ci`
+    #[arg(long, env = "MDCR_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Override a value of the config by its dotted key (e.g. `--set settings.jobs=2`), can be repeated
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub set: Vec<(String, String)>,
+
+    /// Check mode, set by `--check` without subcommand or by `mdcr check`
+    #[arg(skip)]
+    pub check: bool,
+
+    /// Only check the Markdown files of PATH, even without `--check`, can be repeated
+    #[arg(long, value_name = "PATH")]
+    pub check_paths: Vec<PathBuf>,
+
+    /// Update the Markdown files of PATH, even with `--check`, can be repeated
+    #[arg(long, value_name = "PATH", conflicts_with = "annotate_only")]
+    pub write_paths: Vec<PathBuf>,
+
+    /// Check all the files as with `--check`, even the write paths: files are never modified
+    #[arg(long, env = "MDCR_FORCE_CHECK", value_parser = clap::builder::FalseyValueParser::new())]
+    pub force_check: bool,
+
+    /// Never modify files, print GitHub Actions annotations and write a job summary to `GITHUB_STEP_SUMMARY`
+    #[arg(long, conflicts_with_all = ["stdout", "output_dir", "dry_run", "tui"])]
+    pub annotate_only: bool,
+
+    /// Metadata embedded into the reports (e.g. `--meta git_sha=abc123`), can be repeated
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub meta: Vec<(String, String)>,
+
+    /// Only process the code blocks of these languages (e.g. `sh,python`)
+    #[arg(long = "language", value_name = "LANGUAGES", value_delimiter = ',')]
+    pub languages: Vec<String>,
+
+    /// Only process the code blocks overlapping the lines START to END (e.g. `120:160`)
+    #[arg(long, value_name = "START:END", value_parser = parse_line_range)]
+    pub lines: Option<(usize, usize)>,
+
+    /// Only run the preset NAME, can be repeated
+    #[arg(long = "preset", value_name = "NAME")]
+    pub presets: Vec<String>,
+
+    /// Run the preset NAME even if it is disabled in the config, can be repeated
+    #[arg(long = "enable-preset", value_name = "NAME")]
+    pub enable_presets: Vec<String>,
+
+    /// Do not run the preset NAME, can be repeated
+    #[arg(long = "disable-preset", value_name = "NAME")]
+    pub disable_presets: Vec<String>,
+
+    /// Only run the presets of these kinds (formatter, executor, checker), e.g. where executing code is not allowed
+    #[arg(long, value_name = "KINDS", value_delimiter = ',', value_parser = ["formatter", "executor", "checker"])]
+    pub only_kind: Vec<String>,
+
+    /// Write the progress of every code block to FILE as JSON Lines
+    #[arg(long, value_name = "FILE")]
+    pub events: Option<PathBuf>,
+
+    /// Include the lines replacing updated and mismatching blocks in the `--events` log
+    #[arg(long, requires = "events")]
+    pub include_content: bool,
+
+    /// Report format printed on stdout (text, checkstyle)
+    #[arg(long, default_value = "text", value_parser = ["text", "checkstyle"])]
+    pub format: String,
+
+    /// Print the processed Markdown on stdout instead of modifying the files
+    #[arg(long)]
+    pub stdout: bool,
+
+    /// Write the processed Markdown files into DIR, mirroring their relative paths, instead of modifying them
+    #[arg(long, value_name = "DIR", conflicts_with = "stdout")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Stop running commands once N of them were started, the remaining blocks are reported as skipped
+    #[arg(long, value_name = "N")]
+    pub max_commands: Option<usize>,
+
+    /// Stop running commands once the run lasted this long (e.g. `90s`, `10m`), the remaining blocks are reported as skipped
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub max_total_runtime: Option<std::time::Duration>,
+
+    /// Succeed in check mode even when the quotas left blocks unchecked
+    #[arg(long)]
+    pub allow_partial_check: bool,
+
+    /// Kill the commands running longer than this (e.g. `30s`), for the presets without a `timeout`
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub timeout: Option<std::time::Duration>,
+
+    /// Show the diff of each mismatching block and ask whether to apply it
+    #[arg(long, conflicts_with_all = ["annotate_only", "tui", "watch"])]
+    pub interactive: bool,
+
+    /// Compute all the replacements without modifying any file
+    #[arg(long, conflicts_with_all = ["stdout", "output_dir"])]
+    pub dry_run: bool,
+
+    /// Write the replacements computed by `--dry-run` to FILE as a unified patch, instead of printing it
+    #[arg(long, value_name = "FILE", requires = "dry_run")]
+    pub patch: Option<PathBuf>,
+
+    /// Scheduling of the commands: file by file, or grouped by preset across all files
+    #[arg(long, default_value = "file", value_parser = ["file", "preset"])]
+    pub schedule: String,
+
+    /// Show a live dashboard of the code blocks while the run progresses
+    #[arg(long, conflicts_with_all = ["stdout", "estimate"])]
+    pub tui: bool,
+
+    /// Only process the Markdown files staged in git, and stage them again once updated (e.g. in a pre-commit hook)
+    #[arg(long, conflicts_with = "watch")]
+    pub staged: bool,
+
+    /// Only process the Markdown files changed in git since a revision (e.g. `origin/main`)
+    #[arg(long, value_name = "REF", conflicts_with_all = ["watch", "staged"])]
+    pub changed_since: Option<String>,
+
+    /// Keep running and process the Markdown files again whenever they or the config change
+    #[arg(long, conflicts_with_all = ["tui", "estimate", "annotate_only", "patch", "stdout"])]
+    pub watch: bool,
+
+    /// Extensions of the Markdown files to process in directories [default: md]
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub ext: Option<Vec<String>>,
+
+    /// Skip the files and directories matching PATTERN (e.g. `node_modules`, `docs/vendor/*`), can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Number of files and commands processed concurrently [default: number of CPUs]
+    #[arg(long, short = 'j', value_name = "N")]
+    pub jobs: Option<std::num::NonZeroUsize>,
+
+    /// Stop processing at the first command failure or mismatch
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Fail when a `mdcr-skip` marker does not give a `reason`
+    #[arg(long)]
+    pub forbid_unexplained_skips: bool,
+
+    /// Record the environment of each command and diff it against the last successful run on failures
+    #[arg(long)]
+    pub debug_env: bool,
+
+    /// Predict the duration of the run from previous timings, without running anything
+    #[arg(long)]
+    pub estimate: bool,
+
+    /// Record how long the commands of each preset took in the cache directory, for `--estimate`
+    #[arg(long, conflicts_with = "estimate")]
+    pub record_timings: bool,
+
+    /// Skip the presets whose program is not installed, instead of failing
+    #[arg(long)]
+    pub allow_missing_tools: bool,
+
+    /// Fail when a code block with a language has no preset to run on it
+    #[arg(long)]
+    pub error_on_unmatched: bool,
+
+    /// Print the slowest presets and blocks on stderr at the end of the run
+    #[arg(long)]
+    pub timings: bool,
+
+    /// Directory where data about previous runs is stored
+    #[arg(long, default_value = ".mdcr-cache")]
+    pub cache_dir: PathBuf,
+
+    /// Root of the project for the `{repo_root}` and `{relpath}` placeholders [default: closest directory holding `.git`]
+    #[arg(long, value_name = "DIR")]
+    pub project_root: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ApplyArgs {
+    /// Patch generated with `--dry-run --patch` to apply to the Markdown files
+    #[arg(required_unless_present = "stdin", conflicts_with_all = ["stdin", "config"])]
+    pub patch: Option<PathBuf>,
+
+    /// Read the Markdown document from stdin and print the result on stdout
+    #[arg(long)]
+    pub stdin: bool,
+
+    /// Path to the config file
+    #[arg(long, required_if_eq("stdin", "true"))]
+    pub config: Option<PathBuf>,
+
+    /// Root of the project the paths of the patch are relative to [default: closest directory holding `.git`]
+    #[arg(long, value_name = "DIR", conflicts_with = "stdin")]
+    pub project_root: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Read the Markdown files from a git revision (e.g. a tag) instead of the working tree
+    #[arg(
+        long,
+        value_name = "REF",
+        conflicts_with_all = ["watch", "staged", "changed_since", "schedule"]
+    )]
+    pub at: Option<String>,
+}
+
+#[cfg(unix)]
+#[derive(Args)]
+pub struct DaemonArgs {
+    /// Path to the config file
     #[arg(long)]
     pub config: PathBuf,
 
-    /// Run in check mode (do not modify the Markdown file)
+    /// Unix socket the requests are read from
+    #[arg(long, default_value = ".mdcr-cache/daemon.sock")]
+    pub socket: PathBuf,
+}
+
+#[derive(Args)]
+pub struct CompletionsArgs {
+    /// Shell the completion script is written for
+    pub shell: clap_complete::Shell,
+}
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// Paths the Markdown files are processed from
+    #[arg(default_value = ".")]
+    pub paths: Vec<PathBuf>,
+
+    /// Path to the config file, looked for in the current directory and its ancestors when omitted
     #[arg(long)]
-    pub check: bool,
+    pub config: Option<PathBuf>,
+
+    /// Directory where data about previous runs is stored
+    #[arg(long, default_value = ".mdcr-cache")]
+    pub cache_dir: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ListBlocksArgs {
+    /// Path to the Markdown file or directory
+    #[arg(default_value = ".")]
+    pub paths: Vec<PathBuf>,
+
+    /// Extensions of the Markdown files to process in directories [default: md]
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub ext: Option<Vec<String>>,
+
+    /// Skip the files and directories matching PATTERN, can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Output format (table, json)
+    #[arg(long, default_value = "table", value_parser = ["table", "json"])]
+    pub format: String,
+}
+
+#[derive(Args)]
+pub struct ManArgs {
+    /// Write the pages of the command and all its subcommands to DIR
+    #[arg(long, value_name = "DIR")]
+    pub out_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct LspArgs {
+    /// Path to the config file
+    #[arg(long)]
+    pub config: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ReviewArgs {
+    /// Path to the Markdown file or directory
+    #[arg(default_value = ".")]
+    pub paths: Vec<PathBuf>,
+
+    /// Path to the config file
+    #[arg(long)]
+    pub config: PathBuf,
+
+    /// Skip the files and directories matching PATTERN, can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct ValidateArgs {
+    /// Path to the Markdown file or directory
+    #[arg(default_value = ".")]
+    pub paths: Vec<PathBuf>,
+
+    /// Path to the config file
+    #[arg(long)]
+    pub config: PathBuf,
+
+    /// Extensions of the Markdown files to process in directories [default: md]
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub ext: Option<Vec<String>>,
 
-    /// Log level (error, warn, info, debug, trace)
-    #[arg(long, default_value = "warn", value_parser = ["error", "warn", "info", "debug", "trace"])]
-    pub log: String,
+    /// Skip the files and directories matching PATTERN, can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct ExplainArgs {
+    /// Path to the Markdown file or directory
+    #[arg(default_value = ".")]
+    pub paths: Vec<PathBuf>,
 
-    /// Verbose mode (set the log level to `trace`)
+    /// Path to the config file
     #[arg(long)]
-    pub verbose: bool,
+    pub config: PathBuf,
+
+    /// Extensions of the Markdown files to process in directories [default: md]
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub ext: Option<Vec<String>>,
+
+    /// Skip the files and directories matching PATTERN, can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Root of the project for the `{repo_root}` and `{relpath}` placeholders [default: closest directory holding `.git`]
+    #[arg(long, value_name = "DIR")]
+    pub project_root: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ValidateConfigArgs {
+    /// Path to the config file
+    #[arg(long)]
+    pub config: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ImpactArgs {
+    /// Path to the Markdown file or directory
+    #[arg(default_value = ".")]
+    pub paths: Vec<PathBuf>,
+
+    /// Path to the config file
+    #[arg(long)]
+    pub config: PathBuf,
+
+    /// Preset whose blocks are run
+    #[arg(long)]
+    pub preset: String,
+
+    /// Skip the files and directories matching PATTERN, can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct IgnoreArgs {
+    #[command(subcommand)]
+    pub command: IgnoreCommands,
+}
+
+#[derive(Subcommand)]
+pub enum IgnoreCommands {
+    /// Exempt the code block at a location from presets, by adding an `[[ignore]]` entry to the config
+    Add(IgnoreAddArgs),
+}
+
+#[derive(Args)]
+pub struct IgnoreAddArgs {
+    /// Location of the code block, as `<file>:<line>`
+    #[arg(value_name = "FILE:LINE")]
+    pub location: String,
+
+    /// Path to the config file the entry is added to
+    #[arg(long)]
+    pub config: PathBuf,
+
+    /// Preset the block is exempted from, can be repeated [default: all presets]
+    #[arg(long = "preset", value_name = "PRESET")]
+    pub presets: Vec<String>,
+}
+
+/// Parses `START:END`, 1-based and inclusive, either bound being optional.
+fn parse_line_range(s: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("expected START:END line numbers, got `{s}`");
+    let (start, end) = s.split_once(':').ok_or_else(invalid)?;
+    let bound = |bound: &str, default| match bound.trim() {
+        "" => Ok(default),
+        bound => bound.parse::<usize>().map_err(|_| invalid()),
+    };
+    let range = (bound(start, 1)?, bound(end, usize::MAX)?);
+
+    if range.0 > range.1 {
+        return Err(format!("the range `{s}` ends before it starts"));
+    }
+    Ok(range)
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got `{s}`")),
+    }
 }
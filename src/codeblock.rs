@@ -1,15 +1,86 @@
+use crate::ignore::block_hash;
+use crate::report::{CommandFailure, Mismatch, SkippedBlock, Timing};
 use pulldown_cmark::{CodeBlockKind, Event, Parser as MdParser, Tag, TagEnd};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
+const SKIP_MARKER: &str = "mdcr-skip";
+
+/// Metadata attached to a `mdcr-skip` marker, e.g.
+/// `mdcr-skip reason="flaky upstream" issue="#123"`.
+#[derive(Debug, Clone, Default)]
+pub struct SkipMarker {
+    pub reason: Option<String>,
+    pub issue: Option<String>,
+}
+
+impl SkipMarker {
+    fn parse(headers: &str) -> Option<Self> {
+        let (_, metadata) = headers.split_once(SKIP_MARKER)?;
+        let mut attributes = parse_attributes(metadata);
+
+        Some(Self {
+            reason: attributes.remove("reason").filter(|r| !r.trim().is_empty()),
+            issue: attributes.remove("issue").filter(|i| !i.trim().is_empty()),
+        })
+    }
+}
+
+/// Parses `key="quoted value"` and `key=value` pairs separated by whitespace.
+fn parse_attributes(s: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut chars = s.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let key: String =
+            std::iter::from_fn(|| chars.next_if(|c| *c != '=' && !c.is_whitespace())).collect();
+        if key.is_empty() && chars.peek().is_none() {
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            continue;
+        }
+
+        let value: String = if chars.next_if_eq(&'"').is_some() {
+            let value = std::iter::from_fn(|| chars.next_if(|c| *c != '"')).collect();
+            chars.next();
+            value
+        } else {
+            std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect()
+        };
+
+        attributes.insert(key, value);
+    }
+
+    attributes
+}
+
 #[derive(Debug, Clone)]
 pub struct CodeBlock {
     pub path: PathBuf,
     pub lang: String,
     pub headers: String,
     pub code: String,
+    /// Code of the block in the document, which `code` differs from once
+    /// replaced by the output of chained presets
+    pub original_code: String,
     pub start_line: usize,
     pub end_line: usize,
     pub indent: usize,
+    pub skip: Option<SkipMarker>,
+    /// Classes of a Pandoc attribute block (`{.python .numberLines #ex1}`),
+    /// without their leading `.`
+    pub classes: Vec<String>,
+    /// Identifier of a Pandoc attribute block, without its leading `#`
+    pub id: Option<String>,
+    /// Identifier of the block within its file which, unlike line numbers,
+    /// survives edits around the block, see `assign_block_ids`
+    pub block_id: String,
+    /// Target of the image reference following the block after a blank line,
+    /// as inserted by the `artifact` output mode
+    pub image: Option<String>,
 }
 
 impl CodeBlock {
@@ -19,35 +90,56 @@ impl CodeBlock {
             ..self.clone()
         }
     }
+
+    /// The fenced block, with its headers and indentation, holding `code`.
+    pub fn fenced(&self, code: &str) -> String {
+        std::iter::once(format!("```{}", self.headers))
+            .chain(code.lines().map(|l| l.to_string()))
+            .chain(std::iter::once("```".to_string()))
+            .map(|l| {
+                format!("{:indent$}{}", "", l, indent = self.indent)
+                    .trim_end()
+                    .to_string()
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
 }
 
+#[derive(Default)]
 pub struct CodeBlockProcessingResult {
     pub replacements: Vec<CodeBlock>,
+    pub mismatches: Vec<Mismatch>,
+    pub failures: Vec<CommandFailure>,
+    pub timings: Vec<Timing>,
+    pub skipped: Vec<SkippedBlock>,
     pub had_command_failure: bool,
     pub had_mismatch: bool,
+    /// Code of the block once replaced by a preset, which the next presets
+    /// run on
+    pub output: Option<String>,
+}
+
+impl CodeBlockProcessingResult {
+    pub fn merge(&mut self, other: CodeBlockProcessingResult) {
+        self.replacements.extend(other.replacements);
+        self.mismatches.extend(other.mismatches);
+        self.failures.extend(other.failures);
+        self.timings.extend(other.timings);
+        self.skipped.extend(other.skipped);
+        self.had_command_failure |= other.had_command_failure;
+        self.had_mismatch |= other.had_mismatch;
+    }
 }
 
 pub fn parse_code_blocks(path: &Path, content: &str) -> Vec<CodeBlock> {
     let mut blocks = Vec::new();
+    let lines: Vec<&str> = content.lines().collect();
     let mut parser = MdParser::new(content).into_offset_iter();
 
     while let Some((event, range)) = parser.next() {
         if let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(headers))) = event {
-            if headers.contains("mdcr-skip") {
-                // We need to consume until the end of this block
-                for (e, _) in &mut parser {
-                    if let Event::End(TagEnd::CodeBlock) = e {
-                        break;
-                    }
-                }
-                continue;
-            }
-
-            let lang = headers
-                .split_whitespace()
-                .next()
-                .unwrap_or_default()
-                .to_string();
+            let (lang, classes, id) = parse_info(&headers);
 
             let mut code = String::new();
             let start_offset = range.start;
@@ -67,32 +159,114 @@ pub fn parse_code_blocks(path: &Path, content: &str) -> Vec<CodeBlock> {
                 }
             }
 
-            // Calculate lines
-            let start_line = content[..start_offset].lines().count();
-            let end_line = content[..end_offset].lines().count();
+            // Lines are counted from the newlines, so that the last line of a
+            // document without final newline is not special
+            let start_line = line_of(content, start_offset);
+            let end_line = line_of(content, end_offset.saturating_sub(1).max(start_offset)) + 1;
 
-            // Calculate indentation
-            let indent: usize = content
-                .get(..start_offset)
-                .and_then(|s| s.lines().last())
-                .unwrap_or("")
+            // Indentation of the opening fence, whether or not the offset
+            // includes it
+            let line_start = content[..start_offset].rfind('\n').map_or(0, |i| i + 1);
+            let indent = content[line_start..]
                 .chars()
-                .take_while(|c| c.is_whitespace())
+                .take_while(|c| *c == ' ' || *c == '\t')
                 .count();
 
-            // Correction for 1-based indexing expectations if any, or just consistent logic
-            let start_line = start_line - (indent > 0) as usize;
-
             blocks.push(CodeBlock {
                 path: path.to_path_buf(),
                 lang,
                 headers: headers.to_string(),
+                original_code: code.clone(),
                 code,
                 start_line,
                 end_line,
                 indent,
+                skip: SkipMarker::parse(&headers),
+                classes,
+                id,
+                block_id: String::new(),
+                image: image_after(&lines, end_line),
             });
         }
     }
+
+    assign_block_ids(&mut blocks);
     blocks
 }
+
+/// Identifies each block by its name (`#id` Pandoc attribute or `name=...`
+/// header attribute), or else by the hash of its normalized content. Blocks
+/// sharing a name or content are numbered in order of appearance.
+fn assign_block_ids(blocks: &mut [CodeBlock]) {
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+
+    for block in blocks {
+        let key = match block
+            .id
+            .clone()
+            .or_else(|| parse_attributes(&block.headers).remove("name"))
+        {
+            Some(name) => name,
+            None => {
+                let normalized: Vec<&str> = block.code.trim().lines().map(str::trim_end).collect();
+                let content = format!("{}\n{}", block.lang, normalized.join("\n"));
+                format!("sha-{}", block_hash(&content))
+            }
+        };
+
+        let occurrence = occurrences.entry(key.clone()).or_default();
+        *occurrence += 1;
+        block.block_id = match *occurrence {
+            1 => key,
+            n => format!("{key}-{n}"),
+        };
+    }
+}
+
+/// 0-based line holding the byte at `offset`.
+fn line_of(content: &str, offset: usize) -> usize {
+    content.as_bytes()[..offset]
+        .iter()
+        .filter(|&&byte| byte == b'\n')
+        .count()
+}
+
+/// Language, classes and identifier of the info string of a fence. In Pandoc
+/// attribute blocks (`{.python .numberLines #ex1}`), the language is the first
+/// class.
+fn parse_info(headers: &str) -> (String, Vec<String>, Option<String>) {
+    let Some(attributes) = headers
+        .trim()
+        .strip_prefix('{')
+        .and_then(|rest| rest.split_once('}'))
+        .map(|(attributes, _)| attributes)
+    else {
+        let lang = headers.split_whitespace().next().unwrap_or_default();
+        return (lang.to_string(), Vec::new(), None);
+    };
+
+    let mut classes = Vec::new();
+    let mut id = None;
+    for attribute in attributes.split_whitespace() {
+        if let Some(class) = attribute.strip_prefix('.') {
+            classes.push(class.to_string());
+        } else if let Some(identifier) = attribute.strip_prefix('#') {
+            id = Some(identifier.to_string());
+        }
+    }
+    let lang = classes.first().cloned().unwrap_or_default();
+
+    (lang, classes, id)
+}
+
+/// Target of an image reference (`![alt](target)`) alone on the second line
+/// after `end_line`, the first one being blank.
+fn image_after(lines: &[&str], end_line: usize) -> Option<String> {
+    if !lines.get(end_line)?.trim().is_empty() {
+        return None;
+    }
+
+    let reference = lines.get(end_line + 1)?.trim();
+    let (_, target) = reference.strip_prefix("![")?.split_once("](")?;
+    target.strip_suffix(')').map(String::from)
+}
@@ -0,0 +1,69 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const RED: &str = "31";
+pub const GREEN: &str = "32";
+pub const YELLOW: &str = "33";
+pub const CYAN: &str = "36";
+
+static STDOUT: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Applies the `--color` policy: `auto` colors the terminals, unless the
+/// `NO_COLOR` environment variable is set.
+pub fn init(choice: &str) {
+    let auto = std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    let (stdout, stderr) = match choice {
+        "always" => (true, true),
+        "never" => (false, false),
+        _ => (
+            auto && std::io::stdout().is_terminal(),
+            auto && std::io::stderr().is_terminal(),
+        ),
+    };
+
+    STDOUT.store(stdout, Ordering::Relaxed);
+    STDERR.store(stderr, Ordering::Relaxed);
+}
+
+pub fn stdout() -> bool {
+    STDOUT.load(Ordering::Relaxed)
+}
+
+pub fn stderr() -> bool {
+    STDERR.load(Ordering::Relaxed)
+}
+
+/// The unified `diff` with its added lines in green, its removed lines in red
+/// and its hunk headers in cyan, when `enabled`.
+pub fn diff(diff: &str, enabled: bool) -> String {
+    if !enabled {
+        return diff.to_string();
+    }
+
+    diff.split_inclusive('\n')
+        .map(|line| {
+            let (text, newline) = line.split_at(line.trim_end_matches('\n').len());
+            let color = match text.as_bytes().first() {
+                _ if text.starts_with("---") || text.starts_with("+++") => None,
+                Some(b'+') => Some(GREEN),
+                Some(b'-') => Some(RED),
+                Some(b'@') => Some(CYAN),
+                _ => None,
+            };
+            match color {
+                Some(color) => paint(text, color, true) + newline,
+                None => line.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// `text` in the ANSI `color`, when `enabled`.
+pub fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{color}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
@@ -1,87 +1,453 @@
-use log::debug;
+use anyhow::Context;
+use log::{debug, warn};
 use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Output, Stdio};
-use tempfile::NamedTempFile;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tempfile::{NamedTempFile, TempDir};
+use wait_timeout::ChildExt;
+use walkdir::WalkDir;
 
-use crate::config::{InputMode, PresetConfig};
+use crate::config::{InputMode, PresetConfig, Shell};
 
 pub fn run_command(
     cfg: &PresetConfig,
     input: &str,
     lang: &str,
+    artifact: Option<&Path>,
+    project: &ProjectPaths,
 ) -> anyhow::Result<(Command, Output)> {
-    match cfg.input_mode {
-        InputMode::Stdin => run_command_with_stdin(&cfg.command, input, lang),
-        InputMode::File => run_command_with_file(&cfg.command, input, lang),
+    // Kept alive until the command exits, the directories are deleted on drop
+    let workspace = prepare_workspace(&cfg.fixtures)?;
+    // A directory per execution, so that blocks running in parallel never
+    // collide on files with fixed names
+    let tmpdir = if cfg
+        .stages()
+        .flatten()
+        .chain(cfg.fallback_command.iter().flatten())
+        .any(|arg| arg.contains("{tmpdir}"))
+    {
+        Some(TempDir::new()?)
+    } else {
+        None
+    };
+    let context = Placeholders {
+        lang,
+        workspace: workspace.as_ref().map(TempDir::path),
+        tmpdir: tmpdir.as_ref().map(TempDir::path),
+        artifact,
+        project,
+        code: None,
+        shell: &cfg.shell,
+    };
+
+    let mut stages = cfg.stages();
+    let first = stages.next().context("The preset has no command")?;
+    let (mut cmd, mut output) = match cfg.input_mode {
+        InputMode::Stdin => run_command_with_stdin(cfg, first, input.as_bytes(), &context)?,
+        InputMode::File => run_command_with_file(cfg, first, input, &context)?,
+        InputMode::Arg => run_command_with_arg(cfg, first, input, &context)?,
+    };
+
+    // The next commands of a pipeline read the output of the previous one,
+    // until one of them fails
+    for command in stages {
+        if !output.status.success() {
+            break;
+        }
+        let input = std::mem::take(&mut output.stdout);
+        (cmd, output) = run_command_with_stdin(cfg, command, &input, &context)?;
     }
+
+    Ok((cmd, output))
 }
 
-fn run_command_with_stdin(
-    command_template: &[String],
-    input: &str,
+/// Runs the `before` or `after` hook of a preset, failing when it does.
+pub fn run_hook(cfg: &PresetConfig, hook: &[String]) -> anyhow::Result<()> {
+    let Some(program) = hook.first() else {
+        return Ok(());
+    };
+
+    let mut cmd = new_command(hook, cfg, None);
+    debug!("Executing hook {hook:?}");
+    let output = execute(&mut cmd, None, cfg.timeout)
+        .with_context(|| format!("Failed to execute `{program}`"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "The command `{}` returned a non-zero exit status ({}): {}",
+            command_to_string(&cmd),
+            output.status.code().unwrap_or(-1),
+            cfg.decode_output(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Command line a preset would run on a block, without running it. Values only
+/// known when running, e.g. temporary files, are shown as `<file>`, `<tmpdir>`.
+pub fn explain_command(
+    cfg: &PresetConfig,
+    code: &str,
     lang: &str,
-) -> anyhow::Result<(Command, Output)> {
-    let args = expand_command_vec(command_template, None, lang);
+    project: &ProjectPaths,
+) -> Vec<Vec<String>> {
+    let context = Placeholders {
+        lang,
+        workspace: (!cfg.fixtures.is_empty()).then(|| Path::new("<workspace>")),
+        tmpdir: Some(Path::new("<tmpdir>")),
+        artifact: Some(Path::new("<artifact>")),
+        project,
+        code: matches!(cfg.input_mode, InputMode::Arg).then_some(code),
+        shell: &cfg.shell,
+    };
+    let file = matches!(cfg.input_mode, InputMode::File).then(|| Path::new("<file>"));
+    let mut stages = cfg.stages();
+    let first = stages
+        .next()
+        .map(|command| expand_command_vec(command, file, &context));
+
+    // The next commands of a pipeline only read the output of the previous one
+    let context = Placeholders {
+        code: None,
+        ..context
+    };
+    first
+        .into_iter()
+        .chain(stages.map(|command| expand_command_vec(command, None, &context)))
+        .collect()
+}
+
+/// Values of the placeholders of a command.
+#[derive(Clone, Copy)]
+struct Placeholders<'a> {
+    lang: &'a str,
+    workspace: Option<&'a Path>,
+    tmpdir: Option<&'a Path>,
+    /// File the command writes in `artifact` output mode
+    artifact: Option<&'a Path>,
+    project: &'a ProjectPaths,
+    /// Code of the block, only passed as argument in `arg` input mode
+    code: Option<&'a str>,
+    /// Shell the values are quoted for, when the command is a script
+    shell: &'a Shell,
+}
+
+/// Location of the Markdown file of a block in its project, for the
+/// `{repo_root}` and `{relpath}` placeholders.
+pub struct ProjectPaths {
+    pub root: PathBuf,
+    pub relpath: PathBuf,
+}
+
+impl ProjectPaths {
+    /// The project of `file` is rooted at `project_root` when given, or at the
+    /// closest ancestor holding a `.git` entry, or at the current directory.
+    pub fn of(file: &Path, project_root: Option<&Path>) -> Self {
+        let file = std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf());
+        let root = Self::root(file.parent().unwrap_or(&file), project_root);
+        let relpath = file
+            .strip_prefix(&root)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| file.clone());
+
+        Self { root, relpath }
+    }
+
+    /// Root of the project of `dir`: `project_root` when given, or the closest
+    /// directory holding a `.git` entry from `dir` up, or the current directory.
+    pub fn root(dir: &Path, project_root: Option<&Path>) -> PathBuf {
+        match project_root {
+            Some(root) => std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf()),
+            None => std::path::absolute(dir)
+                .unwrap_or_else(|_| dir.to_path_buf())
+                .ancestors()
+                .find(|dir| dir.join(".git").exists())
+                .map(Path::to_path_buf)
+                .or_else(|| std::env::current_dir().ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Creates a temporary working directory holding a copy of the fixtures, when
+/// the preset declares any.
+fn prepare_workspace(fixtures: &[PathBuf]) -> anyhow::Result<Option<TempDir>> {
+    if fixtures.is_empty() {
+        return Ok(None);
+    }
+
+    let workspace = TempDir::new()?;
+    for fixture in fixtures {
+        let Some(name) = fixture.file_name() else {
+            anyhow::bail!("Invalid fixture path: {}", fixture.display());
+        };
+
+        for entry in WalkDir::new(fixture) {
+            let entry =
+                entry.with_context(|| format!("Failed to read fixture: {}", fixture.display()))?;
+            let mut destination = workspace.path().join(name);
+            let relative = entry.path().strip_prefix(fixture)?;
+            if !relative.as_os_str().is_empty() {
+                destination.push(relative);
+            }
+
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&destination)?;
+            } else {
+                fs::copy(entry.path(), &destination).with_context(|| {
+                    format!("Failed to copy fixture: {}", entry.path().display())
+                })?;
+            }
+        }
+    }
+
+    Ok(Some(workspace))
+}
+
+fn new_command(args: &[String], cfg: &PresetConfig, workspace: Option<&Path>) -> Command {
     let mut cmd = Command::new(&args[0]);
+    cmd.args(&args[1..]);
+    if cfg.clear_env {
+        // Removed one by one rather than cleared, so that `--debug-env` sees
+        // the environment of the command
+        for (name, _) in std::env::vars_os() {
+            if !cfg.pass_env.iter().any(|pass| name == pass.as_str()) {
+                cmd.env_remove(name);
+            }
+        }
+    }
+    cmd.envs(&cfg.env);
+    if let Some(workspace) = workspace {
+        cmd.current_dir(workspace);
+    }
+    cmd
+}
 
-    cmd.args(&args[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+/// Runs `cmd`, writing `input` to its stdin, and kills it once it ran for
+/// `timeout`.
+fn execute(
+    cmd: &mut Command,
+    input: Option<&[u8]>,
+    timeout: Option<Duration>,
+) -> std::io::Result<Output> {
+    cmd.stdin(if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped());
+    // Commands with a timeout lead their own process group, so that their
+    // children are killed along with them
+    #[cfg(unix)]
+    if timeout.is_some() {
+        std::os::unix::process::CommandExt::process_group(cmd, 0);
+    }
 
     let mut child = cmd.spawn()?;
-    if let Some(stdin) = child.stdin.as_mut() {
-        stdin.write_all(input.as_bytes())?;
+    // The input is written while the output is read, as commands may fill the
+    // output pipe before reading all of their input
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = input.unwrap_or_default().to_vec();
+        std::thread::spawn(move || stdin.write_all(&input))
+    });
+    let stdout = child.stdout.take().map(read_to_end);
+    let stderr = child.stderr.take().map(read_to_end);
+
+    let status = match timeout {
+        Some(timeout) => match child.wait_timeout(timeout)? {
+            Some(status) => status,
+            None => {
+                kill(&mut child)?;
+                child.wait()?;
+                // The readers are left behind, the pipes may still be held
+                // open by processes having left the group
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "The command `{}` timed out after {}",
+                        cmd.get_program().to_string_lossy(),
+                        humantime::format_duration(timeout)
+                    ),
+                ));
+            }
+        },
+        None => child.wait()?,
+    };
+
+    match writer.map(JoinHandle::join) {
+        // Commands are free to exit without reading their input (e.g. `echo`)
+        Some(Ok(Err(e))) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e),
+        _ => {}
     }
+    let joined = |reader: Option<JoinHandle<Vec<u8>>>| {
+        reader
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default()
+    };
 
-    let output = child.wait_with_output()?;
+    Ok(Output {
+        status,
+        stdout: joined(stdout),
+        stderr: joined(stderr),
+    })
+}
+
+/// Kills `child` and the processes of its group.
+fn kill(child: &mut std::process::Child) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Ok(pid) = libc::pid_t::try_from(child.id()) {
+        // SAFETY: `kill` has no memory safety requirements
+        if unsafe { libc::kill(-pid, libc::SIGKILL) } == 0 {
+            return Ok(());
+        }
+    }
+    child.kill()
+}
+
+/// Reads a pipe of a command in the background.
+fn read_to_end(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = pipe.read_to_end(&mut bytes);
+        bytes
+    })
+}
+
+fn run_command_with_stdin(
+    cfg: &PresetConfig,
+    command_template: &[String],
+    input: &[u8],
+    context: &Placeholders,
+) -> anyhow::Result<(Command, Output)> {
+    let args = expand_command_vec(command_template, None, context);
+    let mut cmd = new_command(&args, cfg, context.workspace);
+    let output = execute(&mut cmd, Some(input), cfg.timeout)?;
 
     Ok((cmd, output))
 }
 
 fn run_command_with_file(
+    cfg: &PresetConfig,
     command_template: &[String],
     input: &str,
-    lang: &str,
+    context: &Placeholders,
 ) -> anyhow::Result<(Command, Output)> {
     let tmp = NamedTempFile::new()?;
     fs::write(tmp.path(), input)?;
-    let args = expand_command_vec(command_template, Some(tmp.path()), lang);
-
-    let mut cmd = Command::new(&args[0]);
-    cmd.args(&args[1..])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+    let args = expand_command_vec(command_template, Some(tmp.path()), context);
 
+    let mut cmd = new_command(&args, cfg, context.workspace);
     debug!("Executing command {args:?}");
-    let output = cmd.output()?;
+    let output = execute(&mut cmd, None, cfg.timeout)?;
 
     Ok((cmd, output))
 }
 
-fn expand_command_vec(template: &[String], file: Option<&Path>, lang: &str) -> Vec<String> {
+/// Passes the code as the `{code}` argument, falling back to the stdin or file
+/// input mode when the command line exceeds the limits of the OS.
+fn run_command_with_arg(
+    cfg: &PresetConfig,
+    command_template: &[String],
+    input: &str,
+    context: &Placeholders,
+) -> anyhow::Result<(Command, Output)> {
+    let with_code = Placeholders {
+        code: Some(input),
+        ..*context
+    };
+    let args = expand_command_vec(command_template, None, &with_code);
+
+    let mut cmd = new_command(&args, cfg, context.workspace);
+    debug!("Executing command {}", args[0]);
+    match execute(&mut cmd, None, cfg.timeout) {
+        Ok(output) => Ok((cmd, output)),
+        Err(e) if e.kind() == std::io::ErrorKind::ArgumentListTooLong => {
+            // Without a fallback command, the same command reads the code
+            // from a file passed in place of the code
+            let fallback = cfg.fallback_command.clone().unwrap_or_else(|| {
+                command_template
+                    .iter()
+                    .map(|arg| arg.replace("{code}", "{file}"))
+                    .collect()
+            });
+
+            if fallback.iter().any(|arg| arg.contains("{file}")) {
+                warn!(
+                    "The command line of `{}` is too long ({} bytes), falling back to the file input mode",
+                    args[0],
+                    input.len()
+                );
+                run_command_with_file(cfg, &fallback, input, context)
+            } else {
+                warn!(
+                    "The command line of `{}` is too long ({} bytes), falling back to the stdin input mode",
+                    args[0],
+                    input.len()
+                );
+                run_command_with_stdin(cfg, &fallback, input.as_bytes(), context)
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn expand_command_vec(
+    template: &[String],
+    file: Option<&Path>,
+    context: &Placeholders,
+) -> Vec<String> {
+    let workspace = context
+        .workspace
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_default();
+    let tmpdir = context.tmpdir.and_then(Path::to_str).unwrap_or("");
+    let artifact = context.artifact.and_then(Path::to_str).unwrap_or("");
+    // Values are quoted when the command is a shell script, the code and the
+    // paths must never run as code
+    let quote = |value: &str| context.shell.quote(value).into_owned();
+
     template
         .iter()
         .map(|arg| {
-            let replaced = arg.replace("{lang}", lang);
+            let replaced = arg
+                .replace("{lang}", &quote(context.lang))
+                .replace("{workspace}", &quote(workspace.to_str().unwrap_or("")))
+                .replace("{tmpdir}", &quote(tmpdir))
+                .replace("{artifact}", &quote(artifact))
+                .replace(
+                    "{repo_root}",
+                    &quote(context.project.root.to_str().unwrap_or("")),
+                )
+                .replace(
+                    "{relpath}",
+                    &quote(context.project.relpath.to_str().unwrap_or("")),
+                );
+            // The code is substituted last, so that placeholders it contains
+            // are never expanded
+            let replaced = match context.code {
+                Some(code) => replaced.replace("{code}", &quote(code)),
+                None => replaced,
+            };
             if let Some(file) = file {
                 replaced
-                    .replace("{file}", file.to_str().unwrap_or("{file}"))
+                    .replace("{file}", &quote(file.to_str().unwrap_or("{file}")))
                     .replace(
                         "{basename}",
-                        file.file_name().and_then(|s| s.to_str()).unwrap_or(""),
+                        &quote(file.file_name().and_then(|s| s.to_str()).unwrap_or("")),
                     )
                     .replace(
                         "{dirname}",
-                        file.parent().and_then(|s| s.to_str()).unwrap_or(""),
+                        &quote(file.parent().and_then(|s| s.to_str()).unwrap_or("")),
                     )
                     .replace(
                         "{suffix}",
-                        file.extension().and_then(|s| s.to_str()).unwrap_or(""),
+                        &quote(file.extension().and_then(|s| s.to_str()).unwrap_or("")),
                     )
-                    .replace("{tmpdir}", std::env::temp_dir().to_str().unwrap_or(""))
             } else {
                 replaced
             }
@@ -89,6 +455,49 @@ fn expand_command_vec(template: &[String], file: Option<&Path>, lang: &str) -> V
         .collect()
 }
 
+/// Runs a user-supplied comparator on the code of a block and the output of its
+/// command, written to the `{expected}` and `{actual}` files. They match when
+/// it exits with `0` and differ when it exits with `1`.
+pub fn run_comparator(comparator: &[String], expected: &str, actual: &str) -> anyhow::Result<bool> {
+    let expected_file = NamedTempFile::new()?;
+    fs::write(expected_file.path(), expected)?;
+    let actual_file = NamedTempFile::new()?;
+    fs::write(actual_file.path(), actual)?;
+
+    let args: Vec<String> = comparator
+        .iter()
+        .map(|arg| {
+            arg.replace("{expected}", expected_file.path().to_str().unwrap_or(""))
+                .replace("{actual}", actual_file.path().to_str().unwrap_or(""))
+        })
+        .collect();
+    let Some((program, args)) = args.split_first() else {
+        anyhow::bail!("The comparator command is empty");
+    };
+
+    debug!("Executing comparator {program}");
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to execute the comparator `{program}`"))?;
+    // Like `diff` and `cmp`, other exit codes are errors rather than differences
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) => {
+            debug!(
+                "The comparator `{program}` reported differences: {}",
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+            Ok(false)
+        }
+        _ => anyhow::bail!(
+            "The comparator `{program}` failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
 pub fn command_to_string(cmd: &Command) -> String {
     let program = cmd.get_program().to_string_lossy();
     let args = cmd
@@ -1,53 +1,1222 @@
+use crate::codeblock::CodeBlock;
+use crate::ignore::normalize;
+use anyhow::{Context, Result};
+use encoding_rs::Encoding;
+use indexmap::IndexMap;
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-#[derive(Debug, Deserialize)]
+/// Names of the config files looked for when `--config` is omitted.
+pub const CONFIG_NAMES: [&str; 2] = ["mdcr.toml", ".mdcr.toml"];
+
+/// The config file of `start`, a directory or a file: the outermost one found
+/// in its ancestors up to the root of its git repository, or the nearest one
+/// outside of a repository. The ones below it are nested configs, merged into
+/// it.
+pub fn discover(start: &Path) -> Option<PathBuf> {
+    let start = absolute(start).ok()?;
+    let config_in = |dir: &Path| {
+        CONFIG_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+    };
+
+    match start.ancestors().find(|dir| dir.join(".git").exists()) {
+        Some(root) => start
+            .ancestors()
+            .take_while(|dir| dir.starts_with(root))
+            .filter_map(config_in)
+            .last(),
+        None => start.ancestors().find_map(config_in),
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "lowercase")]
 #[derive(Default)]
 pub enum InputMode {
     #[default]
     Stdin,
     File,
+    /// The code is passed as the `{code}` argument of the command
+    Arg,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "lowercase")]
 #[derive(Default)]
 pub enum OutputMode {
     #[default]
     Replace,
     Check,
+    /// The command writes a file to `{artifact}`, which is copied to the
+    /// assets directory and referenced after the block
+    Artifact,
+}
+
+/// Exit statuses for which the output of a command replaces the block.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Default)]
+pub enum ReplaceOn {
+    #[default]
+    Success,
+    /// Failing commands are not reported, their stdout and stderr are used
+    Always,
+}
+
+/// How the mismatches and command failures of a preset affect the run.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Default)]
+pub enum Severity {
+    #[default]
+    Error,
+    /// Reported, without affecting the exit code
+    Warning,
 }
 
-#[derive(Debug, Deserialize)]
+impl Severity {
+    pub fn is_error(self) -> bool {
+        self == Severity::Error
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    pub fn log_level(self) -> log::Level {
+        match self {
+            Severity::Error => log::Level::Error,
+            Severity::Warning => log::Level::Warn,
+        }
+    }
+}
+
+/// What running a preset does to the code of its blocks, so that untrusted
+/// environments can restrict runs to the presets which never execute it.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Default)]
+pub enum PresetKind {
+    /// Reformats the code, without executing it
+    Formatter,
+    /// Executes the code, e.g. to insert its output. Unclassified presets are
+    /// assumed to.
+    #[default]
+    Executor,
+    /// Validates the code without modifying it, e.g. a linter
+    Checker,
+}
+
+impl PresetKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PresetKind::Formatter => "formatter",
+            PresetKind::Executor => "executor",
+            PresetKind::Checker => "checker",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PresetConfig {
-    #[serde(deserialize_with = "deserialize_string_or_vec", alias = "language")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string_or_vec",
+        alias = "language"
+    )]
+    #[schemars(with = "StringOrVec")]
     pub languages: Vec<String>,
+    /// Regular expression matching the languages of the blocks to run, e.g.
+    /// `^(ba|z)?sh$`, on top of `languages`
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    #[schemars(with = "Option<String>")]
+    pub language_pattern: Option<Regex>,
+    /// Classes a Pandoc attribute block must all have, e.g. `["numberLines"]`
+    #[serde(default)]
+    pub classes: Vec<String>,
+    /// Identifiers of the Pandoc attribute blocks to run, any when empty
+    #[serde(default)]
+    pub ids: Vec<String>,
+    /// Globs of the Markdown files the preset runs on, e.g. `["docs/api/**"]`,
+    /// all of them when empty
+    #[serde(default, deserialize_with = "deserialize_globs")]
+    #[schemars(with = "Vec<String>")]
+    pub files: Vec<glob::Pattern>,
+    /// Globs of the Markdown files the preset never runs on, e.g. `["CHANGELOG.md"]`
+    #[serde(default, deserialize_with = "deserialize_globs")]
+    #[schemars(with = "Vec<String>")]
+    pub exclude_files: Vec<glob::Pattern>,
+    /// Program and arguments, or a script run through the shell when a string
+    #[serde(default)]
+    #[schemars(with = "StringOrVec")]
     pub command: Vec<String>,
+    /// Commands piped into each other, instead of `command`
+    #[serde(default)]
+    pub commands: Vec<Vec<String>>,
+    /// Run the command through the shell, its arguments joined as a script
+    #[serde(default)]
+    pub shell: Shell,
     #[serde(default)]
     pub input_mode: InputMode,
+    /// Command used in `arg` input mode when the command line is too long
+    pub fallback_command: Option<Vec<String>>,
     #[serde(default)]
     pub output_mode: OutputMode,
+    #[serde(default)]
+    pub replace_on: ReplaceOn,
+    /// Command deciding whether the output matches the block, from the
+    /// `{expected}` and `{actual}` files, instead of comparing their text
+    pub comparator: Option<Vec<String>>,
+    /// Extension of the file written by the command in `artifact` output mode
+    #[serde(default = "default_artifact_extension")]
+    pub artifact_extension: String,
+    /// Directory, relative to the Markdown file, the artifacts are copied to
+    #[serde(default = "default_artifact_dir")]
+    pub artifact_dir: PathBuf,
+    /// Encoding of the command output (e.g. `latin1`), defaults to UTF-8
+    #[serde(default, deserialize_with = "deserialize_encoding")]
+    #[schemars(with = "Option<String>")]
+    pub output_encoding: Option<&'static Encoding>,
+    /// Resolve `{{var}}` placeholders in the code before running the command
+    #[serde(default)]
+    pub substitute_variables: bool,
+    /// Files or directories copied into the working directory of the command
+    #[serde(default)]
+    pub fixtures: Vec<PathBuf>,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub kind: PresetKind,
+    /// Maximum length of the output lines, longer ones are wrapped
+    pub wrap_output_at: Option<usize>,
+    /// Appended to the output lines broken by `wrap_output_at`
+    #[serde(default = "default_wrap_marker")]
+    pub wrap_marker: String,
+    /// Kill the command once it ran this long (e.g. `30s`) and report a failure
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Environment variables set on the command, e.g. `{ NO_COLOR = "1" }`
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Run the command without the environment of `mdcr`, except `pass_env`
+    #[serde(default)]
+    pub clear_env: bool,
+    /// Environment variables kept with `clear_env`, e.g. `["PATH", "HOME"]`
+    #[serde(default)]
+    pub pass_env: Vec<String>,
+    /// Command run once per run, before the first command of the preset,
+    /// e.g. `["npm", "ci"]`
+    #[serde(default)]
+    pub before: Vec<String>,
+    /// Command run once at the end of a run in which the preset ran
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Probe skipping the preset when it fails, e.g. `["which", "terraform"]`
+    #[serde(default)]
+    pub only_if: Vec<String>,
+    /// Disabled presets only run with `--enable-preset`
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
 }
 
-fn deserialize_string_or_vec<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+/// Shell running the command of a preset: `true` for the default one of the
+/// OS, or the name of its program.
+#[derive(Debug, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Shell {
+    Enabled(bool),
+    Program(String),
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::Enabled(false)
+    }
+}
+
+impl Shell {
+    /// Command line running a script passed as its last argument, `None`
+    /// without shell.
+    fn command(&self) -> Option<Vec<String>> {
+        let program = self.program()?;
+        let flags: &[&str] = match self.name().as_deref() {
+            Some("cmd") => &["/C"],
+            Some("pwsh" | "powershell") => &["-NoProfile", "-Command"],
+            _ => &["-c"],
+        };
+
+        Some(
+            std::iter::once(program)
+                .chain(flags.iter().copied())
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    fn program(&self) -> Option<&str> {
+        match self {
+            Shell::Enabled(false) => None,
+            Shell::Enabled(true) if cfg!(windows) => Some("cmd"),
+            Shell::Enabled(true) => Some("sh"),
+            Shell::Program(program) => Some(program),
+        }
+    }
+
+    /// Lowercase name of the program of the shell, without extension.
+    fn name(&self) -> Option<String> {
+        Path::new(self.program()?)
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_lowercase())
+    }
+
+    /// Script running the program and arguments of an array command, each of
+    /// them a single word of the shell. Their placeholders are left as is,
+    /// their values being quoted once substituted.
+    fn script(&self, args: &[String]) -> String {
+        args.iter()
+            .map(|arg| self.word(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn word(&self, arg: &str) -> String {
+        let quote = |literal: &str| {
+            if literal
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+            {
+                literal.to_string()
+            } else {
+                self.quote(literal).into_owned()
+            }
+        };
+        if arg.is_empty() {
+            return self.quote(arg).into_owned();
+        }
+
+        let mut word = String::new();
+        let mut literal = String::new();
+        let mut rest = arg;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            let placeholder = rest[start + 1..].split_once('}').filter(|(name, _)| {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+            });
+            match placeholder {
+                Some((name, tail)) => {
+                    word.push_str(&quote(&literal));
+                    word.push_str(&format!("{{{name}}}"));
+                    literal.clear();
+                    rest = tail;
+                }
+                None => {
+                    literal.push('{');
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+        word.push_str(&quote(&literal));
+
+        word
+    }
+
+    /// Quotes a value substituted in a script, so that the shell never runs
+    /// it as code. Values are left as is without shell.
+    pub fn quote<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        match self.name().as_deref() {
+            None => Cow::Borrowed(value),
+            Some("cmd") => Cow::Owned(format!("\"{}\"", value.replace('"', "\"\""))),
+            Some("pwsh" | "powershell") => Cow::Owned(format!("'{}'", value.replace('\'', "''"))),
+            Some(_) => Cow::Owned(format!("'{}'", value.replace('\'', "'\\''"))),
+        }
+    }
+}
+
+impl PresetConfig {
+    /// Whether the preset runs on `block`, whose language is `lang` once its
+    /// alias is resolved, see `AppSettings::runs`.
+    fn matches(&self, block: &CodeBlock, lang: &str) -> bool {
+        (self.is_fallback()
+            || self.languages.iter().any(|l| l.trim() == lang)
+            || self
+                .language_pattern
+                .as_ref()
+                .is_some_and(|pattern| pattern.is_match(lang)))
+            && self
+                .classes
+                .iter()
+                .all(|class| block.classes.contains(class))
+            && (self.ids.is_empty() || block.id.as_ref().is_some_and(|id| self.ids.contains(id)))
+            && (self.files.is_empty() || matches_file(&self.files, &block.path))
+            && !matches_file(&self.exclude_files, &block.path)
+    }
+
+    /// Commands of the pipeline of the preset, `command` alone without
+    /// `commands`.
+    pub fn stages(&self) -> impl Iterator<Item = &Vec<String>> {
+        let command = self.commands.is_empty().then_some(&self.command);
+        command.into_iter().chain(&self.commands)
+    }
+
+    /// Whether the preset runs on the blocks no other preset runs on, with
+    /// `language = "*"`.
+    pub fn is_fallback(&self) -> bool {
+        self.languages.iter().any(|l| l.trim() == "*")
+    }
+
+    /// Decodes the output of a command using the configured encoding.
+    pub fn decode_output<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match self.output_encoding {
+            Some(encoding) => encoding.decode(bytes).0,
+            None => String::from_utf8_lossy(bytes),
+        }
+    }
+}
+
+fn default_artifact_extension() -> String {
+    "png".to_string()
+}
+
+fn default_artifact_dir() -> PathBuf {
+    PathBuf::from("assets")
+}
+
+fn default_wrap_marker() -> String {
+    "\\".to_string()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn deserialize_encoding<'de, D>(deserializer: D) -> Result<Option<&'static Encoding>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let label = String::deserialize(deserializer)?;
+
+    Encoding::for_label(label.trim().as_bytes())
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown output encoding `{label}`")))
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum StringOrVec {
+    String(String),
+    Vec(Vec<String>),
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    enum StringOrVec {
-        String(String),
-        Vec(Vec<String>),
+    let pattern = String::deserialize(deserializer)?;
+
+    Regex::new(&pattern)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid pattern `{pattern}`: {e}")))
+}
+
+fn deserialize_globs<'de, D>(deserializer: D) -> Result<Vec<glob::Pattern>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| {
+            glob::Pattern::new(pattern)
+                .map_err(|e| serde::de::Error::custom(format!("invalid glob `{pattern}`: {e}")))
+        })
+        .collect()
+}
+
+/// Patterns without a `/` match the name of the file, other patterns match
+/// its whole path: config files make them absolute from their directory, so
+/// that `docs/api/**` matches `/repo/docs/api/index.md` but not
+/// `/repo/site/docs/api/index.md` for `/repo/mdcr.toml`.
+fn matches_file(patterns: &[glob::Pattern], path: &Path) -> bool {
+    if patterns.is_empty() {
+        return false;
     }
 
+    let path = normalize(path);
+    let absolute = absolute(&path).map(|path| normalize(&path));
+    let name = path.file_name().map(Path::new).unwrap_or(&path);
+
+    patterns.iter().any(|pattern| {
+        if !pattern.as_str().contains('/') {
+            pattern.matches_path(name)
+        } else if Path::new(pattern.as_str()).is_absolute() {
+            absolute
+                .as_ref()
+                .is_ok_and(|path| pattern.matches_path(path))
+        } else {
+            pattern.matches_path(&path)
+        }
+    })
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+
+    humantime::parse_duration(duration.trim())
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid duration `{duration}`: {e}")))
+}
+
+fn deserialize_string_or_vec<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
     match StringOrVec::deserialize(deserializer)? {
         StringOrVec::String(s) => Ok(vec![s]),
         StringOrVec::Vec(v) => Ok(v),
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Skip the remaining blocks of a preset once it failed this many times
+    pub max_failures_per_preset: Option<usize>,
+    /// Extensions of the Markdown files to process in directories
+    pub extensions: Option<Vec<String>>,
+    /// Glob patterns of the paths to skip in directories
+    pub exclude: Option<Vec<String>>,
+    /// Log level of stderr
+    pub log: Option<String>,
+    /// Number of files and commands processed concurrently
+    pub jobs: Option<usize>,
+    /// Front matter field set to the current date when a file is updated
+    pub verified_field: Option<String>,
+    /// Fail files whose `verified_field` is older than this in check mode
+    pub max_verified_age_days: Option<i64>,
+    /// End updated documents with a newline, even when the original did not
+    #[serde(default)]
+    pub final_newline: bool,
+    /// Whether all the presets matching a block run on it, or the first one
+    #[serde(default)]
+    pub match_strategy: MatchStrategy,
+    /// Whether each preset matching a block runs on the output of the previous
+    /// ones, rather than on the code of the block
+    #[serde(default)]
+    pub chain_presets: bool,
+    /// Timeout of the presets which set none
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Languages of the blocks no preset runs on, e.g. `["mermaid", "text"]`
+    #[serde(default)]
+    pub skip_languages: Vec<String>,
+}
+
+/// Which of the presets matching a block run on it, in the order of the config.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Default)]
+pub enum MatchStrategy {
+    #[default]
+    All,
+    First,
+}
+
+/// A code block exempted from some presets, identified by its file and the
+/// hash of its content, see `mdcr ignore add`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct IgnoreEntry {
+    pub file: PathBuf,
+    pub hash: String,
+    /// Presets the block is exempted from, all of them when empty
+    #[serde(default)]
+    pub presets: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct AppSettings {
-    pub presets: HashMap<String, PresetConfig>,
+    /// Where the config was loaded from, to find the nested configs
+    #[serde(skip)]
+    source: Option<ConfigSource>,
+    #[serde(default)]
+    pub settings: Settings,
+    /// Values of the `{{var}}` placeholders, the environment is used otherwise
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    #[serde(default)]
+    pub ignore: Vec<IgnoreEntry>,
+    /// Languages of the blocks run as another one, e.g. `py = "python"`
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Presets in the order of the config, which is the order they run in
+    pub presets: IndexMap<String, PresetConfig>,
+    /// Presets of the config left out of the run by `retain_presets`, which
+    /// still cover the blocks they match
+    #[serde(skip)]
+    filtered_presets: Vec<PresetConfig>,
+}
+
+/// JSON Schema of the config files, for editors to validate and complete them.
+/// No key is required, as any of them may come from another config file.
+pub fn schema() -> serde_json::Value {
+    let mut schema = schemars::schema_for!(AppSettings).to_value();
+    schema["title"] = "mdcr config".into();
+    remove_required(&mut schema);
+
+    if let Some(properties) = schema["$defs"]["PresetConfig"]["properties"].as_object_mut() {
+        // Aliases are not part of the generated schema
+        if let Some(languages) = properties.get("languages").cloned() {
+            properties.insert("language".to_string(), languages);
+        }
+        properties.insert(
+            "extends".to_string(),
+            serde_json::json!({
+                "description": "Preset whose fields are used when not set by this one",
+                "type": "string",
+            }),
+        );
+    }
+    if let Some(properties) = schema["properties"].as_object_mut() {
+        properties.insert(
+            "profiles".to_string(),
+            serde_json::json!({
+                "description": "Settings and presets overriding the config when selected with `--profile`",
+                "type": "object",
+                "additionalProperties": { "$ref": "#" },
+            }),
+        );
+    }
+
+    schema
+}
+
+fn remove_required(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.remove("required");
+            object.values_mut().for_each(remove_required);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(remove_required),
+        _ => {}
+    }
+}
+
+/// `path` made absolute, with its `..` components resolved without following
+/// the links, so that its ancestors are the directories it is in.
+pub fn absolute(path: &Path) -> std::io::Result<PathBuf> {
+    let mut absolute = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+
+    Ok(absolute)
+}
+
+/// Presets kept by the options of the run, in nested configs too.
+type PresetFilter = Arc<dyn Fn(&str, &PresetConfig) -> bool + Send + Sync>;
+
+/// The config file an `AppSettings` was loaded from, along with the settings
+/// of its nested configs, by directory.
+struct ConfigSource {
+    dir: PathBuf,
+    value: toml::Value,
+    overrides: Overrides,
+    filter: Option<PresetFilter>,
+    nested: Mutex<HashMap<PathBuf, Option<Arc<AppSettings>>>>,
+}
+
+impl fmt::Debug for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigSource")
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What the command line changes on top of the config files.
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    /// Name of the `[profiles.<name>]` table merged into the config
+    pub profile: Option<String>,
+    /// `--set` dotted keys and their values, applied last
+    pub values: Vec<(String, String)>,
+}
+
+impl Overrides {
+    /// Merges the profile into `value`, then sets the values.
+    fn apply(&self, value: &mut toml::Value, profiles: Option<toml::Value>) -> Result<()> {
+        if let Some(profile) = &self.profile {
+            let Some(overrides) = profiles.as_ref().and_then(|profiles| profiles.get(profile))
+            else {
+                anyhow::bail!("Unknown profile `{profile}`");
+            };
+            merge(value, normalized_presets(overrides.clone()));
+        }
+
+        for (key, raw) in &self.values {
+            let mut keys: Vec<&str> = key.split('.').collect();
+            // The `language` of presets is stored as `languages`
+            if let ["presets", _, last @ "language"] = keys.as_mut_slice() {
+                *last = "languages";
+            }
+            let Some((last, parents)) = keys.split_last() else {
+                continue;
+            };
+
+            let mut table = value.as_table_mut().context("The config must be a table")?;
+            for parent in parents {
+                table = table
+                    .entry(parent.to_string())
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+                    .with_context(|| format!("Cannot set `{key}`: `{parent}` is not a table"))?;
+            }
+            table.insert(last.to_string(), parse_override(raw));
+        }
+
+        Ok(())
+    }
+}
+
+/// A `--set` value as a TOML value (`30`, `true`, `["a", "b"]`), or as a
+/// string when it is not one.
+fn parse_override(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// Loads the config files merged in order, the later ones overriding or adding
+/// presets, then the `overrides` on top of them. Nested configs are looked for
+/// below the last one.
+pub fn load(paths: &[PathBuf], overrides: &Overrides) -> Result<AppSettings> {
+    let (Some(first), Some(last)) = (paths.first(), paths.last()) else {
+        anyhow::bail!("No config file to load");
+    };
+    let mut value = read_value(first)?;
+    for path in &paths[1..] {
+        merge(&mut value, read_value(path)?);
+    }
+    let mut settings = settings_from(value.clone(), overrides)?;
+    settings.source = Some(ConfigSource {
+        dir: absolute(last)?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        value,
+        overrides: overrides.clone(),
+        filter: None,
+        nested: Mutex::default(),
+    });
+
+    Ok(settings)
+}
+
+fn read_value(path: &Path) -> Result<toml::Value> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+
+    let mut value = parse_value(path, &content)
+        .with_context(|| format!("Invalid config file: {}", path.display()))?;
+    if let Some(dir) = absolute(path)?.parent() {
+        resolve_fixtures(&mut value, dir);
+        resolve_files(&mut value, dir);
+        resolve_ignores(&mut value, dir);
+    }
+
+    Ok(value)
+}
+
+/// Makes the `fixtures` of the presets of `value`, and of the presets of its
+/// profiles, relative to `dir`, the directory of the config declaring them.
+fn resolve_fixtures(value: &mut toml::Value, dir: &Path) {
+    for fixture in preset_values(value, &["fixtures"]) {
+        if let Some(path) = fixture.as_str() {
+            *fixture = dir.join(path).to_string_lossy().into_owned().into();
+        }
+    }
+}
+
+/// Anchors the `files` and `exclude_files` patterns containing a `/` of the
+/// presets of `value`, and of the presets of its profiles, at `dir`, the
+/// directory of the config declaring them.
+fn resolve_files(value: &mut toml::Value, dir: &Path) {
+    let dir = glob::Pattern::escape(&dir.to_string_lossy());
+    for pattern in preset_values(value, &["files", "exclude_files"]) {
+        if let Some(relative) = pattern.as_str().filter(|pattern| {
+            pattern.contains('/') && !Path::new(pattern.trim_start_matches("./")).is_absolute()
+        }) {
+            *pattern = format!("{dir}/{}", relative.trim_start_matches("./")).into();
+        }
+    }
+}
+
+/// Makes the `file` of the `[[ignore]]` entries of `value` relative to `dir`,
+/// the directory of the config declaring them.
+fn resolve_ignores(value: &mut toml::Value, dir: &Path) {
+    let files = value
+        .get_mut("ignore")
+        .and_then(toml::Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.get_mut("file"));
+    for file in files {
+        if let Some(path) = file.as_str() {
+            *file = dir.join(path).to_string_lossy().into_owned().into();
+        }
+    }
+}
+
+/// Items of the `keys` arrays of the presets of `value` and of the presets of
+/// its profiles.
+fn preset_values<'a>(
+    value: &'a mut toml::Value,
+    keys: &'a [&str],
+) -> impl Iterator<Item = &'a mut toml::Value> {
+    let mut presets = Vec::new();
+    for (name, value) in value
+        .as_table_mut()
+        .into_iter()
+        .flat_map(|table| table.iter_mut())
+    {
+        match name.as_str() {
+            "presets" => presets.push(value),
+            "profiles" => presets.extend(
+                value
+                    .as_table_mut()
+                    .into_iter()
+                    .flat_map(|profiles| profiles.iter_mut().map(|(_, profile)| profile))
+                    .filter_map(|profile| profile.get_mut("presets")),
+            ),
+            _ => {}
+        }
+    }
+
+    presets
+        .into_iter()
+        .filter_map(toml::Value::as_table_mut)
+        .flat_map(|presets| presets.iter_mut().map(|(_, preset)| preset))
+        .filter_map(toml::Value::as_table_mut)
+        .flat_map(|preset| preset.iter_mut())
+        .filter(|(key, _)| keys.contains(&key.as_str()))
+        .filter_map(|(_, value)| value.as_array_mut())
+        .flatten()
+}
+
+/// Parses a config in the format of its extension: YAML for `.yaml` and
+/// `.yml`, JSON for `.json`, TOML otherwise.
+pub fn parse(path: &Path, content: &str) -> Result<AppSettings> {
+    settings_from(parse_value(path, content)?, &Overrides::default())
+}
+
+fn settings_from(mut value: toml::Value, overrides: &Overrides) -> Result<AppSettings> {
+    let profiles = value
+        .as_table_mut()
+        .and_then(|table| table.remove("profiles"));
+    overrides.apply(&mut value, profiles)?;
+    resolve_extends(&mut value)?;
+    let scripts = shell_commands(&mut value);
+
+    let mut settings: AppSettings = value
+        .clone()
+        .try_into()
+        .map_err(|e| unknown_key_error(&e, &value).unwrap_or_else(|| e.into()))?;
+    if settings.settings.jobs == Some(0) {
+        anyhow::bail!("`settings.jobs` must be at least 1");
+    }
+    if let Some(level) = settings.settings.log.as_deref() {
+        let levels = ["off", "error", "warn", "info", "debug", "trace"];
+        if !levels.contains(&level) {
+            anyhow::bail!(
+                "Unknown `settings.log` level `{level}`, expected one of {}",
+                levels.join(", ")
+            );
+        }
+    }
+    for (name, preset) in &mut settings.presets {
+        if preset.languages.is_empty() && preset.language_pattern.is_none() {
+            anyhow::bail!("Preset `{name}` has no `language` nor `language_pattern`");
+        }
+        match (preset.command.is_empty(), preset.commands.is_empty()) {
+            (true, true) => anyhow::bail!("Preset `{name}` has no `command` nor `commands`"),
+            (false, false) => anyhow::bail!("Preset `{name}` has both `command` and `commands`"),
+            _ => {}
+        }
+        if preset.commands.iter().any(Vec::is_empty) {
+            anyhow::bail!("Preset `{name}` has an empty command in `commands`");
+        }
+        if preset.fallback_command.as_ref().is_some_and(Vec::is_empty) {
+            anyhow::bail!("Preset `{name}` has an empty `fallback_command`");
+        }
+        preset.timeout = preset.timeout.or(settings.settings.timeout);
+        if let Some(shell) = preset.shell.command() {
+            for command in preset
+                .commands
+                .iter_mut()
+                .chain(std::iter::once(&mut preset.command))
+                .filter(|command| !command.is_empty())
+            {
+                // String commands are scripts already
+                let script = if scripts.contains(name) {
+                    command.join(" ")
+                } else {
+                    preset.shell.script(command)
+                };
+                *command = shell.clone();
+                command.push(script);
+            }
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Marks the presets whose command is a string as running it through the
+/// shell, unless they tell otherwise. Returns the names of these presets.
+fn shell_commands(value: &mut toml::Value) -> HashSet<String> {
+    let mut scripts = HashSet::new();
+    let presets = value
+        .get_mut("presets")
+        .and_then(toml::Value::as_table_mut)
+        .into_iter()
+        .flat_map(|presets| presets.iter_mut())
+        .filter_map(|(name, preset)| Some((name, preset.as_table_mut()?)));
+    for (name, preset) in presets {
+        if let Some(toml::Value::String(script)) = preset.get("command") {
+            let command = toml::Value::Array(vec![toml::Value::String(script.clone())]);
+            preset.insert("command".to_string(), command);
+            preset.entry("shell").or_insert(toml::Value::Boolean(true));
+            scripts.insert(name.clone());
+        }
+    }
+
+    scripts
+}
+
+/// The error of an unknown key, located in `value` and with the closest known
+/// key suggested, `None` for other errors.
+fn unknown_key_error(error: &toml::de::Error, value: &toml::Value) -> Option<anyhow::Error> {
+    let (key, expected) = error
+        .message()
+        .trim_end()
+        .strip_prefix("unknown field `")?
+        .split_once("`, expected ")?;
+    let known: Vec<&str> = expected
+        .trim_start_matches("one of ")
+        .split(", ")
+        .map(|known| known.trim_matches('`'))
+        .collect();
+    let path = key_path(value, key).unwrap_or_else(|| key.to_string());
+
+    let suggestion = known
+        .iter()
+        .map(|known| (strsim::jaro_winkler(key, known), known))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0));
+    Some(match suggestion {
+        Some((_, known)) => anyhow::anyhow!("Unknown key `{path}`, did you mean `{known}`?"),
+        None => anyhow::anyhow!(
+            "Unknown key `{path}`, expected one of `{}`",
+            known.join("`, `")
+        ),
+    })
+}
+
+/// Dotted path of the first `key` found in the tables of `value`.
+fn key_path(value: &toml::Value, key: &str) -> Option<String> {
+    let toml::Value::Table(table) = value else {
+        return None;
+    };
+    if table.contains_key(key) {
+        return Some(key.to_string());
+    }
+
+    table
+        .iter()
+        .find_map(|(name, value)| key_path(value, key).map(|path| format!("{name}.{path}")))
+}
+
+/// Whether the config at `path` is written in TOML. Configs named `.json`
+/// were read as TOML before JSON was supported, and still are unless they
+/// hold a JSON object.
+pub fn is_toml(path: &Path, content: &str) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("yaml" | "yml") => false,
+        Some("json") => !content.trim_start().starts_with('{'),
+        _ => true,
+    }
+}
+
+fn parse_value(path: &Path, content: &str) -> Result<toml::Value> {
+    let value = if is_toml(path, content) {
+        toml::from_str(content)?
+    } else if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        serde_json::from_str(content)?
+    } else {
+        serde_yaml::from_str(content)?
+    };
+
+    Ok(normalized_presets(value))
+}
+
+/// `value` with the `language` of its presets renamed to `languages`, so that
+/// merging a preset using one into a preset using the other overrides it.
+fn normalized_presets(mut value: toml::Value) -> toml::Value {
+    let presets = value
+        .get_mut("presets")
+        .and_then(toml::Value::as_table_mut)
+        .into_iter()
+        .flat_map(|presets| presets.iter_mut().map(|(_, preset)| preset))
+        .filter_map(toml::Value::as_table_mut);
+    for preset in presets {
+        if !preset.contains_key("languages") {
+            if let Some(language) = preset.remove("language") {
+                preset.insert("languages".to_string(), language);
+            }
+        }
+    }
+
+    value
+}
+
+/// Replaces the presets declaring `extends = "<preset>"` by that preset, with
+/// their own fields merged into it.
+fn resolve_extends(value: &mut toml::Value) -> Result<()> {
+    let Some(presets) = value.get_mut("presets").and_then(toml::Value::as_table_mut) else {
+        return Ok(());
+    };
+
+    let names: Vec<String> = presets.keys().cloned().collect();
+    let mut resolved = toml::Table::new();
+    for name in &names {
+        resolve_preset(name, presets, &mut resolved, &mut Vec::new())?;
+    }
+    // In the order of the config, rather than the one they were resolved in
+    *presets = names
+        .into_iter()
+        .filter_map(|name| Some((name.clone(), resolved.remove(&name)?)))
+        .collect();
+
+    Ok(())
+}
+
+fn resolve_preset(
+    name: &str,
+    presets: &toml::Table,
+    resolved: &mut toml::Table,
+    chain: &mut Vec<String>,
+) -> Result<toml::Value> {
+    if let Some(preset) = resolved.get(name) {
+        return Ok(preset.clone());
+    }
+    if chain.iter().any(|previous| previous == name) {
+        chain.push(name.to_string());
+        anyhow::bail!("Presets extend each other: {}", chain.join(" -> "));
+    }
+    let Some(mut preset) = presets.get(name).cloned() else {
+        anyhow::bail!(
+            "Preset `{}` extends the unknown preset `{name}`",
+            chain.last().map_or("", String::as_str)
+        );
+    };
+
+    let parent = match preset
+        .as_table_mut()
+        .and_then(|table| table.remove("extends"))
+    {
+        Some(toml::Value::String(parent)) => parent,
+        Some(_) => anyhow::bail!("The `extends` of preset `{name}` must be a preset name"),
+        None => {
+            resolved.insert(name.to_string(), preset.clone());
+            return Ok(preset);
+        }
+    };
+
+    chain.push(name.to_string());
+    let mut extended = resolve_preset(&parent, presets, resolved, chain)?;
+    chain.pop();
+    merge(&mut extended, preset);
+    resolved.insert(name.to_string(), extended.clone());
+
+    Ok(extended)
+}
+
+/// Merges `overlay` into `base`: tables are merged key by key, other values
+/// are replaced.
+fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+impl AppSettings {
+    /// Whether `preset`, one of the presets of these settings, runs on
+    /// `block`, the language of the block being resolved through the aliases
+    /// first. Fallback presets only run on the blocks no other preset runs on,
+    /// and no preset runs on the blocks of `skip_languages`.
+    pub fn runs(&self, preset: &PresetConfig, block: &CodeBlock) -> bool {
+        if self.skips(block) {
+            return false;
+        }
+
+        let lang = self.language(block);
+        let matches = |preset: &PresetConfig| {
+            preset.matches(block, lang)
+                && (!preset.is_fallback()
+                    || !self
+                        .presets
+                        .values()
+                        .chain(&self.filtered_presets)
+                        .any(|other| !other.is_fallback() && other.matches(block, lang)))
+        };
+
+        match self.settings.match_strategy {
+            MatchStrategy::All => matches(preset),
+            MatchStrategy::First => self
+                .presets
+                .values()
+                .find(|other| matches(other))
+                .is_some_and(|first| std::ptr::eq(first, preset)),
+        }
+    }
+
+    /// Whether the language of `block` is one of `skip_languages`.
+    pub fn skips(&self, block: &CodeBlock) -> bool {
+        self.settings.skip_languages.contains(&block.lang)
+    }
+
+    /// Language of `block` after resolving the `aliases`.
+    fn language<'a>(&'a self, block: &'a CodeBlock) -> &'a str {
+        self.aliases
+            .get(&block.lang)
+            .map_or(block.lang.as_str(), String::as_str)
+    }
+
+    /// Whether a preset of the config matches `block`, including the ones
+    /// left out of this run, e.g. with `--preset`.
+    pub fn covers(&self, block: &CodeBlock) -> bool {
+        let lang = self.language(block);
+        self.presets
+            .values()
+            .chain(&self.filtered_presets)
+            .any(|preset| preset.matches(block, lang))
+    }
+
+    /// Moves the presets not selected by `keep` to the filtered ones.
+    fn filter_presets(&mut self, keep: impl Fn(&str, &PresetConfig) -> bool) {
+        let (kept, filtered) = std::mem::take(&mut self.presets)
+            .into_iter()
+            .partition(|(name, preset)| keep(name, preset));
+        self.presets = kept;
+        self.filtered_presets.extend(filtered.into_values());
+    }
+
+    /// Keeps the presets selected by `keep`, here and in the nested configs.
+    pub fn retain_presets(
+        &mut self,
+        keep: impl Fn(&str, &PresetConfig) -> bool + Send + Sync + 'static,
+    ) {
+        self.filter_presets(&keep);
+        if let Some(source) = &mut self.source {
+            let keep: PresetFilter = Arc::new(keep);
+            source.filter = Some(match source.filter.take() {
+                Some(previous) => {
+                    Arc::new(move |name, preset| previous(name, preset) && keep(name, preset))
+                }
+                None => keep,
+            });
+        }
+    }
+
+    /// Settings of the nested configs of the directories between the config
+    /// file and `file`, merged into this config. `None` when there are none.
+    pub fn nested_for(&self, file: &Path) -> Result<Option<Arc<AppSettings>>> {
+        let Some(source) = &self.source else {
+            return Ok(None);
+        };
+        let dir = absolute(file)?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        if let Some(nested) = source.nested.lock().ok().and_then(|n| n.get(&dir).cloned()) {
+            return Ok(nested);
+        }
+
+        // From the outermost directory to the innermost one
+        let mut configs: Vec<PathBuf> = dir
+            .ancestors()
+            .take_while(|ancestor| ancestor.starts_with(&source.dir) && *ancestor != source.dir)
+            .filter_map(|ancestor| {
+                CONFIG_NAMES
+                    .iter()
+                    .map(|name| ancestor.join(name))
+                    .find(|path| path.is_file())
+            })
+            .collect();
+        configs.reverse();
+
+        let nested = if configs.is_empty() {
+            None
+        } else {
+            let mut value = source.value.clone();
+            for config in &configs {
+                merge(&mut value, read_value(config)?);
+            }
+            let mut settings = settings_from(value, &source.overrides).with_context(|| {
+                format!(
+                    "Invalid config file: {}",
+                    configs[configs.len() - 1].display()
+                )
+            })?;
+            if let Some(keep) = &source.filter {
+                settings.filter_presets(|name, preset| keep(name, preset));
+            }
+            Some(Arc::new(settings))
+        };
+        if let Ok(mut cache) = source.nested.lock() {
+            cache.insert(dir, nested.clone());
+        }
+
+        Ok(nested)
+    }
 }
@@ -0,0 +1,145 @@
+use crate::config::AppSettings;
+use crate::report::Failure;
+use crate::runner::{
+    apply_replacements, process, process_content, FileFilter, RunOptions, RunState,
+};
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// A request of a client, on a single line.
+#[derive(Debug, Deserialize)]
+struct Request {
+    /// Echoed back in the response
+    #[serde(default)]
+    id: serde_json::Value,
+    /// File or directory processed in place, or name of the buffer
+    path: Option<PathBuf>,
+    /// Markdown document processed and sent back, instead of a file
+    content: Option<String>,
+    #[serde(default)]
+    check: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    id: serde_json::Value,
+    /// Exit code `mdcr` would have returned for the same run
+    status: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Serves the requests of the clients connecting to `socket`, with the config
+/// parsed once for all of them.
+pub fn serve(socket: &Path, settings: &AppSettings, files: &FileFilter) -> Result<()> {
+    if UnixStream::connect(socket).is_ok() {
+        bail!("A daemon is already listening on `{}`", socket.display());
+    }
+    // Left behind by a daemon that did not exit cleanly
+    if socket.exists() {
+        fs::remove_file(socket)?;
+    }
+    if let Some(parent) = socket.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on `{}`", socket.display()))?;
+    info!("Listening on `{}`", socket.display());
+
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    scope.spawn(move || {
+                        if let Err(e) = handle_client(stream, settings, files) {
+                            error!("Client error: {e:#}");
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept a client: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_client(stream: UnixStream, settings: &AppSettings, files: &FileFilter) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle_request(request, settings, files),
+            Err(e) => Response {
+                id: serde_json::Value::Null,
+                status: Failure::Config.exit_code(),
+                content: None,
+                error: Some(format!("Invalid request: {e}")),
+            },
+        };
+
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn handle_request(request: Request, settings: &AppSettings, files: &FileFilter) -> Response {
+    debug!("Request {}: {:?}", request.id, request.path);
+    let options = RunOptions {
+        check_only: request.check,
+        files: files.clone(),
+        ..RunOptions::default()
+    };
+    let state = RunState::default();
+
+    let result = match (request.content, request.path) {
+        (Some(content), path) => {
+            let path = path.unwrap_or_else(|| PathBuf::from("<buffer>"));
+            process_content(&path, &content, settings, &options, &state)
+                .map(|replacements| {
+                    if replacements.is_empty() || request.check {
+                        content
+                    } else {
+                        apply_replacements(&content, replacements, settings.settings.final_newline)
+                    }
+                })
+                .map(Some)
+        }
+        (None, Some(path)) => process(path, settings, &options, &state)
+            .result()
+            .map(|()| None),
+        (None, None) => Err(anyhow::anyhow!("The request needs a `path` or a `content`")),
+    };
+    state.finish_presets();
+
+    match result {
+        Ok(content) => Response {
+            id: request.id,
+            status: 0,
+            content,
+            error: None,
+        },
+        Err(e) => Response {
+            id: request.id,
+            status: Failure::of(&e).exit_code(),
+            content: None,
+            error: Some(format!("{e:#}")),
+        },
+    }
+}
@@ -0,0 +1,430 @@
+use crate::cache::{EnvCache, TimingCache};
+use crate::color;
+use crate::config::{self, AppSettings, InputMode, OutputMode, PresetConfig};
+use crate::environment::tool_version;
+use crate::runner::{collect_markdown_files, FileFilter};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Result of a single check, with what to do about it.
+#[derive(Debug)]
+pub struct Finding {
+    pub level: Level,
+    pub message: String,
+}
+
+impl Finding {
+    /// The finding, with its level colored when `color`.
+    pub fn render(&self, color: bool) -> String {
+        let (level, code) = match self.level {
+            Level::Ok => ("ok", color::GREEN),
+            Level::Warning => ("warning", color::YELLOW),
+            Level::Error => ("error", color::RED),
+        };
+        format!(
+            "[{}] {}",
+            color::paint(&format!("{level:>7}"), code, color),
+            self.message
+        )
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(false))
+    }
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn push(&mut self, level: Level, message: impl Into<String>) {
+        self.0.push(Finding {
+            level,
+            message: message.into(),
+        });
+    }
+}
+
+/// Inspects the environment of a run: the config, the programs of the presets,
+/// the target paths, the cache and the parallelism settings.
+pub fn diagnose(config: Option<&Path>, paths: &[PathBuf], cache_dir: &Path) -> Vec<Finding> {
+    let mut findings = Findings::default();
+
+    let settings = check_config(config, &mut findings);
+    if let Some(settings) = &settings {
+        check_presets(settings, &mut findings);
+        check_placeholders(settings, &mut findings);
+        check_languages(settings, &mut findings);
+        check_jobs(settings, &mut findings);
+    }
+    let extensions = settings
+        .and_then(|settings| settings.settings.extensions)
+        .unwrap_or_else(|| vec!["md".to_string()]);
+    match FileFilter::new(&extensions, &[]) {
+        Ok(filter) => check_paths(paths, &filter, &mut findings),
+        Err(e) => findings.push(Level::Error, format!("{e:#}")),
+    }
+    check_cache(cache_dir, &mut findings);
+
+    findings.0
+}
+
+/// Inspects the config alone: the programs of the presets, the placeholders of
+/// their commands and the languages they run on.
+pub fn validate_config(config: &Path) -> Vec<Finding> {
+    let mut findings = Findings::default();
+
+    if let Some(settings) = check_config(Some(config), &mut findings) {
+        check_presets(&settings, &mut findings);
+        check_placeholders(&settings, &mut findings);
+        check_languages(&settings, &mut findings);
+    }
+
+    findings.0
+}
+
+fn check_config(config: Option<&Path>, findings: &mut Findings) -> Option<AppSettings> {
+    let config = match config {
+        Some(config) => config.to_path_buf(),
+        None => match std::env::current_dir()
+            .ok()
+            .and_then(|dir| config::discover(&dir))
+        {
+            Some(config) => {
+                findings.push(
+                    Level::Ok,
+                    format!(
+                        "Using `{}`, found from the current directory",
+                        config.display()
+                    ),
+                );
+                config
+            }
+            None => {
+                findings.push(
+                    Level::Error,
+                    format!(
+                        "No config file, pass one with `--config` or create `{}`",
+                        config::CONFIG_NAMES[0]
+                    ),
+                );
+                return None;
+            }
+        },
+    };
+
+    let content = match fs::read_to_string(&config) {
+        Ok(content) => content,
+        Err(e) => {
+            findings.push(
+                Level::Error,
+                format!("Cannot read config `{}`: {e}", config.display()),
+            );
+            return None;
+        }
+    };
+    match config::parse(&config, &content) {
+        Ok(settings) => {
+            findings.push(
+                Level::Ok,
+                format!(
+                    "Config `{}` defines {} presets",
+                    config.display(),
+                    settings.presets.len()
+                ),
+            );
+            Some(settings)
+        }
+        Err(e) => {
+            findings.push(
+                Level::Error,
+                format!("Invalid config `{}`: {e}", config.display()),
+            );
+            None
+        }
+    }
+}
+
+fn check_presets(settings: &AppSettings, findings: &mut Findings) {
+    let mut presets: Vec<_> = settings.presets.iter().collect();
+    presets.sort_by_key(|(name, _)| *name);
+
+    for (name, preset) in presets {
+        let programs = preset
+            .stages()
+            .chain(&preset.fallback_command)
+            .chain(&preset.comparator)
+            .filter_map(|command| command.first());
+
+        for program in programs {
+            // Programs built from placeholders are only known at run time
+            if program.contains('{') {
+                continue;
+            }
+
+            match find_program(program) {
+                Some(path) => {
+                    let version = tool_version(&path.to_string_lossy())
+                        .unwrap_or_else(|| "unknown version".to_string());
+                    findings.push(
+                        Level::Ok,
+                        format!("Preset `{name}`: `{program}` found ({version})"),
+                    );
+                }
+                None => findings.push(
+                    Level::Error,
+                    format!(
+                        "Preset `{name}`: `{program}` not found in PATH, install it or fix the command of the preset"
+                    ),
+                ),
+            }
+        }
+    }
+}
+
+/// Placeholders of the commands, along with whether the preset provides them.
+const PLACEHOLDERS: [&str; 11] = [
+    "artifact",
+    "basename",
+    "code",
+    "dirname",
+    "file",
+    "lang",
+    "relpath",
+    "repo_root",
+    "suffix",
+    "tmpdir",
+    "workspace",
+];
+
+fn check_placeholders(settings: &AppSettings, findings: &mut Findings) {
+    let mut presets: Vec<_> = settings.presets.iter().collect();
+    presets.sort_by_key(|(name, _)| *name);
+
+    for (name, preset) in presets {
+        if preset.stages().any(Vec::is_empty) {
+            findings.push(
+                Level::Error,
+                format!("Preset `{name}`: the command is empty"),
+            );
+        }
+
+        let commands = preset.stages().chain(&preset.fallback_command);
+        for placeholder in commands.flatten().flat_map(|arg| placeholders(arg)) {
+            if !PLACEHOLDERS.contains(&placeholder) {
+                findings.push(
+                    Level::Error,
+                    format!(
+                        "Preset `{name}`: unknown placeholder `{{{placeholder}}}`, expected one of {}",
+                        PLACEHOLDERS.map(|p| format!("`{{{p}}}`")).join(", ")
+                    ),
+                );
+            } else if let Some(problem) = unavailable(preset, placeholder) {
+                findings.push(
+                    Level::Error,
+                    format!("Preset `{name}`: `{{{placeholder}}}` {problem}"),
+                );
+            }
+        }
+
+        for placeholder in preset
+            .comparator
+            .iter()
+            .flatten()
+            .flat_map(|arg| placeholders(arg))
+        {
+            if !matches!(placeholder, "expected" | "actual") {
+                findings.push(
+                    Level::Error,
+                    format!(
+                        "Preset `{name}`: unknown placeholder `{{{placeholder}}}` in the comparator, expected `{{expected}}` or `{{actual}}`"
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Names of the `{name}` placeholders of a command argument. Other braces, e.g.
+/// of `awk` programs, are not placeholders.
+fn placeholders(arg: &str) -> impl Iterator<Item = &str> {
+    arg.split('{').skip(1).filter_map(|rest| {
+        let (name, _) = rest.split_once('}')?;
+        (!name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_'))
+            .then_some(name)
+    })
+}
+
+/// Why a known placeholder is never expanded for `preset`, if it is not.
+fn unavailable(preset: &PresetConfig, placeholder: &str) -> Option<&'static str> {
+    match placeholder {
+        "code" if !matches!(preset.input_mode, InputMode::Arg) => {
+            Some("is only expanded with `input_mode = \"arg\"`")
+        }
+        "file" | "basename" | "dirname" | "suffix"
+            if !matches!(preset.input_mode, InputMode::File) =>
+        {
+            Some("is only expanded with `input_mode = \"file\"`")
+        }
+        "artifact" if !matches!(preset.output_mode, OutputMode::Artifact) => {
+            Some("is only expanded with `output_mode = \"artifact\"`")
+        }
+        _ => None,
+    }
+}
+
+/// Warns about presets running on the same languages, which is fine for
+/// e.g. a linter and a formatter but usually a copy-paste mistake.
+fn check_languages(settings: &AppSettings, findings: &mut Findings) {
+    let mut presets: Vec<_> = settings.presets.iter().collect();
+    presets.sort_by_key(|(name, _)| *name);
+
+    for (index, (name, preset)) in presets.iter().enumerate() {
+        for (position, language) in preset.languages.iter().enumerate() {
+            if preset.languages[..position].contains(language) {
+                findings.push(
+                    Level::Warning,
+                    format!("Preset `{name}`: language `{language}` is listed twice"),
+                );
+            }
+        }
+
+        for (other, other_preset) in &presets[index + 1..] {
+            let shared: Vec<_> = preset
+                .languages
+                .iter()
+                .filter(|language| other_preset.languages.contains(language))
+                .map(|language| format!("`{language}`"))
+                .collect();
+            if !shared.is_empty() {
+                findings.push(
+                    Level::Warning,
+                    format!(
+                        "Presets `{name}` and `{other}` both run on {} blocks",
+                        shared.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Path of `program`, looked up in `PATH` unless it contains a directory.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return program.is_file().then(|| program.to_path_buf());
+    }
+
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .flat_map(|dir| {
+            [
+                dir.join(program),
+                dir.join(program)
+                    .with_extension(std::env::consts::EXE_EXTENSION),
+            ]
+        })
+        .find(|path| path.is_file())
+}
+
+fn check_jobs(settings: &AppSettings, findings: &mut Findings) {
+    let cpus = std::thread::available_parallelism().map_or(1, usize::from);
+
+    match settings.settings.jobs {
+        Some(0) => findings.push(
+            Level::Warning,
+            "`jobs = 0` runs one job per CPU, remove it or set a number of jobs",
+        ),
+        Some(jobs) if jobs > cpus => findings.push(
+            Level::Warning,
+            format!("`jobs = {jobs}` exceeds the {cpus} CPUs, commands will compete for them"),
+        ),
+        Some(jobs) => findings.push(Level::Ok, format!("{jobs} jobs, out of {cpus} CPUs")),
+        None => findings.push(Level::Ok, format!("{cpus} jobs, one per CPU")),
+    }
+}
+
+fn check_paths(paths: &[PathBuf], filter: &FileFilter, findings: &mut Findings) {
+    for path in paths {
+        let files = match collect_markdown_files(path, filter) {
+            Ok(files) => files,
+            Err(e) => {
+                findings.push(
+                    Level::Error,
+                    format!("Cannot list `{}`: {e:#}", path.display()),
+                );
+                continue;
+            }
+        };
+
+        let read_only: Vec<_> = files
+            .iter()
+            .filter(|file| fs::metadata(file).is_ok_and(|m| m.permissions().readonly()))
+            .collect();
+        match read_only.first() {
+            Some(file) => findings.push(
+                Level::Error,
+                format!(
+                    "{} of the {} Markdown files of `{}` are read-only (e.g. `{}`), they cannot be updated",
+                    read_only.len(),
+                    files.len(),
+                    path.display(),
+                    file.display()
+                ),
+            ),
+            None if files.is_empty() => findings.push(
+                Level::Warning,
+                format!("No Markdown files in `{}`", path.display()),
+            ),
+            None => findings.push(
+                Level::Ok,
+                format!(
+                    "{} Markdown files in `{}` are writable",
+                    files.len(),
+                    path.display()
+                ),
+            ),
+        }
+    }
+}
+
+fn check_cache(cache_dir: &Path, findings: &mut Findings) {
+    let corrupt = [
+        TimingCache::load(cache_dir).err(),
+        EnvCache::load(cache_dir).err(),
+    ];
+    let corrupt: Vec<_> = corrupt.into_iter().flatten().collect();
+    for e in &corrupt {
+        findings.push(
+            Level::Warning,
+            format!("{e:#}, it is ignored: delete it to start afresh"),
+        );
+    }
+
+    // The directory is created on the first run
+    let writable = !cache_dir.exists() || tempfile::NamedTempFile::new_in(cache_dir).is_ok();
+    if !writable {
+        findings.push(
+            Level::Warning,
+            format!(
+                "Cache directory `{}` is not writable, timings and environments are not recorded",
+                cache_dir.display()
+            ),
+        );
+    } else if corrupt.is_empty() {
+        findings.push(
+            Level::Ok,
+            format!("Cache directory `{}` is healthy", cache_dir.display()),
+        );
+    }
+}
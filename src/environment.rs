@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::process::{Command, Stdio};
+
+/// Environment a command was executed with. Variables are stored as hashes,
+/// as they may hold secrets.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct EnvSnapshot {
+    pub cwd: String,
+    pub env: BTreeMap<String, String>,
+    pub program: String,
+    pub tool_version: Option<String>,
+}
+
+impl EnvSnapshot {
+    pub fn capture(command: &Command, tool_version: Option<String>) -> Self {
+        // Inherited variables (e.g. `LANG`, `TZ`, `HOME`) change outputs as
+        // much as the ones of the preset
+        let mut env: BTreeMap<String, String> = std::env::vars_os()
+            .map(|(key, value)| {
+                (
+                    key.to_string_lossy().to_string(),
+                    hash(&value.to_string_lossy()),
+                )
+            })
+            .collect();
+        for (key, value) in command.get_envs() {
+            let key = key.to_string_lossy().to_string();
+            match value {
+                Some(value) => env.insert(key, hash(&value.to_string_lossy())),
+                None => env.remove(&key),
+            };
+        }
+
+        let cwd = command
+            .get_current_dir()
+            .map(|dir| dir.to_path_buf())
+            .or_else(|| std::env::current_dir().ok())
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default();
+
+        Self {
+            cwd,
+            env,
+            program: command.get_program().to_string_lossy().to_string(),
+            tool_version,
+        }
+    }
+
+    /// Lists the differences between a previous snapshot and this one.
+    pub fn diff(&self, previous: &EnvSnapshot) -> Vec<String> {
+        let mut changes = Vec::new();
+
+        if self.cwd != previous.cwd {
+            changes.push(format!("cwd: `{}` -> `{}`", previous.cwd, self.cwd));
+        }
+        if self.program != previous.program {
+            changes.push(format!(
+                "program: `{}` -> `{}`",
+                previous.program, self.program
+            ));
+        }
+        if self.tool_version != previous.tool_version {
+            changes.push(format!(
+                "tool version: `{}` -> `{}`",
+                previous.tool_version.as_deref().unwrap_or("unknown"),
+                self.tool_version.as_deref().unwrap_or("unknown")
+            ));
+        }
+
+        // Only the names are logged, the values may be secrets
+        for (key, value) in &previous.env {
+            match self.env.get(key) {
+                None => changes.push(format!("env: -{key}")),
+                Some(current) if current != value => changes.push(format!("env: ~{key}")),
+                _ => {}
+            }
+        }
+        for key in self.env.keys() {
+            if !previous.env.contains_key(key) {
+                changes.push(format!("env: +{key}"));
+            }
+        }
+
+        changes
+    }
+}
+
+fn hash(value: &str) -> String {
+    Sha256::digest(value.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Returns the first line printed by `<program> --version`, if any.
+pub fn tool_version(program: &str) -> Option<String> {
+    let output = Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(String::from)
+}
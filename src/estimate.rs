@@ -0,0 +1,86 @@
+use crate::cache::TimingCache;
+use crate::codeblock::parse_code_blocks;
+use crate::config::AppSettings;
+use crate::runner::{collect_markdown_files, FileFilter};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct Estimate {
+    pub commands: usize,
+    pub command_time: Duration,
+    pub threads: usize,
+    /// Presets without any recorded timing, with their number of commands
+    pub unknown: BTreeMap<String, usize>,
+}
+
+impl Estimate {
+    pub fn wall_time(&self) -> Duration {
+        self.command_time / self.threads.max(1) as u32
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Commands to execute: {}", self.commands)?;
+        writeln!(
+            f,
+            "Estimated duration: {:.1}s ({:.1}s of command time across {} threads)",
+            self.wall_time().as_secs_f64(),
+            self.command_time.as_secs_f64(),
+            self.threads
+        )?;
+        for (preset, commands) in &self.unknown {
+            writeln!(
+                f,
+                "No timing data for preset `{preset}` ({commands} commands not estimated)"
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Predicts the cost of a run from the timings recorded by previous runs,
+/// without executing any command.
+pub fn estimate(
+    paths: &[PathBuf],
+    config: &AppSettings,
+    cache: &TimingCache,
+    files: &FileFilter,
+) -> Result<Estimate> {
+    let mut estimate = Estimate {
+        threads: rayon::current_num_threads(),
+        ..Estimate::default()
+    };
+
+    for path in paths {
+        for file in collect_markdown_files(path, files)? {
+            let content = fs::read_to_string(&file)?;
+
+            for block in parse_code_blocks(&file, &content) {
+                if block.skip.is_some() {
+                    continue;
+                }
+
+                for (preset, preset_cfg) in &config.presets {
+                    if !config.runs(preset_cfg, &block) {
+                        continue;
+                    }
+
+                    estimate.commands += 1;
+                    match cache.average(preset) {
+                        Some(average) => estimate.command_time += average,
+                        None => *estimate.unknown.entry(preset.clone()).or_default() += 1,
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(estimate)
+}
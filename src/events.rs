@@ -0,0 +1,161 @@
+use crate::codeblock::CodeBlock;
+use anyhow::{Context, Result};
+use log::warn;
+use serde_json::{json, Value};
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Status of a code block for a preset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    Running,
+    Unchanged,
+    Updated,
+    Mismatch,
+    Failed,
+    Skipped,
+}
+
+impl BlockStatus {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Unchanged => "unchanged",
+            Self::Updated => "updated",
+            Self::Mismatch => "mismatch",
+            Self::Failed => "failed",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+
+/// Progress of a code block, sent to the observers of a run (e.g. `--tui`).
+#[derive(Debug, Clone)]
+pub struct BlockEvent {
+    pub path: PathBuf,
+    pub block_id: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub preset: String,
+    pub status: BlockStatus,
+    pub duration: Option<Duration>,
+    /// Diff of the block for updates and mismatches, error message for failures
+    pub details: String,
+    /// Lines replacing the block, fences included, for updates and mismatches
+    pub replacement: Option<String>,
+}
+
+impl BlockEvent {
+    pub fn new(block: &CodeBlock, preset: &str, status: BlockStatus) -> Self {
+        Self {
+            path: block.path.clone(),
+            block_id: block.block_id.clone(),
+            start_line: block.start_line,
+            end_line: block.end_line,
+            preset: preset.to_string(),
+            status,
+            duration: None,
+            details: String::new(),
+            replacement: None,
+        }
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    pub fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = details.into();
+        self
+    }
+
+    pub fn with_replacement(mut self, replacement: impl Into<String>) -> Self {
+        self.replacement = Some(replacement.into());
+        self
+    }
+}
+
+/// JSON Lines log of the events of a run (`--events`), flushed line by line so
+/// that it can be followed while the run progresses.
+pub struct EventLog {
+    writer: Mutex<LineWriter<File>>,
+    include_content: bool,
+}
+
+impl EventLog {
+    /// Creates the log, starting with a `run` event holding the `--meta` pairs.
+    pub fn create(
+        path: &Path,
+        metadata: &BTreeMap<String, String>,
+        include_content: bool,
+    ) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create event log: {}", path.display()))?;
+        let log = Self {
+            writer: Mutex::new(LineWriter::new(file)),
+            include_content,
+        };
+        log.write_line(&json!({ "type": "run", "metadata": metadata }))?;
+
+        Ok(log)
+    }
+
+    pub fn write(&self, event: &BlockEvent) {
+        // A broken log must not interrupt the run
+        if let Err(e) = self.write_line(&self.block_json(event)) {
+            warn!("Failed to write event log: {e:#}");
+        }
+    }
+
+    fn write_line(&self, value: &Value) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(writer, "{value}")?;
+
+        Ok(())
+    }
+
+    /// Lines are 1-based, from the opening fence to the closing one.
+    fn block_json(&self, event: &BlockEvent) -> Value {
+        let mut value = json!({
+            "type": "block",
+            "path": event.path,
+            "block_id": event.block_id,
+            "start_line": event.start_line + 1,
+            "end_line": event.end_line,
+            "preset": event.preset,
+            "status": event.status.name(),
+        });
+        if let Some(duration) = event.duration {
+            value["duration_ms"] = json!(duration.as_millis() as u64);
+        }
+        match event.status {
+            BlockStatus::Updated | BlockStatus::Mismatch => value["diff"] = json!(event.details),
+            BlockStatus::Failed | BlockStatus::Skipped => value["message"] = json!(event.details),
+            BlockStatus::Running | BlockStatus::Unchanged => {}
+        }
+        if self.include_content {
+            if let Some(replacement) = &event.replacement {
+                value["replacement"] = json!(replacement);
+            }
+        }
+
+        value
+    }
+}
+
+/// Unified diff between the code of a block and the output of its command.
+pub fn block_diff(code: &str, output: &str) -> String {
+    TextDiff::from_lines(
+        &format!("{}\n", code.trim()),
+        &format!("{}\n", output.trim()),
+    )
+    .unified_diff()
+    .header("code", "output")
+    .to_string()
+}
@@ -0,0 +1,110 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lines of the YAML front matter of `content`, between its `---` fences.
+fn front_matter(content: &str) -> Option<Vec<&str>> {
+    let mut lines = content.lines();
+    if lines.next()?.trim_end() != "---" {
+        return None;
+    }
+
+    let mut front_matter = Vec::new();
+    for line in lines {
+        if matches!(line.trim_end(), "---" | "...") {
+            return Some(front_matter);
+        }
+        front_matter.push(line);
+    }
+
+    None
+}
+
+/// Value of a top-level `field: value` line of the front matter, unquoted.
+pub fn field<'a>(content: &'a str, field: &str) -> Option<&'a str> {
+    front_matter(content)?.into_iter().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key == field).then(|| value.trim().trim_matches(['"', '\'']))
+    })
+}
+
+/// Sets a top-level field of the front matter, adding it at the end when
+/// missing. Documents without front matter are left as is.
+pub fn set_field(content: &str, field: &str, value: &str) -> Option<String> {
+    let length = front_matter(content)?.len();
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    let line = format!("{field}: {value}");
+
+    // The front matter spans the lines 1 to `length`, after the opening fence
+    match (1..=length).find(|&i| {
+        lines[i]
+            .split_once(':')
+            .is_some_and(|(key, _)| key == field)
+    }) {
+        Some(i) => lines[i] = line,
+        None => lines.insert(length + 1, line),
+    }
+
+    let mut document = lines.join("\n");
+    if content.ends_with('\n') {
+        document.push('\n');
+    }
+
+    Some(document)
+}
+
+/// Today's date, as `YYYY-MM-DD` in UTC.
+pub fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() / 86_400)
+        .unwrap_or_default() as i64;
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Number of days from `date` (`YYYY-MM-DD`) to today.
+pub fn age_in_days(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.get(..2)?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let today = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() / 86_400;
+
+    Some(today as i64 - days_from_civil(year, month, day))
+}
+
+// Conversions between dates of the proleptic Gregorian calendar and days since
+// 1970-01-01, from http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
@@ -0,0 +1,118 @@
+use crate::ignore::normalize;
+use crate::patch::render_patch;
+use crate::runner::Document;
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::NamedTempFile;
+
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git").args(args).output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Lists the files under `path` at the revision `rev`, relative to the
+/// current directory.
+pub fn list_files(rev: &str, path: &Path) -> Result<Vec<PathBuf>> {
+    let path = path.to_string_lossy();
+    let files: Vec<PathBuf> = git(&["ls-tree", "-r", "--name-only", rev, "--", &path])?
+        .lines()
+        .map(PathBuf::from)
+        .collect();
+
+    if files.is_empty() {
+        return Err(anyhow!("Path `{path}` does not exist at revision `{rev}`"));
+    }
+
+    Ok(files)
+}
+
+/// Reads the content of `file` at the revision `rev`.
+pub fn show_file(rev: &str, file: &Path) -> Result<String> {
+    git(&["show", &format!("{rev}:./{}", file.display())])
+}
+
+/// Lists the files under `paths` added, copied, modified or renamed in the
+/// index, relative to the current directory.
+pub fn staged_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    diff_files(&["--cached"], paths)
+}
+
+/// Lists the files under `paths` added, copied, modified or renamed since the
+/// revision `rev`, relative to the current directory: in the commits since
+/// the merge base with `rev`, in the index and in the working tree, along with
+/// the untracked files.
+pub fn changed_files(rev: &str, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = diff_files(&[&format!("{rev}...HEAD")], paths)?;
+    files.extend(diff_files(&["HEAD"], paths)?);
+    files.extend(untracked_files(paths)?);
+    // Files of the commits may have been removed from the working tree since
+    files.retain(|file| file.exists());
+    files.sort();
+    files.dedup();
+
+    Ok(files)
+}
+
+fn untracked_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut args = vec!["ls-files", "--others", "--exclude-standard", "--"];
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    args.extend(paths.iter().map(String::as_str));
+
+    Ok(git(&args)?.lines().map(PathBuf::from).collect())
+}
+
+fn diff_files(options: &[&str], paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut args = vec!["diff"];
+    args.extend(options);
+    args.extend(["--name-only", "--diff-filter=ACMR", "--relative", "--"]);
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    args.extend(paths.iter().map(String::as_str));
+
+    Ok(git(&args)?.lines().map(PathBuf::from).collect())
+}
+
+/// Stages the changes of the rewritten `documents`, relative to the current
+/// directory, on top of their staged content, so that their unstaged changes
+/// stay unstaged. Fails when the staged content differs around the rewritten
+/// blocks.
+pub fn stage(documents: &[Document]) -> Result<()> {
+    // Paths of patches applied to the index are relative to the top level
+    let prefix = git(&["rev-parse", "--show-prefix"])?;
+    let toplevel = git(&["rev-parse", "--show-toplevel"])?;
+    let documents: Vec<Document> = documents
+        .iter()
+        .map(|document| Document {
+            path: Path::new(prefix.trim_end()).join(normalize(&document.path)),
+            ..document.clone()
+        })
+        .collect();
+
+    let patch = NamedTempFile::new()?;
+    fs::write(patch.path(), render_patch(&documents))?;
+    git(&[
+        "-C",
+        toplevel.trim_end(),
+        "apply",
+        "--cached",
+        &patch.path().to_string_lossy(),
+    ])
+    .context("Failed to stage the updated files, their staged content differs from their content around the updated blocks")
+    .map(|_| ())
+}
@@ -0,0 +1,128 @@
+use crate::codeblock::{parse_code_blocks, CodeBlock};
+use crate::config::{self, AppSettings, IgnoreEntry};
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+/// Hash identifying the content of a code block in `[[ignore]]` entries.
+pub fn block_hash(code: &str) -> String {
+    let digest = Sha256::digest(code.as_bytes());
+
+    digest[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+impl IgnoreEntry {
+    fn matches(&self, block: &CodeBlock, preset: &str, hash: &str) -> bool {
+        self.hash == hash
+            && config::absolute(&self.file).ok() == config::absolute(&block.path).ok()
+            && (self.presets.is_empty() || self.presets.iter().any(|p| p == preset))
+    }
+}
+
+/// Whether an `[[ignore]]` entry exempts `block` from `preset`.
+pub fn is_ignored(config: &AppSettings, block: &CodeBlock, preset: &str) -> bool {
+    if config.ignore.is_empty() {
+        return false;
+    }
+
+    // Entries hash the code of the document, not the output of chained presets
+    let hash = block_hash(&block.original_code);
+    config
+        .ignore
+        .iter()
+        .any(|entry| entry.matches(block, preset, &hash))
+}
+
+/// Path without `.` components, so that `./docs/a.md` matches `docs/a.md`.
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .collect()
+}
+
+/// Appends an `[[ignore]]` entry for the code block at `location`
+/// (`<file>:<line>`) to the config file, exempting it from `presets`, or from
+/// all presets when empty. The file of the entry is relative to the directory
+/// of the config file when it is in it.
+pub fn add_ignore(config: &Path, location: &str, presets: &[String]) -> Result<()> {
+    let (file, line) = location
+        .rsplit_once(':')
+        .and_then(|(file, line)| Some((file, line.parse::<usize>().ok()?)))
+        .ok_or_else(|| anyhow!("Invalid location `{location}`, expected `<file>:<line>`"))?;
+    let file = normalize(Path::new(file));
+
+    let content = fs::read_to_string(&file)
+        .with_context(|| format!("Failed to read file: {}", file.display()))?;
+    // Lines are 1-based, from the opening fence to the closing one
+    let block = parse_code_blocks(&file, &content)
+        .into_iter()
+        .find(|block| block.start_line < line && line <= block.end_line)
+        .ok_or_else(|| anyhow!("No code block at line {line} of `{}`", file.display()))?;
+
+    let config_dir = config::absolute(config)?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let absolute = config::absolute(&file)?;
+    let entry = IgnoreEntry {
+        file: absolute
+            .strip_prefix(&config_dir)
+            .map(Path::to_path_buf)
+            .unwrap_or(absolute.clone()),
+        hash: block_hash(&block.code),
+        presets: presets.to_vec(),
+    };
+    let content = fs::read_to_string(config)
+        .with_context(|| format!("Failed to read config file: {}", config.display()))?;
+    if !config::is_toml(config, &content) {
+        bail!(
+            "Only TOML configs can be edited, add the entry to `{}` by hand",
+            config.display()
+        );
+    }
+    let settings = config::parse(config, &content)?;
+    if settings.ignore.iter().any(|existing| {
+        normalize(&existing.file) == entry.file
+            && existing.hash == entry.hash
+            && existing.presets == entry.presets
+    }) {
+        info!(
+            "The code block is already ignored in `{}`",
+            config.display()
+        );
+        return Ok(());
+    }
+
+    let mut toml = format!(
+        "\n[[ignore]]\nfile = {}\nhash = {}\n",
+        toml::Value::from(entry.file.to_string_lossy().to_string()),
+        toml::Value::from(entry.hash.clone())
+    );
+    if !presets.is_empty() {
+        toml.push_str(&format!(
+            "presets = {}\n",
+            toml::Value::from(presets.to_vec())
+        ));
+    }
+
+    fs::OpenOptions::new()
+        .append(true)
+        .open(config)
+        .and_then(|mut config| config.write_all(toml.as_bytes()))
+        .with_context(|| format!("Failed to write config file: {}", config.display()))?;
+    info!(
+        "Ignoring the code block at `{}:{}-{}` ({})",
+        file.display(),
+        block.start_line + 1,
+        block.end_line,
+        entry.hash
+    );
+
+    Ok(())
+}
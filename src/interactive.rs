@@ -0,0 +1,79 @@
+use crate::codeblock::CodeBlock;
+use crate::color;
+use crate::runner::RunState;
+use anyhow::Result;
+use similar::TextDiff;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Prompts of concurrent files must not interleave.
+static PROMPT: Mutex<()> = Mutex::new(());
+
+/// Shows the diff of each replacement of `path` and asks whether to apply it,
+/// returning the accepted ones. Quitting cancels the run.
+pub fn approve(
+    path: &Path,
+    content: &str,
+    replacements: Vec<CodeBlock>,
+    state: &RunState,
+) -> Result<Vec<CodeBlock>> {
+    let _prompt = PROMPT.lock().unwrap_or_else(|e| e.into_inner());
+    let lines: Vec<&str> = content.lines().collect();
+    let mut stdin = std::io::stdin().lock();
+    let mut stderr = std::io::stderr().lock();
+    let color = color::stderr();
+    let mut accepted = Vec::new();
+
+    // Replacements come from the last block to the first one
+    for block in replacements.into_iter().rev() {
+        if state.is_stopped() {
+            break;
+        }
+
+        let original = lines
+            .get(block.start_line..block.end_line.min(lines.len()))
+            .unwrap_or_default()
+            .join("\n");
+        let location = format!(
+            "{}:{}-{}",
+            path.display(),
+            block.start_line + 1,
+            block.end_line
+        );
+        let diff = TextDiff::from_lines(&format!("{original}\n"), &format!("{}\n", block.code))
+            .unified_diff()
+            .header(&location, &location)
+            .to_string();
+        write!(stderr, "{}", color::diff(&diff, color))?;
+
+        loop {
+            write!(stderr, "Apply this change to `{location}` [y,n,q,?]? ")?;
+            stderr.flush()?;
+
+            let mut answer = String::new();
+            if stdin.read_line(&mut answer)? == 0 {
+                // No more answers, e.g. stdin was closed
+                writeln!(stderr)?;
+                state.cancel();
+                break;
+            }
+            match answer.trim() {
+                "y" => accepted.push(block.clone()),
+                "n" => {}
+                "q" => state.cancel(),
+                _ => {
+                    writeln!(
+                        stderr,
+                        "y - apply this change\nn - do not apply this change\nq - quit, do not apply this change nor any of the remaining ones"
+                    )?;
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+
+    accepted.reverse();
+    Ok(accepted)
+}
@@ -0,0 +1,127 @@
+use crate::color;
+use crate::progress;
+use anyhow::{Context, Result};
+use env_logger::fmt::Formatter;
+use env_logger::{Builder, Env, Target, WriteStyle};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock, RwLockReadGuard};
+
+/// Logs to stderr at `level`, and every record to `file` when set.
+struct Logger {
+    stderr: RwLock<env_logger::Logger>,
+    file: Option<env_logger::Logger>,
+    json: bool,
+    /// Whether the level of stderr was set on the command line, which the
+    /// config must not override
+    explicit_level: bool,
+}
+
+static LOGGER: OnceLock<&'static Logger> = OnceLock::new();
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr().enabled(metadata) || self.file.as_ref().is_some_and(|f| f.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        self.stderr().log(record);
+        if let Some(file) = &self.file {
+            file.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr().flush();
+        if let Some(file) = &self.file {
+            file.flush();
+        }
+    }
+}
+
+impl Logger {
+    fn stderr(&self) -> RwLockReadGuard<'_, env_logger::Logger> {
+        self.stderr.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.file
+            .as_ref()
+            .map_or(LevelFilter::Off, |file| file.filter())
+            .max(self.stderr().filter())
+    }
+}
+
+/// Installs the logger, `RUST_LOG` taking precedence over `level` on stderr.
+/// Without `level`, stderr logs warnings unless the config sets another level.
+pub fn init(level: Option<&str>, format: &str, file: Option<&Path>) -> Result<()> {
+    let json = format == "json";
+    let file = file
+        .map(|path| {
+            let log = File::create(path)
+                .with_context(|| format!("Failed to create log file: {}", path.display()))?;
+            let mut file = Builder::new();
+            file.filter_level(LevelFilter::Trace)
+                .write_style(WriteStyle::Never)
+                .target(Target::Pipe(Box::new(log)));
+            if json {
+                file.format(json_line);
+            }
+            anyhow::Ok(file.build())
+        })
+        .transpose()?;
+
+    let logger: &'static Logger = Box::leak(Box::new(Logger {
+        stderr: RwLock::new(stderr_logger(level.unwrap_or("warn"), json)),
+        file,
+        json,
+        explicit_level: level.is_some(),
+    }));
+    log::set_logger(logger)?;
+    log::set_max_level(logger.max_level());
+    let _ = LOGGER.set(logger);
+
+    Ok(())
+}
+
+/// Sets the level of stderr from the `log` setting of the config, unless it
+/// was set on the command line.
+pub fn set_default_level(level: &str) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    if logger.explicit_level {
+        return;
+    }
+
+    *logger.stderr.write().unwrap_or_else(|e| e.into_inner()) = stderr_logger(level, logger.json);
+    log::set_max_level(logger.max_level());
+}
+
+fn stderr_logger(level: &str, json: bool) -> env_logger::Logger {
+    let mut stderr = Builder::from_env(Env::default().default_filter_or(level));
+    stderr
+        .write_style(if color::stderr() {
+            WriteStyle::Always
+        } else {
+            WriteStyle::Never
+        })
+        .target(Target::Pipe(Box::new(progress::LogWriter)));
+    if json {
+        stderr.format(json_line);
+    }
+    stderr.build()
+}
+
+/// Formats a log record as one JSON object per line, for log aggregators.
+fn json_line(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let line = serde_json::json!({
+        "timestamp": buf.timestamp().to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    writeln!(buf, "{line}")
+}
@@ -0,0 +1,273 @@
+use crate::codeblock::CodeBlock;
+use crate::config::{AppSettings, Severity};
+use crate::runner::{process_content, RunOptions, RunState};
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+const CODE_ACTION_TITLE: &str = "Update block from command output";
+
+/// An open document, with the replacements computed when it was last saved.
+struct Document {
+    text: String,
+    replacements: Vec<CodeBlock>,
+}
+
+/// Speaks the Language Server Protocol on stdin and stdout: mismatching blocks
+/// and failing commands are published as diagnostics when a document is opened
+/// or saved, and mismatching blocks can be updated with a code action.
+pub fn serve(settings: &AppSettings) -> Result<()> {
+    let mut input = std::io::stdin().lock();
+    let mut output = std::io::stdout().lock();
+    let mut documents: HashMap<String, Document> = HashMap::new();
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        debug!("LSP message: {method}");
+
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": true } },
+                    "codeActionProvider": true,
+                },
+                "serverInfo": { "name": "mdcr", "version": clap::crate_version!() },
+            })),
+            "shutdown" => Some(Value::Null),
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                let document = check(uri, text.to_string(), settings, &mut output)?;
+                documents.insert(uri.to_string(), document);
+                None
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                // Full synchronization, the last change holds the whole text
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(document), Some(text)) = (documents.get_mut(uri), text) {
+                    document.text = text.to_string();
+                    // Line numbers are stale until the next save
+                    document.replacements.clear();
+                }
+                None
+            }
+            "textDocument/didSave" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = params["text"]
+                    .as_str()
+                    .map(String::from)
+                    .or_else(|| documents.get(uri).map(|document| document.text.clone()));
+                if let Some(text) = text {
+                    let document = check(uri, text, settings, &mut output)?;
+                    documents.insert(uri.to_string(), document);
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                documents.remove(uri);
+                publish_diagnostics(&mut output, uri, Vec::new())?;
+                None
+            }
+            "textDocument/codeAction" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let start = params["range"]["start"]["line"].as_u64().unwrap_or(0) as usize;
+                let end = params["range"]["end"]["line"].as_u64().unwrap_or(0) as usize;
+                let actions: Vec<Value> = documents
+                    .get(uri)
+                    .map(|document| {
+                        document
+                            .replacements
+                            .iter()
+                            .filter(|block| block.start_line <= end && start < block.end_line)
+                            .map(|block| code_action(uri, block))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Some(Value::Array(actions))
+            }
+            _ => {
+                if !message["id"].is_null() {
+                    write_message(
+                        &mut output,
+                        &json!({
+                            "jsonrpc": "2.0",
+                            "id": message["id"],
+                            "error": { "code": -32601, "message": format!("Unsupported method `{method}`") },
+                        }),
+                    )?;
+                }
+                None
+            }
+        };
+
+        if let Some(result) = result {
+            write_message(
+                &mut output,
+                &json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the presets on the document and publishes its diagnostics.
+fn check(
+    uri: &str,
+    text: String,
+    settings: &AppSettings,
+    output: &mut impl Write,
+) -> Result<Document> {
+    let path = uri_to_path(uri);
+    let state = RunState::default();
+    // Replacements are computed without check mode, to offer them as code
+    // actions, documents are never written
+    let replacements =
+        process_content(&path, &text, settings, &RunOptions::default(), &state).unwrap_or_default();
+    state.finish_presets();
+
+    let report = state.into_report();
+    let mismatches = report.mismatches.iter().map(|mismatch| {
+        diagnostic(
+            mismatch.start_line,
+            mismatch.end_line,
+            mismatch.severity,
+            &mismatch.preset,
+            "Code block differs from the command output",
+        )
+    });
+    let failures = report.failures.iter().map(|failure| {
+        diagnostic(
+            failure.start_line,
+            failure.end_line,
+            failure.severity,
+            &failure.preset,
+            &failure.message,
+        )
+    });
+    publish_diagnostics(output, uri, mismatches.chain(failures).collect())?;
+
+    Ok(Document { text, replacements })
+}
+
+fn diagnostic(
+    start_line: usize,
+    end_line: usize,
+    severity: Severity,
+    preset: &str,
+    message: &str,
+) -> Value {
+    json!({
+        "range": range(start_line, end_line),
+        // Error and Warning in the protocol
+        "severity": if severity.is_error() { 1 } else { 2 },
+        "source": "mdcr",
+        "code": preset,
+        "message": message,
+    })
+}
+
+fn code_action(uri: &str, block: &CodeBlock) -> Value {
+    json!({
+        "title": CODE_ACTION_TITLE,
+        "kind": "quickfix",
+        "edit": {
+            "changes": {
+                uri: [{ "range": range(block.start_line, block.end_line), "newText": format!("{}\n", block.code) }],
+            },
+        },
+    })
+}
+
+/// Range of the lines of a block, from its opening fence to its closing one.
+fn range(start_line: usize, end_line: usize) -> Value {
+    json!({
+        "start": { "line": start_line, "character": 0 },
+        "end": { "line": end_line, "character": 0 },
+    })
+}
+
+fn publish_diagnostics(output: &mut impl Write, uri: &str, diagnostics: Vec<Value>) -> Result<()> {
+    write_message(
+        output,
+        &json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }),
+    )
+}
+
+/// Path of a `file://` URI, with its percent-encoded bytes decoded.
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    PathBuf::from(String::from_utf8_lossy(&bytes).to_string())
+}
+
+/// Reads a message framed by a `Content-Length` header, `None` at the end of
+/// the input.
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>()?);
+        }
+    }
+
+    let Some(length) = length else {
+        bail!("Invalid LSP message: missing `Content-Length` header");
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .context("Invalid LSP message")
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+    let body = serde_json::to_string(message)?;
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()?;
+
+    Ok(())
+}
@@ -1,39 +1,1158 @@
+mod artifact;
+mod cache;
 mod cli;
 mod codeblock;
+mod color;
 mod command;
 mod config;
+#[cfg(unix)]
+mod daemon;
+mod doctor;
+mod environment;
+mod estimate;
+mod events;
+mod frontmatter;
+mod git;
+mod ignore;
+mod interactive;
+mod logging;
+mod lsp;
+mod patch;
+mod progress;
+mod report;
+mod review;
 mod runner;
+mod tui;
+mod variables;
+mod watch;
 
-use crate::config::AppSettings;
-use crate::runner::process;
-use anyhow::Result;
-use cli::Cli;
+use crate::cache::{EnvCache, TimingCache};
+use crate::codeblock::parse_code_blocks;
+use crate::command::{explain_command, run_hook, ProjectPaths};
+use crate::config::{AppSettings, InputMode, Overrides, PresetConfig};
+#[cfg(unix)]
+use crate::daemon::serve;
+use crate::estimate::estimate;
+use crate::events::EventLog;
+use crate::ignore::{add_ignore, is_ignored};
+use crate::patch::{apply_patch, relative_to_project, render_patch};
+use crate::report::{
+    render_checkstyle, render_github_annotations, render_step_summary, render_timings, Failure,
+    RunReport,
+};
+use crate::runner::{
+    apply_replacements, collect_markdown_files, file_outcome, outermost_paths, process,
+    process_content, process_grouped_by_preset, process_revision, FileFilter, OutputTarget,
+    RunOptions, RunState,
+};
+use crate::watch::{Change, Watcher};
+use anyhow::{Context, Result};
+#[cfg(unix)]
+use cli::DaemonArgs;
+use cli::{
+    ApplyArgs, CheckArgs, Cli, Commands, CompletionsArgs, DoctorArgs, ExplainArgs, IgnoreCommands,
+    ImpactArgs, ListBlocksArgs, LspArgs, ManArgs, ReviewArgs, RunArgs, ValidateArgs,
+    ValidateConfigArgs,
+};
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 
-fn main() -> Result<()> {
-    let args = Cli::parse();
-    let mut log = args.log;
+fn main() {
+    let args = Cli::try_parse().unwrap_or_else(|e| {
+        if e.use_stderr() {
+            let _ = e.print();
+            std::process::exit(Failure::Config.exit_code());
+        }
+        e.exit()
+    });
+    // `-v` and `-q` take precedence over `--log`
+    let log = match (args.quiet, args.verbose) {
+        (true, _) => Some("error"),
+        (false, 0) => args.log.as_deref(),
+        (false, 1) => Some("info"),
+        (false, 2) => Some("debug"),
+        (false, _) => Some("trace"),
+    };
+
+    color::init(&args.color);
+    let json_logs = args.log_format == "json";
+    if let Err(e) = logging::init(log, &args.log_format, args.log_file.as_deref()) {
+        eprintln!("Error: {e:#}");
+        std::process::exit(Failure::Config.exit_code());
+    }
+
+    let result = match args.command {
+        Some(Commands::Apply(apply_args)) => apply(apply_args),
+        Some(Commands::Check(check_args)) => check(*check_args),
+        #[cfg(unix)]
+        Some(Commands::Daemon(daemon_args)) => daemon(daemon_args),
+        Some(Commands::Completions(completions_args)) => completions(completions_args),
+        Some(Commands::Doctor(doctor_args)) => doctor(doctor_args),
+        Some(Commands::ListBlocks(list_args)) => list_blocks(list_args),
+        Some(Commands::Man(man_args)) => man(man_args),
+        Some(Commands::Explain(explain_args)) => explain(explain_args),
+        Some(Commands::Lsp(lsp_args)) => lsp(lsp_args),
+        Some(Commands::Review(review_args)) => review(review_args),
+        Some(Commands::Run(run_args)) => run(*run_args, None),
+        Some(Commands::Schema) => schema(),
+        Some(Commands::Validate(validate_args)) => validate(validate_args),
+        Some(Commands::ValidateConfig(validate_args)) => validate_config(validate_args),
+        Some(Commands::Impact(impact_args)) => impact(impact_args),
+        Some(Commands::Ignore(ignore_args)) => match ignore_args.command {
+            IgnoreCommands::Add(add) => add_ignore(&add.config, &add.location, &add.presets),
+        },
+        None => run(
+            RunArgs {
+                check: args.check,
+                ..args.run
+            },
+            None,
+        ),
+    };
+
+    if let Err(e) = result {
+        // Errors carrying a failure class have already been logged
+        if e.downcast_ref::<Failure>().is_none() {
+            if json_logs {
+                error!("{e:#}");
+            } else {
+                eprintln!(
+                    "{} {e:#}",
+                    color::paint("Error:", color::RED, color::stderr())
+                );
+            }
+        }
+        std::process::exit(Failure::of(&e).exit_code());
+    }
+}
+
+/// Presets selected on the command line, on top of their `enabled` field.
+#[derive(Default)]
+struct PresetSelection {
+    /// Presets kept alone, enabled when disabled in the config
+    presets: Vec<String>,
+    enable: Vec<String>,
+    disable: Vec<String>,
+    only_kind: Vec<String>,
+    allow_missing_tools: bool,
+}
+
+fn load_settings(path: &Path) -> Result<AppSettings> {
+    load_configs(
+        &[path.to_path_buf()],
+        &Overrides::default(),
+        &PresetSelection::default(),
+    )
+}
+
+/// Loads the config files, the later ones overriding or adding presets, and
+/// the overrides of the command line on top of them, keeping the presets
+/// selected by `selection` whose `only_if` probe succeeds.
+fn load_configs(
+    paths: &[PathBuf],
+    overrides: &Overrides,
+    selection: &PresetSelection,
+) -> Result<AppSettings> {
+    let mut settings = config::load(paths, overrides)?;
+    if let Some(level) = &settings.settings.log {
+        logging::set_default_level(level);
+    }
+
+    if let Some(unknown) = selection
+        .presets
+        .iter()
+        .chain(&selection.enable)
+        .chain(&selection.disable)
+        .find(|preset| !settings.presets.contains_key(*preset))
+    {
+        anyhow::bail!("Unknown preset `{unknown}`");
+    }
+
+    // Selecting a disabled preset with `--preset` enables it too
+    let enabled: Vec<String> = selection
+        .enable
+        .iter()
+        .chain(&selection.presets)
+        .cloned()
+        .collect();
+    let disabled = selection.disable.clone();
+    settings.retain_presets(move |name, preset| {
+        (preset.enabled || enabled.iter().any(|enabled| enabled == name))
+            && !disabled.iter().any(|disabled| disabled == name)
+    });
+
+    if !selection.presets.is_empty() {
+        let presets = selection.presets.clone();
+        settings.retain_presets(move |name, _| presets.iter().any(|preset| preset == name));
+    }
+
+    if !selection.only_kind.is_empty() {
+        let before = settings.presets.len();
+        let kinds = selection.only_kind.clone();
+        settings
+            .retain_presets(move |_, preset| kinds.iter().any(|kind| kind == preset.kind.as_str()));
+        info!(
+            "Running the {} presets of kind {}, skipping {} others",
+            settings.presets.len(),
+            selection.only_kind.join(", "),
+            before - settings.presets.len()
+        );
+    }
+
+    if selection.allow_missing_tools {
+        let mut missing: Vec<String> = settings
+            .presets
+            .iter()
+            .filter_map(|(name, preset)| {
+                missing_program(preset).map(|program| format!("`{name}` (`{program}`)"))
+            })
+            .collect();
+        settings.retain_presets(|_, preset| missing_program(preset).is_none());
+        if !missing.is_empty() {
+            missing.sort();
+            warn!(
+                "Skipping {} presets whose tool is not installed: {}",
+                missing.len(),
+                missing.join(", ")
+            );
+        }
+    }
+
+    // Presets whose probe fails are meant for other machines
+    settings.retain_presets(|name, preset| {
+        if preset.only_if.is_empty() {
+            return true;
+        }
+        match run_hook(preset, &preset.only_if) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Skipping preset `{name}`, its `only_if` probe failed: {e:#}");
+                false
+            }
+        }
+    });
+
+    Ok(settings)
+}
+
+/// Loads the config, keeping the presets selected by the options of the run.
+fn load_run_settings(configs: &[PathBuf], args: &RunArgs) -> Result<AppSettings> {
+    let mut overrides = Overrides {
+        profile: args.profile.clone(),
+        values: args.set.clone(),
+    };
+    if let Some(timeout) = args.timeout {
+        overrides.values.push((
+            "settings.timeout".to_string(),
+            humantime::format_duration(timeout).to_string(),
+        ));
+    }
+    let selection = PresetSelection {
+        presets: args.presets.clone(),
+        enable: args.enable_presets.clone(),
+        disable: args.disable_presets.clone(),
+        only_kind: args.only_kind.clone(),
+        allow_missing_tools: args.allow_missing_tools,
+    };
+
+    load_configs(configs, &overrides, &selection)
+}
+
+/// The first program of the commands of `preset` which is not installed.
+fn missing_program(preset: &PresetConfig) -> Option<&str> {
+    preset.stages().find_map(|command| {
+        let program = command.first()?;
+        // Programs built from placeholders are only known at run time
+        (!program.contains('{') && doctor::find_program(program).is_none())
+            .then_some(program.as_str())
+    })
+}
+
+fn check(mut args: CheckArgs) -> Result<()> {
+    if args.run.interactive {
+        anyhow::bail!("`--interactive` cannot be used with `mdcr check`");
+    }
+    args.run.check = true;
+
+    run(args.run, args.at.as_deref())
+}
+
+/// Processes the given paths, from the git revision `at` when given.
+fn run(mut args: RunArgs, at: Option<&str>) -> Result<()> {
+    // The processed document of stdin and the report would both be printed
+    // on stdout
+    if args.format != "text"
+        && !(args.check || args.annotate_only || args.force_check)
+        && args.paths.iter().any(|path| path.as_os_str() == "-")
+    {
+        anyhow::bail!(
+            "`--format {}` cannot be used with `-` as path, unless with `--check`",
+            args.format
+        );
+    }
+
+    // The check and write paths are processed too, without the current
+    // directory processed by default
+    if !args.check_paths.is_empty() || !args.write_paths.is_empty() {
+        if args.paths == [PathBuf::from(".")] {
+            args.paths.clear();
+        }
+        args.paths.extend(args.check_paths.iter().cloned());
+        args.paths.extend(args.write_paths.iter().cloned());
+        args.paths = outermost_paths(args.paths);
+    }
+
+    let groups = if args.config.is_empty() {
+        discover_configs(&args.paths)?
+    } else {
+        vec![(args.config.clone(), args.paths.clone())]
+    };
+    let mut configs = Vec::new();
+    for (config, paths) in groups {
+        let settings = load_run_settings(&config, &args)?;
+        configs.push((config, paths, settings));
+    }
+
+    // Prompts are answered one file after the other, and the lowest limit of
+    // the configs applies to all of them
+    let jobs = if args.interactive {
+        Some(1)
+    } else {
+        args.jobs.map(usize::from).or_else(|| {
+            configs
+                .iter()
+                .filter_map(|(_, _, settings)| settings.settings.jobs)
+                .min()
+        })
+    };
+    start_workers(jobs)?;
+
+    if args.watch {
+        let [(config, _, settings)] = <[_; 1]>::try_from(configs).map_err(|_| {
+            anyhow::anyhow!(
+                "The paths use different configs, pass one with `--config` to watch them"
+            )
+        })?;
+        args.config = config;
+        return watch(args, settings);
+    }
+
+    // Each group of paths is processed with its own config, in a single run
+    if configs.len() > 1 {
+        for (config, paths, _) in &configs {
+            info!(
+                "Using the config `{}` for {} paths",
+                config[0].display(),
+                paths.len()
+            );
+        }
+    }
+    let groups: Vec<(Vec<PathBuf>, &AppSettings)> = configs
+        .iter()
+        .map(|(_, paths, settings)| (paths.clone(), settings))
+        .collect();
+
+    run_with_settings(&args, at, &groups)
+}
+
+/// Paths grouped by the config found upward from each of them, stdin using
+/// the one of the current directory.
+fn discover_configs(paths: &[PathBuf]) -> Result<Vec<(Vec<PathBuf>, Vec<PathBuf>)>> {
+    let mut configs: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        let start = if path.as_os_str() == "-" {
+            Path::new(".")
+        } else {
+            path
+        };
+        let Some(config) = config::discover(start) else {
+            anyhow::bail!(
+                "No config file found for `{}`, pass one with `--config` or create `{}`",
+                path.display(),
+                config::CONFIG_NAMES[0]
+            );
+        };
+        debug!(
+            "Using the config `{}` for `{}`",
+            config.display(),
+            path.display()
+        );
+        configs.entry(config).or_default().push(path.clone());
+    }
+
+    Ok(configs
+        .into_iter()
+        .map(|(config, paths)| (vec![config], paths))
+        .collect())
+}
+
+/// Starts the threads processing files and commands, one per CPU by default.
+fn start_workers(jobs: Option<usize>) -> Result<()> {
+    // Zero lets rayon use one thread per CPU
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.unwrap_or(0))
+        .build_global()
+        .context("Failed to start the worker threads")
+}
+
+/// Selects the Markdown files found in directories, from the command line or
+/// the settings.
+fn file_filter(
+    ext: Option<&Vec<String>>,
+    exclude: &[String],
+    settings: &AppSettings,
+) -> Result<FileFilter> {
+    let extensions = ext
+        .or(settings.settings.extensions.as_ref())
+        .cloned()
+        .unwrap_or_else(|| vec!["md".to_string()]);
+    let exclude = match &settings.settings.exclude {
+        Some(excluded) if exclude.is_empty() => excluded,
+        _ => exclude,
+    };
+
+    FileFilter::new(&extensions, exclude)
+}
+
+/// Processes all the paths once, then the Markdown files again whenever they
+/// change, and all of them when the config changes.
+fn watch(mut args: RunArgs, mut settings: AppSettings) -> Result<()> {
+    let configs = args.config.clone();
+    let paths = args.paths.clone();
+    let mut watcher = Watcher::new(&paths, &configs)?;
+
+    loop {
+        if let Err(e) = run_with_settings(&args, None, &[(args.paths.clone(), &settings)]) {
+            // Errors carrying a failure class have already been logged
+            if e.downcast_ref::<Failure>().is_none() {
+                error!("{e:#}");
+            }
+        }
+        let filter = file_filter(args.ext.as_ref(), &args.exclude, &settings)?;
+        watcher.processed(&args.paths, &filter);
+
+        args.paths = match watcher.next_change(&filter)? {
+            Change::Config => {
+                match load_run_settings(&configs, &args) {
+                    Ok(reloaded) => settings = reloaded,
+                    Err(e) => {
+                        error!("{e:#}, keeping the previous config");
+                        continue;
+                    }
+                }
+                info!("Config changed, processing all the files");
+                paths.clone()
+            }
+            Change::Files(files) => files,
+        };
+    }
+}
+
+/// Processes once the groups of paths, each with the settings of its config,
+/// the reports and outputs of all of them being written once.
+fn run_with_settings(
+    args: &RunArgs,
+    at: Option<&str>,
+    groups: &[(Vec<PathBuf>, &AppSettings)],
+) -> Result<()> {
+    let mut timings = TimingCache::load(&args.cache_dir).unwrap_or_else(|e| {
+        warn!("Ignoring timing cache: {e:#}");
+        TimingCache::default()
+    });
+    let check_only = args.check || args.annotate_only || args.force_check;
+    let mut work = Vec::new();
+    for (paths, settings) in groups {
+        let files = file_filter(args.ext.as_ref(), &args.exclude, settings)?;
+        let paths = if args.staged {
+            git_markdown_files(git::staged_files(paths)?, &files)
+        } else if let Some(rev) = &args.changed_since {
+            git_markdown_files(git::changed_files(rev, paths)?, &files)
+        } else {
+            paths.clone()
+        };
+        if paths.is_empty() {
+            continue;
+        }
+
+        let options = RunOptions {
+            check_only,
+            debug_env: args.debug_env,
+            fail_fast: args.fail_fast,
+            force_check: args.force_check,
+            interactive: args.interactive,
+            max_commands: args.max_commands,
+            max_total_runtime: args.max_total_runtime,
+            project_root: args.project_root.clone(),
+            files,
+            check_paths: args.check_paths.clone(),
+            write_paths: args.write_paths.clone(),
+            languages: args.languages.clone(),
+            lines: args.lines,
+            output: match &args.output_dir {
+                Some(dir) => OutputTarget::Directory(dir.clone()),
+                None if args.stdout => OutputTarget::Stdout,
+                None if args.dry_run => OutputTarget::DryRun,
+                None => OutputTarget::InPlace,
+            },
+        };
+        work.push((paths, *settings, options));
+    }
+    if work.is_empty() {
+        if args.staged {
+            info!("No staged Markdown files");
+        } else if let Some(rev) = &args.changed_since {
+            info!("No Markdown files changed since `{rev}`");
+        }
+        return Ok(());
+    }
+
+    if args.estimate {
+        for (paths, settings, options) in &work {
+            print!("{}", estimate(paths, settings, &timings, &options.files)?);
+        }
+        return Ok(());
+    }
+    let execute_all = |state: &RunState| {
+        let mut report = RunReport::default();
+        for (paths, settings, options) in &work {
+            report.merge(execute(args, paths, at, settings, options, state));
+        }
+        report
+    };
+    let environments = if args.debug_env {
+        EnvCache::load(&args.cache_dir).unwrap_or_else(|e| {
+            warn!("Ignoring environment cache: {e:#}");
+            EnvCache::default()
+        })
+    } else {
+        EnvCache::default()
+    };
+
+    let mut state = RunState::new(environments);
+    if let Some(path) = &args.events {
+        let metadata = args.meta.iter().cloned().collect();
+        state = state.with_event_log(EventLog::create(path, &metadata, args.include_content)?);
+    }
+    // The bar would garble the dashboard, the prompts and the structured reports
+    let progress = (!args.tui && !args.interactive && !args.annotate_only && args.format == "text")
+        .then(progress::start)
+        .flatten();
+    if let Some(bar) = &progress {
+        state = state.with_progress(bar.clone());
+    }
+    let events = if args.tui {
+        let (sender, events) = mpsc::channel();
+        state = state.with_events(sender);
+        Some(events)
+    } else {
+        None
+    };
+
+    let outcomes = if let Some(events) = events {
+        let state = &state;
+        let finished = AtomicBool::new(false);
+
+        // Logs would garble the dashboard, they are muted while it is displayed
+        let log_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Off);
+        let (outcomes, dashboard) = std::thread::scope(|scope| {
+            let processing = scope.spawn(|| {
+                let outcomes = execute_all(state);
+                finished.store(true, Ordering::Relaxed);
+                outcomes
+            });
+            let dashboard = tui::dashboard(events, &finished, state);
+            (processing.join(), dashboard)
+        });
+        log::set_max_level(log_level);
+
+        dashboard.and_then(|()| outcomes.map_err(|_| anyhow::anyhow!("Processing thread panicked")))
+    } else {
+        Ok(execute_all(&state))
+    };
+    if let Some(bar) = &progress {
+        progress::finish(bar);
+    }
+    // The `after` hooks run even when the dashboard failed
+    state.finish_presets();
+    let outcomes = outcomes?;
+
+    if args.staged {
+        let updated = state.take_updated_files();
+        if !updated.is_empty() {
+            git::stage(&updated)?;
+            info!("Staged {} updated files", updated.len());
+        }
+    }
+
+    let documents = state.take_documents();
+    if args.dry_run {
+        let patch = render_patch(&relative_to_project(
+            documents,
+            args.project_root.as_deref(),
+        )?);
+        match &args.patch {
+            Some(file) => fs::write(file, patch)
+                .with_context(|| format!("Failed to write patch file: {}", file.display()))?,
+            None => print!("{}", color::diff(&patch, color::stdout())),
+        }
+    } else if args.stdout {
+        let mut stdout = std::io::stdout().lock();
+        for (index, document) in documents.iter().enumerate() {
+            // Documents are told apart the way `head` does with several files
+            if documents.len() > 1 {
+                let separator = if index == 0 { "" } else { "\n" };
+                writeln!(stdout, "{separator}==> {} <==", document.path.display())?;
+            }
+            stdout.write_all(document.processed.as_bytes())?;
+        }
+    }
+
+    if args.debug_env {
+        if let Err(e) = state.updated_environments().save(&args.cache_dir) {
+            warn!("Failed to save environment cache: {e:#}");
+        }
+    }
+
+    let mut report = state.into_report();
+    report.merge(outcomes);
+    report.sort();
+    report.metadata = args.meta.iter().cloned().collect();
+    let mut failure = report.failure();
+    for ((preset, reason), count) in report.skipped_summary() {
+        warn!("{count} blocks of preset `{preset}` were {reason}");
+    }
+    if check_only
+        && !args.allow_partial_check
+        && report.skipped.iter().any(|skipped| skipped.over_quota)
+    {
+        error!("The quotas were reached before all the blocks were checked, use `--allow-partial-check` to accept it");
+        // Unchecked blocks fail the run like any other check
+        failure = failure.max(Some(Failure::Mismatch));
+    }
+    // Which tools drifted, rather than one line per block
+    for (preset, (blocks, files)) in report.mismatch_summary() {
+        if check_only {
+            warn!("{preset}: {blocks} blocks stale across {files} files");
+        } else {
+            info!("{preset}: {blocks} blocks updated across {files} files");
+        }
+    }
+
+    if args.forbid_unexplained_skips {
+        for skip in report
+            .skip_annotations
+            .iter()
+            .filter(|s| s.reason.is_none())
+        {
+            error!(
+                "Code block in `{}:{}-{}` is skipped without a reason, add `reason=\"...\"` to its `mdcr-skip` marker",
+                skip.path.display(),
+                skip.start_line,
+                skip.end_line
+            );
+            // Unexplained skips fail the run like any other check
+            failure = failure.max(Some(Failure::Mismatch));
+        }
+    }
+
+    if args.timings {
+        eprint!("{}", render_timings(&report, 10));
+    }
 
-    if args.verbose {
-        log = "trace".to_string();
+    if args.error_on_unmatched {
+        for block in &report.unmatched {
+            error!(
+                "Code block in `{}:{}-{}` has no preset for language `{}`",
+                block.path.display(),
+                block.start_line + 1,
+                block.end_line,
+                block.lang
+            );
+            // Uncovered blocks fail the run like any other check
+            failure = failure.max(Some(Failure::Mismatch));
+        }
+    }
+
+    if args.record_timings {
+        timings.record(&report.timings);
+        if let Err(e) = timings.save(&args.cache_dir) {
+            warn!("Failed to save timing cache: {e:#}");
+        }
+    }
+
+    if args.format == "checkstyle" {
+        print!("{}", render_checkstyle(&report));
+    }
+
+    if args.annotate_only {
+        print!("{}", render_github_annotations(&report));
+        if let Some(path) = std::env::var_os("GITHUB_STEP_SUMMARY") {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(render_step_summary(&report).as_bytes()))
+                .with_context(|| {
+                    format!(
+                        "Failed to write job summary: {}",
+                        Path::new(&path).display()
+                    )
+                })?;
+        }
+    }
+
+    match failure {
+        Some(failure) => Err(failure.into()),
+        None => Ok(()),
+    }
+}
+
+/// Keeps the Markdown files listed by git, skipping the excluded ones.
+fn git_markdown_files(files: Vec<PathBuf>, filter: &FileFilter) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|file| {
+            filter.is_markdown(file)
+                && !file
+                    .ancestors()
+                    .any(|ancestor| filter.is_excluded(Path::new(""), ancestor))
+        })
+        .collect()
+}
+
+/// Processes all the paths, returning the outcome of each of their files.
+fn execute(
+    args: &RunArgs,
+    paths: &[PathBuf],
+    at: Option<&str>,
+    settings: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> RunReport {
+    if args.schedule == "preset" && at.is_none() {
+        return process_grouped_by_preset(paths, settings, options, state);
+    }
+
+    let mut report = RunReport::default();
+    for path in paths {
+        if state.is_stopped() {
+            break;
+        }
+        report.merge(if let Some(rev) = at {
+            process_revision(rev, path, settings, options, state)
+        } else if path.as_os_str() == "-" {
+            let result = process_stdin(settings, options, state);
+            vec![file_outcome(Path::new("<stdin>"), result)].into()
+        } else {
+            process(path.clone(), settings, options, state)
+        });
+    }
+    report
+}
+
+/// Serves processing requests on a socket, with the config loaded once.
+#[cfg(unix)]
+fn daemon(args: DaemonArgs) -> Result<()> {
+    let settings = load_settings(&args.config)?;
+    start_workers(settings.settings.jobs)?;
+    let files = file_filter(None, &[], &settings)?;
+
+    serve(&args.socket, &settings, &files)
+}
+
+/// Prints the findings about the environment, failing when one of them is an
+/// error.
+fn doctor(args: DoctorArgs) -> Result<()> {
+    report_findings(doctor::diagnose(
+        args.config.as_deref(),
+        &args.paths,
+        &args.cache_dir,
+    ))
+}
+
+/// Checks the presets of the config, without reading any Markdown file.
+fn validate_config(args: ValidateConfigArgs) -> Result<()> {
+    report_findings(doctor::validate_config(&args.config))
+}
+
+/// Prints the findings, failing when some of them are errors.
+fn report_findings(findings: Vec<doctor::Finding>) -> Result<()> {
+    for finding in &findings {
+        println!("{}", finding.render(color::stdout()));
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.level == doctor::Level::Error)
+        .count();
+    if errors > 0 {
+        anyhow::bail!("{errors} problems found");
+    }
+
+    Ok(())
+}
+
+/// Runs a language server on stdin and stdout.
+fn lsp(args: LspArgs) -> Result<()> {
+    let settings = load_settings(&args.config)?;
+    start_workers(settings.settings.jobs)?;
+
+    lsp::serve(&settings)
+}
+
+/// Reviews the mismatching blocks of the paths in a terminal UI.
+fn review(args: ReviewArgs) -> Result<()> {
+    let settings = load_settings(&args.config)?;
+    start_workers(settings.settings.jobs)?;
+    let filter = file_filter(None, &args.exclude, &settings)?;
+
+    let mut files = Vec::new();
+    for path in &args.paths {
+        files.extend(collect_markdown_files(path, &filter)?);
+    }
+
+    review::review(&files, &settings)
+}
+
+/// Runs the blocks of a single preset without modifying anything, and lists
+/// the files that would change.
+fn impact(args: ImpactArgs) -> Result<()> {
+    let settings = load_configs(
+        std::slice::from_ref(&args.config),
+        &Overrides::default(),
+        &PresetSelection {
+            presets: vec![args.preset.clone()],
+            ..PresetSelection::default()
+        },
+    )?;
+    start_workers(settings.settings.jobs)?;
+
+    let options = RunOptions {
+        files: file_filter(None, &args.exclude, &settings)?,
+        output: OutputTarget::DryRun,
+        ..RunOptions::default()
+    };
+    let state = RunState::default();
+    let mut report = RunReport::default();
+    for path in &args.paths {
+        report.merge(process(path.clone(), &settings, &options, &state));
+    }
+    state.finish_presets();
+    report.sort();
+
+    let changed: Vec<_> = report
+        .files
+        .iter()
+        .filter(|file| file.replacements > 0)
+        .collect();
+    let count = |count: usize| color::paint(&count.to_string(), color::YELLOW, color::stdout());
+    for file in &changed {
+        println!(
+            "{}: {} code blocks would change",
+            file.path.display(),
+            count(file.replacements)
+        );
+    }
+    println!(
+        "{} of {} files would change with preset `{}`",
+        count(changed.len()),
+        report.files.len(),
+        args.preset
+    );
+
+    report.result()
+}
+
+/// Checks that the config is valid and the Markdown files can be read and
+/// parsed, printing the number of code blocks each preset would run on.
+fn validate(args: ValidateArgs) -> Result<()> {
+    let settings = load_settings(&args.config)
+        .with_context(|| format!("Invalid config `{}`", args.config.display()))?;
+    let filter = file_filter(args.ext.as_ref(), &args.exclude, &settings)?;
+
+    let mut files = 0;
+    let mut blocks = 0;
+    let mut presets: BTreeMap<&str, usize> = settings
+        .presets
+        .keys()
+        .map(|preset| (preset.as_str(), 0))
+        .collect();
+    for path in &args.paths {
+        for file in collect_markdown_files(path, &filter)? {
+            let content = fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read file: {}", file.display()))?;
+            files += 1;
+
+            for block in parse_code_blocks(&file, &content) {
+                blocks += 1;
+                if block.skip.is_some() {
+                    continue;
+                }
+                for (preset, preset_cfg) in &settings.presets {
+                    if settings.runs(preset_cfg, &block) {
+                        *presets.entry(preset.as_str()).or_default() += 1;
+                    }
+                }
+            }
+        }
     }
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&log)).init();
+    println!(
+        "Config `{}` is valid, {} presets",
+        args.config.display(),
+        settings.presets.len()
+    );
+    let count = |count: usize| color::paint(&count.to_string(), color::CYAN, color::stdout());
+    println!(
+        "{} code blocks in {} Markdown files",
+        count(blocks),
+        count(files)
+    );
+    for (preset, blocks) in presets {
+        println!("  {preset}: {} code blocks", count(blocks));
+    }
+
+    Ok(())
+}
 
-    let settings: AppSettings = toml::from_str(&fs::read_to_string(&args.config)?)?;
+/// Prints the presets matching each code block and the commands they would
+/// run, without running any of them.
+fn explain(args: ExplainArgs) -> Result<()> {
+    let settings = load_settings(&args.config)?;
+    let filter = file_filter(args.ext.as_ref(), &args.exclude, &settings)?;
 
-    let mut had_error = false;
     for path in &args.paths {
-        if let Err(_e) = process(path.clone(), &settings, args.check) {
-            had_error = true;
+        for file in collect_markdown_files(path, &filter)? {
+            let content = fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read file: {}", file.display()))?;
+            let project = ProjectPaths::of(&file, args.project_root.as_deref());
+
+            for block in parse_code_blocks(&file, &content) {
+                let location = format!(
+                    "{}:{}-{} `{}`",
+                    file.display(),
+                    block.start_line + 1,
+                    block.end_line,
+                    block.lang
+                );
+                if block.skip.is_some() {
+                    println!("{location}: skipped by the `mdcr-skip` marker");
+                    continue;
+                }
+
+                let matching: Vec<_> = settings
+                    .presets
+                    .iter()
+                    .filter(|(_, preset_cfg)| settings.runs(preset_cfg, &block))
+                    .collect();
+                if matching.is_empty() {
+                    println!("{location}: no preset");
+                    continue;
+                }
+
+                println!("{location}");
+                for (preset, preset_cfg) in matching {
+                    if is_ignored(&settings, &block, preset) {
+                        println!("  {preset}: ignored in the config");
+                        continue;
+                    }
+                    let commands = explain_command(preset_cfg, &block.code, &block.lang, &project);
+                    let input = match preset_cfg.input_mode {
+                        InputMode::Stdin => "code on stdin",
+                        InputMode::File => "code in <file>",
+                        InputMode::Arg => "code as argument",
+                    };
+                    let pipeline: Vec<String> =
+                        commands.iter().map(|command| shell_line(command)).collect();
+                    println!("  {preset}: {} ({input})", pipeline.join(" | "));
+                }
+            }
         }
     }
 
-    if had_error {
-        std::process::exit(1);
+    Ok(())
+}
+
+/// The command line definition, named after the binary.
+fn command() -> clap::Command {
+    Cli::command().name("mdcr").bin_name("mdcr")
+}
+
+fn completions(args: CompletionsArgs) -> Result<()> {
+    // Generated in memory, as the generator panics when stdout is closed
+    let mut script = Vec::new();
+    clap_complete::generate(args.shell, &mut command(), "mdcr", &mut script);
+    std::io::stdout().write_all(&script)?;
+
+    Ok(())
+}
+
+fn schema() -> Result<()> {
+    writeln!(
+        std::io::stdout(),
+        "{}",
+        serde_json::to_string_pretty(&config::schema())?
+    )?;
+
+    Ok(())
+}
+
+fn man(args: ManArgs) -> Result<()> {
+    match args.out_dir {
+        Some(dir) => {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+            clap_mangen::generate_to(command(), &dir)
+                .with_context(|| format!("Failed to write manual pages to {}", dir.display()))?;
+        }
+        None => clap_mangen::Man::new(command()).render(&mut std::io::stdout())?,
     }
 
     Ok(())
 }
+
+/// Prints the fenced code blocks of the paths, without any config.
+fn list_blocks(args: ListBlocksArgs) -> Result<()> {
+    let extensions = args.ext.unwrap_or_else(|| vec!["md".to_string()]);
+    let filter = FileFilter::new(&extensions, &args.exclude)?;
+
+    let mut blocks = Vec::new();
+    for path in &args.paths {
+        for file in collect_markdown_files(path, &filter)? {
+            let content = fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read file: {}", file.display()))?;
+            blocks.extend(parse_code_blocks(&file, &content));
+        }
+    }
+
+    if args.format == "json" {
+        let blocks: Vec<_> = blocks
+            .iter()
+            .map(|block| {
+                serde_json::json!({
+                    "path": block.path,
+                    "block_id": block.block_id,
+                    "start_line": block.start_line + 1,
+                    "end_line": block.end_line,
+                    "language": block.lang,
+                    "headers": block.headers,
+                    "classes": block.classes,
+                    "id": block.id,
+                    "indent": block.indent,
+                    "skip": block.skip.is_some(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&blocks)?);
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 5]> = blocks
+        .iter()
+        .map(|block| {
+            [
+                block.path.display().to_string(),
+                format!("{}-{}", block.start_line + 1, block.end_line),
+                block.lang.clone(),
+                block.indent.to_string(),
+                block.headers.clone(),
+            ]
+        })
+        .collect();
+    let header = ["FILE", "LINES", "LANGUAGE", "INDENT", "HEADERS"].map(String::from);
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+/// Command line as typed in a shell, arguments with special characters being
+/// single-quoted.
+fn shell_line(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            if !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+            {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn apply(args: ApplyArgs) -> Result<()> {
+    if let Some(patch) = args.patch {
+        let root = ProjectPaths::root(&std::env::current_dir()?, args.project_root.as_deref());
+        return apply_patch(&patch, &root);
+    }
+
+    let Some(config) = args.config else {
+        anyhow::bail!("The `--config` option is required");
+    };
+    let settings = load_settings(&config)?;
+
+    let state = RunState::default();
+    let result = process_stdin(&settings, &RunOptions::default(), &state);
+    state.finish_presets();
+
+    result.map(|_| ())
+}
+
+/// Processes a Markdown document read on stdin and prints the transformed
+/// document on stdout, unless running in check mode.
+fn process_stdin(settings: &AppSettings, options: &RunOptions, state: &RunState) -> Result<usize> {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+
+    let result = process_content(Path::new("<stdin>"), &input, settings, options, state);
+    if options.check_only {
+        return result.map(|replacements| replacements.len());
+    }
+
+    // On failure the original document is echoed back, so that editors piping
+    // the buffer through mdcr never lose its content.
+    let (output, result) = match result {
+        Ok(replacements) if replacements.is_empty() => (input, Ok(0)),
+        Ok(replacements) => {
+            let replaced = replacements.len();
+            (
+                apply_replacements(&input, replacements, settings.settings.final_newline),
+                Ok(replaced),
+            )
+        }
+        Err(e) => (input, Err(e)),
+    };
+
+    std::io::stdout().write_all(output.as_bytes())?;
+
+    result
+}
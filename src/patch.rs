@@ -0,0 +1,223 @@
+use crate::command::ProjectPaths;
+use crate::runner::Document;
+use anyhow::{bail, Context, Result};
+use log::info;
+use similar::TextDiff;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Renders the changes of `documents` as a unified patch that applies with
+/// `git apply` or `patch -p1`.
+pub fn render_patch(documents: &[Document]) -> String {
+    let mut patch = String::new();
+
+    for document in documents {
+        let path = patch_path(&document.path);
+        let diff = TextDiff::from_lines(&document.original, &document.processed);
+
+        patch.push_str(&format!("diff --git a/{path} b/{path}\n"));
+        patch.push_str(
+            &diff
+                .unified_diff()
+                .header(&format!("a/{path}"), &format!("b/{path}"))
+                .to_string(),
+        );
+    }
+
+    patch
+}
+
+/// Makes the paths of `documents` relative to the root of their project, as
+/// expected by `git apply`.
+pub fn relative_to_project(
+    documents: Vec<Document>,
+    project_root: Option<&Path>,
+) -> Result<Vec<Document>> {
+    documents
+        .into_iter()
+        .map(|document| {
+            let project = ProjectPaths::of(&document.path, project_root);
+            if project.relpath.is_absolute() {
+                bail!(
+                    "`{}` is outside of the project root `{}`, use `--project-root` to set it",
+                    document.path.display(),
+                    project.root.display()
+                );
+            }
+            Ok(Document {
+                path: project.relpath,
+                ..document
+            })
+        })
+        .collect()
+}
+
+/// Path as written in the patch headers, without leading `./`.
+fn patch_path(path: &Path) -> String {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Replacements recorded in a patch for a single file.
+struct FilePatch {
+    path: PathBuf,
+    hunks: Vec<Hunk>,
+}
+
+struct Hunk {
+    /// First line of the hunk in the original file, 1-based
+    start_line: usize,
+    original: Vec<String>,
+    replacement: Vec<String>,
+}
+
+/// Applies a patch generated with `--dry-run --patch`, its paths being
+/// relative to `root`. All the files are validated before any of them is
+/// written, so that a stale patch never leaves the documents half updated.
+pub fn apply_patch(patch: &Path, root: &Path) -> Result<()> {
+    let content = fs::read_to_string(patch)
+        .with_context(|| format!("Failed to read patch file: {}", patch.display()))?;
+
+    let mut documents = Vec::new();
+    for mut file in parse_patch(&content)? {
+        file.path = root.join(&file.path);
+        let original = fs::read_to_string(&file.path)
+            .with_context(|| format!("Failed to read file: {}", file.path.display()))?;
+        let mut lines: Vec<String> = original.lines().map(String::from).collect();
+
+        // Hunks are applied from the last one to the first so that line
+        // numbers stay valid
+        for hunk in file.hunks.iter().rev() {
+            let start = if hunk.original.is_empty() {
+                hunk.start_line
+            } else {
+                hunk.start_line.saturating_sub(1)
+            };
+            let end = start + hunk.original.len();
+
+            if lines.get(start..end) != Some(hunk.original.as_slice()) {
+                bail!(
+                    "Lines {}-{} of `{}` changed since the patch was generated",
+                    start + 1,
+                    end,
+                    file.path.display()
+                );
+            }
+            lines.splice(start..end, hunk.replacement.iter().cloned());
+        }
+
+        let mut document = lines.join("\n");
+        if original.ends_with('\n') {
+            document.push('\n');
+        }
+        documents.push((file.path, document));
+    }
+
+    for (path, document) in documents {
+        fs::write(&path, document)?;
+        info!("Updated: {}", path.display());
+    }
+
+    Ok(())
+}
+
+fn parse_patch(content: &str) -> Result<Vec<FilePatch>> {
+    let mut files: Vec<FilePatch> = Vec::new();
+    // Lines of the current hunk still to read in the original and the
+    // replacement, hunk lines can look like headers (e.g. `--- ` when
+    // removing a `-- comment` line)
+    let mut remaining: (usize, usize) = (0, 0);
+    // Header of the current hunk, to locate the errors
+    let mut header = "";
+
+    for line in content.lines() {
+        if remaining != (0, 0) {
+            let Some(file) = files.last_mut() else {
+                bail!("Invalid patch: `{line}` is outside of a hunk");
+            };
+            let Some(hunk) = file.hunks.last_mut() else {
+                bail!("Invalid patch: `{line}` is outside of a hunk");
+            };
+            let (marker, text) = match line.chars().next() {
+                Some(marker) => (marker, &line[marker.len_utf8()..]),
+                // Some tools strip the trailing space of empty context lines
+                None => (' ', ""),
+            };
+            let (original, replacement) = match marker {
+                '-' => (1, 0),
+                '+' => (0, 1),
+                ' ' => (1, 1),
+                '\\' => continue,
+                _ => bail!("Invalid patch: unexpected line `{line}`"),
+            };
+            remaining = match (
+                remaining.0.checked_sub(original),
+                remaining.1.checked_sub(replacement),
+            ) {
+                (Some(original), Some(replacement)) => (original, replacement),
+                _ => bail!(
+                    "Invalid patch: hunk `{header}` of `{}` has more lines than its header counts",
+                    file.path.display()
+                ),
+            };
+            if original == 1 {
+                hunk.original.push(text.to_string());
+            }
+            if replacement == 1 {
+                hunk.replacement.push(text.to_string());
+            }
+            continue;
+        }
+
+        if let Some(path) = line.strip_prefix("+++ ") {
+            let path = path.split('\t').next().unwrap_or(path);
+            files.push(FilePatch {
+                path: PathBuf::from(path.strip_prefix("b/").unwrap_or(path)),
+                hunks: Vec::new(),
+            });
+        } else if line.starts_with("@@ ") {
+            let Some(file) = files.last_mut() else {
+                bail!("Invalid patch: `{line}` is outside of a file section");
+            };
+            let (start_line, original, replacement) = parse_hunk_header(line)
+                .with_context(|| format!("Invalid patch: malformed hunk header `{line}`"))?;
+            file.hunks.push(Hunk {
+                start_line,
+                original: Vec::new(),
+                replacement: Vec::new(),
+            });
+            remaining = (original, replacement);
+            header = line;
+        }
+    }
+
+    if remaining != (0, 0) {
+        let path = files.last().map(|file| file.path.display().to_string());
+        bail!(
+            "Invalid patch: hunk `{header}` of `{}` is truncated",
+            path.unwrap_or_default()
+        );
+    }
+
+    Ok(files)
+}
+
+/// Parses `@@ -start,len +start,len @@` into the original start line and the
+/// lengths of the original and the replacement.
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ -")?.split(' ');
+    let range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+
+    let (start_line, original) = range(ranges.next()?)?;
+    let (_, replacement) = range(ranges.next()?.strip_prefix('+')?)?;
+
+    Some((start_line, original, replacement))
+}
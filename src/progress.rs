@@ -0,0 +1,48 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The bar being drawn, which the log lines must not garble.
+static ACTIVE: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Starts a bar of the files processed on stderr, `None` when it is not a
+/// terminal.
+pub fn start() -> Option<ProgressBar> {
+    if !std::io::stderr().is_terminal() {
+        return None;
+    }
+
+    let bar = ProgressBar::new(0).with_style(
+        ProgressStyle::with_template("{spinner} [{bar:30}] {pos}/{len} files {wide_msg}")
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("=> "),
+    );
+    bar.enable_steady_tick(Duration::from_millis(100));
+    *ACTIVE.lock().unwrap_or_else(|e| e.into_inner()) = Some(bar.clone());
+
+    Some(bar)
+}
+
+/// Clears the bar from the terminal.
+pub fn finish(bar: &ProgressBar) {
+    bar.finish_and_clear();
+    ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Stderr, hiding the bar while the log lines are written.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        match active {
+            Some(bar) => bar.suspend(|| std::io::stderr().write(buf)),
+            None => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
@@ -0,0 +1,679 @@
+use crate::codeblock::{CodeBlock, SkipMarker};
+use crate::config::Severity;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Class of failure of a run, ordered by severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Failure {
+    /// A code block does not match its command output in check mode
+    Mismatch,
+    /// A command could not be executed or returned a non-zero exit status
+    Command,
+    /// The configuration is invalid or a file could not be read or written
+    Config,
+}
+
+impl Failure {
+    /// Returns the failure carried by an error, errors without one are
+    /// configuration or I/O errors.
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<Failure>()
+            .copied()
+            .unwrap_or(Failure::Config)
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Mismatch => 1,
+            Failure::Command => 2,
+            Failure::Config => 3,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Mismatch => write!(f, "code block mismatch"),
+            Failure::Command => write!(f, "command failure"),
+            Failure::Config => write!(f, "configuration or I/O error"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub block_id: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub preset: String,
+    pub lang: String,
+    pub severity: Severity,
+}
+
+impl Mismatch {
+    pub fn new(block: &CodeBlock, preset: &str, severity: Severity) -> Self {
+        Self {
+            path: block.path.clone(),
+            block_id: block.block_id.clone(),
+            start_line: block.start_line,
+            end_line: block.end_line,
+            preset: preset.to_string(),
+            lang: block.lang.clone(),
+            severity,
+        }
+    }
+}
+
+/// A command that could not be executed or returned a non-zero exit status.
+#[derive(Debug, Clone)]
+pub struct CommandFailure {
+    pub path: PathBuf,
+    pub block_id: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub preset: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl CommandFailure {
+    pub fn new(
+        block: &CodeBlock,
+        preset: &str,
+        severity: Severity,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            path: block.path.clone(),
+            block_id: block.block_id.clone(),
+            start_line: block.start_line,
+            end_line: block.end_line,
+            preset: preset.to_string(),
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+/// A code block opted out of processing with a `mdcr-skip` marker.
+#[derive(Debug, Clone)]
+pub struct SkipAnnotation {
+    pub path: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub reason: Option<String>,
+    pub issue: Option<String>,
+}
+
+impl SkipAnnotation {
+    pub fn new(block: &CodeBlock, marker: &SkipMarker) -> Self {
+        Self {
+            path: block.path.clone(),
+            start_line: block.start_line,
+            end_line: block.end_line,
+            reason: marker.reason.clone(),
+            issue: marker.issue.clone(),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        let mut message = match &self.reason {
+            Some(reason) => format!("Code block skipped: {reason}"),
+            None => "Code block skipped without a reason".to_string(),
+        };
+        if let Some(issue) = &self.issue {
+            message.push_str(&format!(" (issue: {issue})"));
+        }
+        message
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkippedBlock {
+    pub preset: String,
+    pub reason: String,
+    /// Whether the block was skipped after reaching `--max-commands` or
+    /// `--max-total-runtime`, and so was left unchecked
+    pub over_quota: bool,
+}
+
+impl SkippedBlock {
+    pub fn new(preset: &str, reason: &str) -> Self {
+        Self {
+            preset: preset.to_string(),
+            reason: reason.to_string(),
+            over_quota: false,
+        }
+    }
+
+    pub fn over_quota(preset: &str, reason: &str) -> Self {
+        Self {
+            over_quota: true,
+            ..Self::new(preset, reason)
+        }
+    }
+}
+
+/// A code block with a language that no preset runs on.
+#[derive(Debug, Clone)]
+pub struct UnmatchedBlock {
+    pub path: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub lang: String,
+}
+
+impl UnmatchedBlock {
+    pub fn new(block: &CodeBlock) -> Self {
+        Self {
+            path: block.path.clone(),
+            start_line: block.start_line,
+            end_line: block.end_line,
+            lang: block.lang.clone(),
+        }
+    }
+}
+
+/// Duration of the command of a preset on a block.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub path: PathBuf,
+    pub block_id: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub preset: String,
+    pub duration: Duration,
+}
+
+impl Timing {
+    pub fn new(block: &CodeBlock, preset: &str, duration: Duration) -> Self {
+        Self {
+            path: block.path.clone(),
+            block_id: block.block_id.clone(),
+            start_line: block.start_line,
+            end_line: block.end_line,
+            preset: preset.to_string(),
+            duration,
+        }
+    }
+}
+
+/// Outcome of a processed file, or of a path whose files could not be listed.
+#[derive(Debug, Clone)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    /// Code blocks replaced in the file
+    pub replacements: usize,
+    pub error: Option<FileError>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileError {
+    pub failure: Failure,
+    pub message: String,
+}
+
+impl FileOutcome {
+    pub fn new(path: &Path, result: anyhow::Result<usize>) -> Self {
+        let (replacements, error) = match result {
+            Ok(replacements) => (replacements, None),
+            Err(e) => (
+                0,
+                Some(FileError {
+                    failure: Failure::of(&e),
+                    message: format!("{e:#}"),
+                }),
+            ),
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            replacements,
+            error,
+        }
+    }
+}
+
+/// Everything that happened during a run, which the exit code and all the
+/// report formats are derived from.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub files: Vec<FileOutcome>,
+    pub mismatches: Vec<Mismatch>,
+    pub failures: Vec<CommandFailure>,
+    pub timings: Vec<Timing>,
+    pub skipped: Vec<SkippedBlock>,
+    pub skip_annotations: Vec<SkipAnnotation>,
+    pub unmatched: Vec<UnmatchedBlock>,
+    /// `--meta` pairs describing the run (e.g. git SHA, CI job URL)
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl From<Vec<FileOutcome>> for RunReport {
+    fn from(files: Vec<FileOutcome>) -> Self {
+        Self {
+            files,
+            ..Self::default()
+        }
+    }
+}
+
+impl RunReport {
+    pub fn merge(&mut self, other: RunReport) {
+        self.files.extend(other.files);
+        self.mismatches.extend(other.mismatches);
+        self.failures.extend(other.failures);
+        self.timings.extend(other.timings);
+        self.skipped.extend(other.skipped);
+        self.skip_annotations.extend(other.skip_annotations);
+        self.unmatched.extend(other.unmatched);
+        self.metadata.extend(other.metadata);
+    }
+
+    /// The most severe failure of the files, if any.
+    pub fn failure(&self) -> Option<Failure> {
+        self.files
+            .iter()
+            .filter_map(|file| file.error.as_ref())
+            .map(|error| error.failure)
+            .max()
+    }
+
+    /// Fails with the most severe failure of the files, if any.
+    pub fn result(&self) -> anyhow::Result<()> {
+        match self.failure() {
+            Some(failure) => {
+                Err(anyhow::Error::new(failure).context("One or more files failed to process"))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Errors of the files that could not be processed at all, e.g. because
+    /// they could not be read. Mismatches and command failures are reported
+    /// block by block instead.
+    pub fn file_errors(&self) -> impl Iterator<Item = (&PathBuf, &FileError)> {
+        self.files.iter().filter_map(|file| {
+            let error = file.error.as_ref()?;
+            (error.failure == Failure::Config).then_some((&file.path, error))
+        })
+    }
+
+    /// Sorts the entries by file path and line number, so that reports do not
+    /// depend on the order in which files were processed in parallel.
+    pub fn sort(&mut self) {
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+        self.mismatches.sort_by(|a, b| {
+            (&a.path, a.start_line, &a.preset).cmp(&(&b.path, b.start_line, &b.preset))
+        });
+        self.failures.sort_by(|a, b| {
+            (&a.path, a.start_line, &a.preset).cmp(&(&b.path, b.start_line, &b.preset))
+        });
+        self.skip_annotations
+            .sort_by(|a, b| (&a.path, a.start_line).cmp(&(&b.path, b.start_line)));
+        self.unmatched
+            .sort_by(|a, b| (&a.path, a.start_line).cmp(&(&b.path, b.start_line)));
+    }
+
+    /// Number of skipped blocks, grouped by preset and reason
+    pub fn skipped_summary(&self) -> BTreeMap<(&str, &str), usize> {
+        let mut summary = BTreeMap::new();
+        for skipped in &self.skipped {
+            *summary
+                .entry((skipped.preset.as_str(), skipped.reason.as_str()))
+                .or_default() += 1;
+        }
+        summary
+    }
+
+    /// Total command time of each preset, and its number of blocks, the
+    /// slowest preset first.
+    pub fn preset_timings(&self) -> Vec<(&str, Duration, usize)> {
+        let mut presets: BTreeMap<&str, (Duration, usize)> = BTreeMap::new();
+        for timing in &self.timings {
+            let entry = presets.entry(&timing.preset).or_default();
+            entry.0 += timing.duration;
+            entry.1 += 1;
+        }
+
+        let mut presets: Vec<(&str, Duration, usize)> = presets
+            .into_iter()
+            .map(|(preset, (duration, blocks))| (preset, duration, blocks))
+            .collect();
+        presets.sort_by_key(|&(_, duration, _)| std::cmp::Reverse(duration));
+        presets
+    }
+
+    /// Number of mismatching blocks of each preset, and of files they are in.
+    pub fn mismatch_summary(&self) -> BTreeMap<&str, (usize, usize)> {
+        let mut files: BTreeMap<&str, BTreeSet<&PathBuf>> = BTreeMap::new();
+        let mut summary: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+        for mismatch in &self.mismatches {
+            summary.entry(&mismatch.preset).or_default().0 += 1;
+            files
+                .entry(&mismatch.preset)
+                .or_default()
+                .insert(&mismatch.path);
+        }
+        for (preset, files) in files {
+            summary.entry(preset).or_default().1 = files.len();
+        }
+        summary
+    }
+}
+
+pub fn render_checkstyle(report: &RunReport) -> String {
+    // (line, severity, message, source) entries grouped by file
+    let mut files: BTreeMap<&PathBuf, Vec<(usize, &str, String, String)>> = BTreeMap::new();
+    for mismatch in &report.mismatches {
+        let message = format!(
+            "Code block mismatch detected in lines {}-{} (preset: `{}`, language: `{}`, block: `{}`)",
+            mismatch.start_line + 1,
+            mismatch.end_line,
+            mismatch.preset,
+            mismatch.lang,
+            mismatch.block_id
+        );
+        files.entry(&mismatch.path).or_default().push((
+            mismatch.start_line + 1,
+            mismatch.severity.as_str(),
+            message,
+            format!("mdcr.{}", mismatch.preset),
+        ));
+    }
+    for failure in &report.failures {
+        let message = format!(
+            "Command failure in lines {}-{} (preset: `{}`, block: `{}`): {}",
+            failure.start_line + 1,
+            failure.end_line,
+            failure.preset,
+            failure.block_id,
+            failure.message
+        );
+        files.entry(&failure.path).or_default().push((
+            failure.start_line + 1,
+            failure.severity.as_str(),
+            message,
+            format!("mdcr.{}", failure.preset),
+        ));
+    }
+    for (path, error) in report.file_errors() {
+        files.entry(path).or_default().push((
+            1,
+            "error",
+            error.message.clone(),
+            "mdcr.file".to_string(),
+        ));
+    }
+    for skip in &report.skip_annotations {
+        let severity = if skip.reason.is_some() {
+            "info"
+        } else {
+            "warning"
+        };
+        files.entry(&skip.path).or_default().push((
+            skip.start_line + 1,
+            severity,
+            skip.message(),
+            "mdcr.skip".to_string(),
+        ));
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<checkstyle version=\"4.3\">\n");
+    if !report.metadata.is_empty() {
+        xml.push_str("  <properties>\n");
+        for (key, value) in &report.metadata {
+            xml.push_str(&format!(
+                "    <property name=\"{}\" value=\"{}\"/>\n",
+                escape_xml(key),
+                escape_xml(value)
+            ));
+        }
+        xml.push_str("  </properties>\n");
+    }
+
+    for (path, mut entries) in files {
+        entries.sort_by(|(line_a, _, _, source_a), (line_b, _, _, source_b)| {
+            (line_a, source_a).cmp(&(line_b, source_b))
+        });
+        xml.push_str(&format!(
+            "  <file name=\"{}\">\n",
+            escape_xml(&path.display().to_string())
+        ));
+        for (line, severity, message, source) in entries {
+            xml.push_str(&format!(
+                "    <error line=\"{}\" severity=\"{}\" message=\"{}\" source=\"{}\"/>\n",
+                line,
+                severity,
+                escape_xml(&message),
+                escape_xml(&source)
+            ));
+        }
+        xml.push_str("  </file>\n");
+    }
+
+    xml.push_str("</checkstyle>\n");
+    xml
+}
+
+/// Renders the report as GitHub Actions workflow commands, displayed as
+/// annotations on the Markdown files.
+pub fn render_github_annotations(report: &RunReport) -> String {
+    // (path, line, command) entries, sorted by file and line
+    let mut annotations: Vec<(&PathBuf, usize, String)> = Vec::new();
+    for mismatch in &report.mismatches {
+        annotations.push((
+            &mismatch.path,
+            mismatch.start_line,
+            github_command(
+                mismatch.severity.as_str(),
+                &mismatch.path,
+                mismatch.start_line,
+                mismatch.end_line,
+                &format!("mdcr ({})", mismatch.preset),
+                &format!(
+                    "Code block mismatch detected (preset: `{}`, language: `{}`, block: `{}`)",
+                    mismatch.preset, mismatch.lang, mismatch.block_id
+                ),
+            ),
+        ));
+    }
+    for failure in &report.failures {
+        annotations.push((
+            &failure.path,
+            failure.start_line,
+            github_command(
+                failure.severity.as_str(),
+                &failure.path,
+                failure.start_line,
+                failure.end_line,
+                &format!("mdcr ({})", failure.preset),
+                &format!("{} (block: `{}`)", failure.message, failure.block_id),
+            ),
+        ));
+    }
+    for skip in &report.skip_annotations {
+        let level = if skip.reason.is_some() {
+            "notice"
+        } else {
+            "warning"
+        };
+        annotations.push((
+            &skip.path,
+            skip.start_line,
+            github_command(
+                level,
+                &skip.path,
+                skip.start_line,
+                skip.end_line,
+                "mdcr (skip)",
+                &skip.message(),
+            ),
+        ));
+    }
+    annotations.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+    annotations
+        .into_iter()
+        .map(|(_, _, command)| command + "\n")
+        .collect()
+}
+
+fn github_command(
+    level: &str,
+    path: &Path,
+    start_line: usize,
+    end_line: usize,
+    title: &str,
+    message: &str,
+) -> String {
+    format!(
+        "::{} file={},line={},endLine={},title={}::{}",
+        level,
+        escape_github_property(&path.display().to_string()),
+        start_line + 1,
+        end_line,
+        escape_github_property(title),
+        escape_github_data(message)
+    )
+}
+
+fn escape_github_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_github_property(s: &str) -> String {
+    escape_github_data(s)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+/// Renders the report as Markdown for the summary of a GitHub Actions job.
+pub fn render_step_summary(report: &RunReport) -> String {
+    let mut summary = String::from("## Markdown Code Runner\n\n");
+    summary.push_str("| Result | Blocks |\n| --- | ---: |\n");
+    summary.push_str(&format!(
+        "| Replaced | {} |\n| Mismatching | {} |\n| Failed | {} |\n| Skipped | {} |\n",
+        report
+            .files
+            .iter()
+            .map(|file| file.replacements)
+            .sum::<usize>(),
+        report.mismatches.len(),
+        report.failures.len(),
+        report.skip_annotations.len() + report.skipped.len()
+    ));
+
+    if !report.metadata.is_empty() {
+        summary.push_str("\n| Run | |\n| --- | --- |\n");
+        for (key, value) in &report.metadata {
+            summary.push_str(&format!(
+                "| {} | {} |\n",
+                escape_table_cell(key),
+                escape_table_cell(value)
+            ));
+        }
+    }
+
+    if !report.mismatches.is_empty() {
+        summary.push_str(
+            "\n### Mismatches by preset\n\n| Preset | Blocks | Files |\n| --- | ---: | ---: |\n",
+        );
+        for (preset, (blocks, files)) in report.mismatch_summary() {
+            summary.push_str(&format!(
+                "| `{}` | {blocks} | {files} |\n",
+                escape_table_cell(preset)
+            ));
+        }
+
+        summary.push_str("\n### Mismatching blocks\n\n");
+        for mismatch in &report.mismatches {
+            summary.push_str(&format!(
+                "- `{}:{}` (preset: `{}`, language: `{}`)\n",
+                mismatch.path.display(),
+                mismatch.start_line + 1,
+                mismatch.preset,
+                mismatch.lang
+            ));
+        }
+    }
+
+    if !report.failures.is_empty() {
+        summary.push_str("\n### Failed commands\n\n");
+        for failure in &report.failures {
+            summary.push_str(&format!(
+                "- `{}:{}` (preset: `{}`): {}\n",
+                failure.path.display(),
+                failure.start_line + 1,
+                failure.preset,
+                failure.message.lines().next().unwrap_or_default()
+            ));
+        }
+    }
+
+    let file_errors: Vec<_> = report.file_errors().collect();
+    if !file_errors.is_empty() {
+        summary.push_str("\n### Unprocessed files\n\n");
+        for (path, error) in file_errors {
+            summary.push_str(&format!(
+                "- `{}`: {}\n",
+                path.display(),
+                error.message.lines().next().unwrap_or_default()
+            ));
+        }
+    }
+
+    summary
+}
+
+/// `|` ends the cells of Markdown tables, even in code spans.
+fn escape_table_cell(s: &str) -> String {
+    s.replace('|', "\\|")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// The slowest presets, then the `limit` slowest blocks.
+pub fn render_timings(report: &RunReport, limit: usize) -> String {
+    let mut rendered = String::from("Slowest presets:\n");
+    for (preset, duration, blocks) in report.preset_timings() {
+        rendered.push_str(&format!(
+            "{:>9.3}s  {preset} ({blocks} blocks)\n",
+            duration.as_secs_f64()
+        ));
+    }
+
+    let mut timings: Vec<&Timing> = report.timings.iter().collect();
+    timings.sort_by_key(|timing| std::cmp::Reverse(timing.duration));
+    rendered.push_str("Slowest blocks:\n");
+    for timing in timings.into_iter().take(limit) {
+        rendered.push_str(&format!(
+            "{:>9.3}s  {}:{}-{} ({}, block: `{}`)\n",
+            timing.duration.as_secs_f64(),
+            timing.path.display(),
+            timing.start_line + 1,
+            timing.end_line,
+            timing.preset,
+            timing.block_id
+        ));
+    }
+
+    rendered
+}
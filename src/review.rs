@@ -0,0 +1,447 @@
+use crate::codeblock::CodeBlock;
+use crate::config::AppSettings;
+use crate::report::{CommandFailure, Failure};
+use crate::runner::{process_content_partially, updated_document, RunOptions, RunState};
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use ratatui::backend::{Backend, TestBackend};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use rayon::prelude::*;
+use similar::{ChangeTag, TextDiff};
+use std::fs;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    Pending,
+    Apply,
+    Skip,
+}
+
+/// A mismatching block, along with what to do about it.
+struct Change {
+    path: PathBuf,
+    /// Current lines of the block, fences included
+    original: String,
+    replacement: CodeBlock,
+    decision: Decision,
+}
+
+/// Lists the mismatching blocks of `files` in a terminal UI, where each of
+/// them is applied or skipped. The applied ones are written on quitting.
+///
+/// When stdin is not a terminal, e.g. in scripts, the keys are read from it
+/// instead, and the last screen is printed on stdout.
+pub fn review(files: &[PathBuf], settings: &AppSettings) -> Result<()> {
+    let (changes, failures) = changes(files, settings);
+    let mut review = Review {
+        changes,
+        failures,
+        table: TableState::default().with_selected(0),
+        scroll: 0,
+        tty: std::io::stdin().is_terminal(),
+    };
+
+    if review.changes.is_empty() {
+        println!("No mismatching blocks");
+    } else if review.tty {
+        // Logs would garble the interface, they are muted while it is displayed
+        let log_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Off);
+        let mut terminal = ratatui::try_init()?;
+        let result = review.run(&mut terminal, read_key, settings);
+        ratatui::try_restore()?;
+        log::set_max_level(log_level);
+
+        if result? {
+            write_applied(&review.changes, settings)?;
+        }
+    } else {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        let mut keys = input.chars().filter(|&c| c != '\n').map(|c| match c {
+            '\x1b' => KeyCode::Esc,
+            c => KeyCode::Char(c),
+        });
+        let mut terminal = Terminal::new(TestBackend::new(120, 30))?;
+        let result = review.run(&mut terminal, || Ok(keys.next()), settings);
+        print_screen(terminal.backend());
+
+        if result? {
+            write_applied(&review.changes, settings)?;
+        }
+    }
+
+    // The changes of the other blocks were still reviewed
+    for failure in &review.failures {
+        error!(
+            "Preset `{}` failed in `{}:{}-{}`: {}",
+            failure.preset,
+            failure.path.display(),
+            failure.start_line + 1,
+            failure.end_line,
+            failure.message
+        );
+    }
+    if !review.failures.is_empty() {
+        return Err(anyhow::Error::new(Failure::Command)
+            .context("One or more commands failed during the review"));
+    }
+
+    Ok(())
+}
+
+/// Waits for the next key pressed in the terminal.
+fn read_key() -> Result<Option<KeyCode>> {
+    loop {
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                return Ok(Some(key.code));
+            }
+        }
+    }
+}
+
+fn print_screen(backend: &TestBackend) {
+    let buffer = backend.buffer();
+    for y in 0..buffer.area.height {
+        let line: String = (0..buffer.area.width)
+            .map(|x| buffer[(x, y)].symbol())
+            .collect();
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Runs the presets on `files`, returning their mismatching blocks sorted by
+/// file and line, along with the commands that failed.
+fn changes(files: &[PathBuf], settings: &AppSettings) -> (Vec<Change>, Vec<CommandFailure>) {
+    let state = RunState::default();
+    let mut changes: Vec<Change> = files
+        .par_iter()
+        .flat_map_iter(|file| file_changes(file, settings, &state))
+        .collect();
+    state.finish_presets();
+    changes.sort_by(|a, b| {
+        (&a.path, a.replacement.start_line).cmp(&(&b.path, b.replacement.start_line))
+    });
+
+    (changes, state.into_report().failures)
+}
+
+/// Mismatching blocks of `file`, the failures of the commands on its other
+/// blocks being recorded in `state`.
+fn file_changes(file: &Path, settings: &AppSettings, state: &RunState) -> Vec<Change> {
+    let content = match fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(e) => {
+            warn!("Skipping `{}`: {e:#}", file.display());
+            return Vec::new();
+        }
+    };
+    let replacements =
+        process_content_partially(file, &content, settings, &RunOptions::default(), state);
+
+    let lines: Vec<&str> = content.lines().collect();
+    replacements
+        .into_iter()
+        .map(|replacement| Change {
+            path: file.to_path_buf(),
+            original: lines
+                .get(replacement.start_line..replacement.end_line.min(lines.len()))
+                .unwrap_or_default()
+                .join("\n"),
+            replacement,
+            decision: Decision::Pending,
+        })
+        .collect()
+}
+
+/// Writes the applied changes, file by file.
+fn write_applied(changes: &[Change], settings: &AppSettings) -> Result<()> {
+    let mut files: Vec<&PathBuf> = changes
+        .iter()
+        .filter(|change| change.decision == Decision::Apply)
+        .map(|change| &change.path)
+        .collect();
+    files.dedup();
+
+    for file in files {
+        let mut replacements: Vec<CodeBlock> = changes
+            .iter()
+            .filter(|change| &change.path == file && change.decision == Decision::Apply)
+            .map(|change| change.replacement.clone())
+            .collect();
+        // Replacements are applied from the last block to the first
+        replacements.sort_by_key(|block| std::cmp::Reverse(block.start_line));
+
+        let content = fs::read_to_string(file)
+            .with_context(|| format!("Failed to read file: {}", file.display()))?;
+        fs::write(file, updated_document(&content, replacements, settings))
+            .with_context(|| format!("Failed to write file: {}", file.display()))?;
+        info!("Updated: {}", file.display());
+    }
+
+    Ok(())
+}
+
+struct Review {
+    changes: Vec<Change>,
+    failures: Vec<CommandFailure>,
+    table: TableState,
+    scroll: u16,
+    /// Whether the interface is displayed in the terminal
+    tty: bool,
+}
+
+impl Review {
+    /// Handles the keys returned by `next_key` until the user quits, returning
+    /// whether the applied changes must be written. Running out of keys quits
+    /// without writing.
+    fn run<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        mut next_key: impl FnMut() -> Result<Option<KeyCode>>,
+        settings: &AppSettings,
+    ) -> Result<bool> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Some(key) = next_key()? else {
+                return Ok(false);
+            };
+            match key {
+                KeyCode::Char('q') => return Ok(true),
+                KeyCode::Esc => return Ok(false),
+                KeyCode::Char('a') => self.decide(Decision::Apply),
+                KeyCode::Char('s') => self.decide(Decision::Skip),
+                KeyCode::Char('e') => self.edit(terminal, settings)?,
+                KeyCode::Down | KeyCode::Char('j') => self.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => {
+                    self.table.select_previous();
+                    self.scroll = 0;
+                }
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+                KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+                _ => {}
+            }
+        }
+    }
+
+    fn select_next(&mut self) {
+        self.table.select_next();
+        self.scroll = 0;
+    }
+
+    fn selected(&self) -> Option<&Change> {
+        self.table.selected().and_then(|row| self.changes.get(row))
+    }
+
+    fn decide(&mut self, decision: Decision) {
+        if let Some(change) = self
+            .table
+            .selected()
+            .and_then(|row| self.changes.get_mut(row))
+        {
+            change.decision = decision;
+            self.select_next();
+        }
+    }
+
+    /// Opens the selected block in `$EDITOR`, then runs the presets on the
+    /// edited file again.
+    fn edit<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        settings: &AppSettings,
+    ) -> Result<()> {
+        let Some(change) = self.selected() else {
+            return Ok(());
+        };
+        let path = change.path.clone();
+        let line = change.replacement.start_line + 1;
+
+        let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+        let mut words = editor.split_whitespace();
+        let program = words.next().unwrap_or("vi");
+
+        if self.tty {
+            ratatui::try_restore()?;
+        }
+        let status = Command::new(program)
+            .args(words)
+            .arg(format!("+{line}"))
+            .arg(&path)
+            .status();
+        if self.tty {
+            ratatui::try_init()?;
+            terminal.clear()?;
+        }
+        status.with_context(|| format!("Failed to run the editor `{editor}`"))?;
+
+        // Decisions on the other blocks of the file are kept
+        let decisions: Vec<(String, Decision)> = self
+            .changes
+            .iter()
+            .filter(|change| change.path == path)
+            .map(|change| (change.original.clone(), change.decision))
+            .collect();
+        self.changes.retain(|change| change.path != path);
+        self.failures.retain(|failure| failure.path != path);
+        let (changes, failures) = changes(std::slice::from_ref(&path), settings);
+        self.failures.extend(failures);
+        for mut change in changes {
+            if let Some((_, decision)) = decisions
+                .iter()
+                .find(|(original, _)| *original == change.original)
+            {
+                change.decision = *decision;
+            }
+            self.changes.push(change);
+        }
+        self.changes.sort_by(|a, b| {
+            (&a.path, a.replacement.start_line).cmp(&(&b.path, b.replacement.start_line))
+        });
+        if self.table.selected() >= Some(self.changes.len()) {
+            self.table.select(self.changes.len().checked_sub(1));
+        }
+        self.scroll = 0;
+
+        Ok(())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [table_area, diff_area, help_area] = Layout::vertical([
+            Constraint::Percentage(35),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let count = |decision| {
+            self.changes
+                .iter()
+                .filter(|change| change.decision == decision)
+                .count()
+        };
+        let mut title = format!(
+            " mdcr review: {} to apply, {} skipped, {} pending",
+            count(Decision::Apply),
+            count(Decision::Skip),
+            count(Decision::Pending)
+        );
+        if !self.failures.is_empty() {
+            title.push_str(&format!(", {} failed", self.failures.len()));
+        }
+        title.push(' ');
+
+        // Blocks are grouped by file, whose name is only displayed once
+        let rows = self.changes.iter().enumerate().map(|(row, change)| {
+            let first_of_file = row == 0 || self.changes[row - 1].path != change.path;
+            Row::new(vec![
+                Cell::from(if first_of_file {
+                    change.path.display().to_string()
+                } else {
+                    String::new()
+                }),
+                Cell::from(format!(
+                    "{}-{}",
+                    change.replacement.start_line + 1,
+                    change.replacement.end_line
+                )),
+                Cell::from(decision_label(change.decision)).style(decision_style(change.decision)),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(11),
+                Constraint::Length(10),
+            ],
+        )
+        .header(
+            Row::new(["File", "Lines", "Decision"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::new().borders(Borders::ALL).title(title))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, table_area, &mut self.table);
+
+        let [current_area, output_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(diff_area);
+        let (current, output) = self
+            .selected()
+            .map(|change| side_by_side(&change.original, &change.replacement.code))
+            .unwrap_or_default();
+        frame.render_widget(
+            Paragraph::new(current)
+                .block(Block::new().borders(Borders::ALL).title(" Current "))
+                .scroll((self.scroll, 0)),
+            current_area,
+        );
+        frame.render_widget(
+            Paragraph::new(output)
+                .block(Block::new().borders(Borders::ALL).title(" Command output "))
+                .scroll((self.scroll, 0)),
+            output_area,
+        );
+
+        frame.render_widget(
+            Paragraph::new(
+                "↑/↓: select  a: apply  s: skip  e: edit  PgUp/PgDn: scroll  q: write and quit  Esc: quit without writing",
+            )
+            .style(Style::new().fg(Color::DarkGray)),
+            help_area,
+        );
+    }
+}
+
+/// Lines of both sides of the diff, the unchanged lines being aligned.
+fn side_by_side(original: &str, code: &str) -> (Vec<Line<'static>>, Vec<Line<'static>>) {
+    let original = format!("{original}\n");
+    let code = format!("{code}\n");
+    let diff = TextDiff::from_lines(&original, &code);
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+
+    for change in diff.iter_all_changes() {
+        let line = change.value().trim_end_matches('\n').to_string();
+        match change.tag() {
+            ChangeTag::Equal => {
+                let rows = left.len().max(right.len());
+                left.resize(rows, Line::default());
+                right.resize(rows, Line::default());
+                left.push(Line::from(line.clone()));
+                right.push(Line::from(line));
+            }
+            ChangeTag::Delete => left.push(Line::styled(line, Style::new().fg(Color::Red))),
+            ChangeTag::Insert => right.push(Line::styled(line, Style::new().fg(Color::Green))),
+        }
+    }
+
+    (left, right)
+}
+
+fn decision_label(decision: Decision) -> &'static str {
+    match decision {
+        Decision::Pending => "pending",
+        Decision::Apply => "apply",
+        Decision::Skip => "skip",
+    }
+}
+
+fn decision_style(decision: Decision) -> Style {
+    match decision {
+        Decision::Pending => Style::new().fg(Color::Yellow),
+        Decision::Apply => Style::new().fg(Color::Green),
+        Decision::Skip => Style::new().fg(Color::DarkGray),
+    }
+}
@@ -1,85 +1,1057 @@
-use crate::config::{AppSettings, OutputMode, PresetConfig};
+use crate::config::{AppSettings, OutputMode, PresetConfig, ReplaceOn};
 
+use crate::artifact::{reference_block, Artifact};
+use crate::cache::EnvCache;
 use crate::codeblock::{CodeBlock, CodeBlockProcessingResult};
-use crate::command::{command_to_string, run_command};
+use crate::command::{command_to_string, run_command, run_comparator, run_hook, ProjectPaths};
+use crate::environment::{tool_version, EnvSnapshot};
+use crate::events::{block_diff, BlockEvent, BlockStatus, EventLog};
+use crate::frontmatter;
+use crate::git;
+use crate::ignore::{is_ignored, normalize};
+use crate::interactive;
+use crate::report::{
+    CommandFailure, Failure, FileOutcome, Mismatch, RunReport, SkipAnnotation, SkippedBlock,
+    Timing, UnmatchedBlock,
+};
+use crate::variables::substitute;
 
 use anyhow::anyhow;
 use anyhow::{Context, Result};
-use log::{debug, error, info};
+use indicatif::ProgressBar;
+use log::{debug, error, info, log, trace, warn};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Output;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
-pub fn process(path: PathBuf, config: &AppSettings, check_only: bool) -> anyhow::Result<()> {
-    let files = collect_markdown_files(&path)?;
+/// Where processed documents are written.
+#[derive(Debug, Default, Clone)]
+pub enum OutputTarget {
+    /// Rewrite the Markdown files in place
+    #[default]
+    InPlace,
+    /// Print the processed documents on stdout, never touching the files
+    Stdout,
+    /// Write the processed documents into a directory mirroring the sources
+    Directory(PathBuf),
+    /// Only collect the changes, e.g. to render them as a patch
+    DryRun,
+}
+
+/// A processed document collected instead of being written in place, or
+/// rewritten in place.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub path: PathBuf,
+    pub original: String,
+    pub processed: String,
+}
+
+/// Options controlling how a run processes files.
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    /// Do not modify files, fail on mismatching blocks instead
+    pub check_only: bool,
+    /// Record the environment of each command and diff it on failures
+    pub debug_env: bool,
+    /// Stop processing at the first command failure or check mismatch
+    pub fail_fast: bool,
+    /// Check all the files, along with `check_only`, whatever the write paths
+    pub force_check: bool,
+    /// Ask before applying each replacement
+    pub interactive: bool,
+    /// Stop running commands once this many were started
+    pub max_commands: Option<usize>,
+    /// Stop running commands once the first one started this long ago
+    pub max_total_runtime: Option<Duration>,
+    /// Root of the project for `{repo_root}`, detected from `.git` otherwise
+    pub project_root: Option<PathBuf>,
+    /// Markdown files to process when walking directories
+    pub files: FileFilter,
+    pub output: OutputTarget,
+    /// Paths whose files are only checked, whatever `check_only`
+    pub check_paths: Vec<PathBuf>,
+    /// Paths whose files are updated, whatever `check_only`
+    pub write_paths: Vec<PathBuf>,
+    /// Languages of the processed blocks, all of them when empty
+    pub languages: Vec<String>,
+    /// Lines the processed blocks overlap, 1-based and inclusive
+    pub lines: Option<(usize, usize)>,
+}
+
+impl RunOptions {
+    /// Whether `block` is processed during this run.
+    fn selects(&self, block: &CodeBlock) -> bool {
+        (self.languages.is_empty() || self.languages.contains(&block.lang))
+            && self
+                .lines
+                .is_none_or(|(start, end)| start <= block.end_line && block.start_line < end)
+    }
+
+    /// Options for the file `path`, in check mode when the closest of the
+    /// check and write paths containing it is a check path, or with
+    /// `force_check`.
+    pub fn for_file(&self, path: &Path) -> Cow<'_, Self> {
+        if self.force_check {
+            return Cow::Borrowed(self);
+        }
+
+        let path = canonical(path);
+        let depth = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|parent| canonical(parent))
+                .filter(|parent| path.starts_with(parent))
+                .map(|parent| parent.components().count())
+                .max()
+        };
+        let check_only = match (depth(&self.check_paths), depth(&self.write_paths)) {
+            // Equal paths are only checked, to be on the safe side
+            (Some(check), Some(write)) => check >= write,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return Cow::Borrowed(self),
+        };
+
+        if check_only == self.check_only {
+            Cow::Borrowed(self)
+        } else {
+            Cow::Owned(Self {
+                check_only,
+                ..self.clone()
+            })
+        }
+    }
+}
+
+/// `paths` without those inside another one, so that no file is processed
+/// twice.
+pub fn outermost_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut outermost: Vec<PathBuf> = Vec::new();
+
+    for path in &paths {
+        let normal = normalize(path);
+        let inside = |other: &PathBuf| normal.starts_with(normalize(other));
+        // Equal paths are kept once, as the first one is already in
+        if !outermost.iter().any(inside)
+            && !paths
+                .iter()
+                .any(|other| normalize(other) != normal && inside(other))
+        {
+            outermost.push(path.clone());
+        }
+    }
+
+    outermost
+}
+
+/// Canonical `path`, so that `docs` contains `/repo/docs/a.md` and
+/// `docs/../docs/a.md`, or `path` normalized when it does not exist.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| normalize(path))
+}
+
+/// Outcome of a `before` hook, set once it ran.
+type HookOutcome = OnceLock<Result<(), String>>;
+
+/// State shared by all the files processed during a run.
+#[derive(Default)]
+pub struct RunState {
+    pub report: Mutex<RunReport>,
+    preset_failures: Mutex<HashMap<String, usize>>,
+    /// Environments of the last successful run of each preset
+    previous_environments: EnvCache,
+    environments: Mutex<BTreeMap<String, EnvSnapshot>>,
+    unsuccessful_presets: Mutex<HashSet<String>>,
+    tool_versions: Mutex<HashMap<String, Option<String>>>,
+    stopped: AtomicBool,
+    /// Commands started, and when the first one was, for the quotas
+    commands: AtomicUsize,
+    first_command: OnceLock<Instant>,
+    documents: Mutex<Vec<Document>>,
+    /// Files rewritten in place
+    updated_files: Mutex<Vec<Document>>,
+    /// Observer of the progress of the code blocks
+    events: Option<Sender<BlockEvent>>,
+    event_log: Option<EventLog>,
+    /// Files done out of those found, drawn on stderr
+    progress: Option<ProgressBar>,
+    /// Outcome of the `before` hook of each preset which started
+    before_hooks: Mutex<HashMap<String, Arc<HookOutcome>>>,
+    /// Presets which started and have an `after` hook, in order
+    after_hooks: Mutex<Vec<(String, PresetConfig)>>,
+}
+
+impl RunState {
+    pub fn new(previous_environments: EnvCache) -> Self {
+        Self {
+            previous_environments,
+            ..Self::default()
+        }
+    }
+
+    /// Sends the progress of the code blocks to `events`.
+    pub fn with_events(self, events: Sender<BlockEvent>) -> Self {
+        Self {
+            events: Some(events),
+            ..self
+        }
+    }
+
+    /// Writes the progress of the code blocks to `log`.
+    pub fn with_event_log(self, log: EventLog) -> Self {
+        Self {
+            event_log: Some(log),
+            ..self
+        }
+    }
+
+    /// Shows the files processed on `progress`.
+    pub fn with_progress(self, progress: ProgressBar) -> Self {
+        Self {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    fn add_files(&self, count: usize) {
+        if let Some(progress) = &self.progress {
+            progress.inc_length(count as u64);
+        }
+    }
+
+    fn start_file(&self, path: &Path) {
+        if let Some(progress) = &self.progress {
+            progress.set_message(path.display().to_string());
+        }
+    }
+
+    fn finish_file(&self) {
+        if let Some(progress) = &self.progress {
+            progress.inc(1);
+        }
+    }
+
+    /// Whether processing stopped early because of `--fail-fast`, or because
+    /// the run was cancelled.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Stops processing the remaining blocks, running commands are not killed.
+    pub fn cancel(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Runs the `before` hook of `preset` the first time one of its commands
+    /// is about to run, the other blocks waiting for it.
+    fn start_preset(&self, preset: &str, cfg: &PresetConfig) -> Result<()> {
+        if cfg.before.is_empty() && cfg.after.is_empty() {
+            return Ok(());
+        }
+
+        let hook = self
+            .before_hooks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(preset.to_string())
+            .or_default()
+            .clone();
+        hook.get_or_init(|| {
+            if !cfg.after.is_empty() {
+                if let Ok(mut after_hooks) = self.after_hooks.lock() {
+                    after_hooks.push((preset.to_string(), cfg.clone()));
+                }
+            }
+            run_hook(cfg, &cfg.before).map_err(|e| format!("{e:#}"))
+        })
+        .clone()
+        .map_err(|e| anyhow!("The `before` hook of preset `{preset}` failed: {e}"))
+    }
+
+    /// Runs the `after` hooks of the presets which started, even when their
+    /// `before` hook failed.
+    pub fn finish_presets(&self) {
+        let after_hooks = self
+            .after_hooks
+            .lock()
+            .map(|mut hooks| std::mem::take(&mut *hooks))
+            .unwrap_or_default();
+        for (preset, cfg) in after_hooks {
+            if let Err(e) = run_hook(&cfg, &cfg.after) {
+                error!("The `after` hook of preset `{preset}` failed: {e:#}");
+            }
+        }
+    }
+
+    /// Sends an event to the observers, if any. Events are only built when
+    /// someone listens, as diffing blocks is not free.
+    fn emit(&self, event: impl FnOnce() -> BlockEvent) {
+        if self.events.is_none() && self.event_log.is_none() {
+            return;
+        }
+
+        let event = event();
+        if let Some(log) = &self.event_log {
+            log.write(&event);
+        }
+        if let Some(events) = &self.events {
+            // The observer going away must not interrupt the run
+            let _ = events.send(event);
+        }
+    }
+
+    fn stop(&self, options: &RunOptions) {
+        if options.fail_fast && !self.stopped.swap(true, Ordering::Relaxed) {
+            info!("Stopping at the first failure (`--fail-fast`)");
+        }
+    }
+
+    /// Takes the documents collected by the `Stdout` and `DryRun` output
+    /// targets, sorted by path.
+    pub fn take_documents(&self) -> Vec<Document> {
+        let mut documents = self
+            .documents
+            .lock()
+            .map(|mut documents| std::mem::take(&mut *documents))
+            .unwrap_or_default();
+        documents.sort_by(|a, b| a.path.cmp(&b.path));
+        documents
+    }
+
+    fn collect_document(&self, path: &Path, original: &str, processed: String) {
+        if let Ok(mut documents) = self.documents.lock() {
+            documents.push(Document {
+                path: path.to_path_buf(),
+                original: original.to_string(),
+                processed,
+            });
+        }
+    }
+
+    /// Takes the files rewritten in place, sorted by path.
+    pub fn take_updated_files(&self) -> Vec<Document> {
+        let mut files = self
+            .updated_files
+            .lock()
+            .map(|mut files| std::mem::take(&mut *files))
+            .unwrap_or_default();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+
+    pub fn into_report(self) -> RunReport {
+        let mut report = self.report.into_inner().unwrap_or_default();
+        report.sort();
+        report
+    }
+
+    /// Previous environments, updated with the environments of the presets
+    /// which neither failed nor mismatched during this run.
+    pub fn updated_environments(&self) -> EnvCache {
+        let mut presets = self.previous_environments.presets.clone();
+
+        if let (Ok(unsuccessful), Ok(environments)) =
+            (self.unsuccessful_presets.lock(), self.environments.lock())
+        {
+            presets.extend(
+                environments
+                    .iter()
+                    .filter(|(preset, _)| !unsuccessful.contains(*preset))
+                    .map(|(preset, snapshot)| (preset.clone(), snapshot.clone())),
+            );
+        }
+
+        EnvCache { presets }
+    }
+
+    fn record_environment(&self, preset: &str, command: &Command) {
+        let program = command.get_program().to_string_lossy().to_string();
+        let version = match self.tool_versions.lock() {
+            Ok(mut versions) => versions
+                .entry(program.clone())
+                .or_insert_with(|| tool_version(&program))
+                .clone(),
+            Err(_) => None,
+        };
+
+        let snapshot = EnvSnapshot::capture(command, version);
+        debug!(
+            "Environment of preset `{}`: cwd `{}`, program `{}`, tool version `{}`",
+            preset,
+            snapshot.cwd,
+            snapshot.program,
+            snapshot.tool_version.as_deref().unwrap_or("unknown")
+        );
+        trace!(
+            "Environment variables of preset `{preset}`: {:?}",
+            snapshot.env
+        );
+
+        if let Ok(mut environments) = self.environments.lock() {
+            environments.insert(preset.to_string(), snapshot);
+        }
+    }
+
+    /// Logs, once per preset, how its environment differs from the last
+    /// successful run.
+    fn report_environment_changes(&self, preset: &str) {
+        let first_time = self
+            .unsuccessful_presets
+            .lock()
+            .map(|mut unsuccessful| unsuccessful.insert(preset.to_string()))
+            .unwrap_or_default();
+        if !first_time {
+            return;
+        }
+
+        let Some(previous) = self.previous_environments.presets.get(preset) else {
+            warn!("No successful run of preset `{preset}` was recorded with `--debug-env` yet");
+            return;
+        };
+        let Some(current) = self
+            .environments
+            .lock()
+            .ok()
+            .and_then(|e| e.get(preset).cloned())
+        else {
+            return;
+        };
+
+        let changes = current.diff(previous);
+        if changes.is_empty() {
+            warn!("Environment of preset `{preset}` is identical to its last successful run");
+        } else {
+            warn!(
+                "Environment of preset `{}` changed since its last successful run:\n  {}",
+                preset,
+                changes.join("\n  ")
+            );
+        }
+    }
+
+    /// Reserves a command against the quotas, returning the reason the block
+    /// is skipped when one of them is exhausted.
+    fn exceeded_quota(&self, options: &RunOptions) -> Option<&'static str> {
+        let started = *self.first_command.get_or_init(Instant::now);
+        if options
+            .max_total_runtime
+            .is_some_and(|max| started.elapsed() >= max)
+        {
+            return Some("skipped after reaching `--max-total-runtime`");
+        }
+
+        let commands = self.commands.fetch_add(1, Ordering::Relaxed);
+        if options.max_commands.is_some_and(|max| commands >= max) {
+            return Some("skipped after reaching `--max-commands`");
+        }
+
+        None
+    }
+
+    fn record_failure(&self, preset: &str) {
+        if let Ok(mut failures) = self.preset_failures.lock() {
+            *failures.entry(preset.to_string()).or_default() += 1;
+        }
+    }
+
+    fn failures(&self, preset: &str) -> usize {
+        self.preset_failures
+            .lock()
+            .map(|failures| failures.get(preset).copied().unwrap_or_default())
+            .unwrap_or_default()
+    }
+}
+
+/// Processes the Markdown files of `path`, returning the outcome of each of
+/// them. Their mismatches, failures and timings are recorded in `state`.
+pub fn process(
+    path: PathBuf,
+    config: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> RunReport {
+    let files = match collect_markdown_files(&path, &options.files) {
+        Ok(files) => files,
+        Err(e) => return vec![file_outcome(&path, Err(e))].into(),
+    };
 
     // Process files in parallel
-    let results: Vec<anyhow::Result<()>> = files
+    state.add_files(files.len());
+    let outcomes: Vec<FileOutcome> = files
         .par_iter()
-        .map(|file| process_markdown_file(file, config, check_only))
+        .map(|file| {
+            state.start_file(file);
+            let outcome = file_outcome(
+                file,
+                process_markdown_file(&path, file, config, options, state),
+            );
+            state.finish_file();
+            outcome
+        })
         .collect();
 
-    if results.iter().any(Result::is_err) {
-        return Err(anyhow!("One or more files failed to process"));
+    outcomes.into()
+}
+
+/// Checks the Markdown files of `path` as they were at the git revision `rev`,
+/// without checking it out. Files are never modified.
+pub fn process_revision(
+    rev: &str,
+    path: &Path,
+    config: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> RunReport {
+    let files: Vec<PathBuf> = match git::list_files(rev, path) {
+        Ok(files) => files
+            .into_iter()
+            .filter(|file| options.files.is_markdown(file))
+            .collect(),
+        Err(e) => return vec![file_outcome(path, Err(e))].into(),
+    };
+
+    state.add_files(files.len());
+    let outcomes: Vec<FileOutcome> = files
+        .par_iter()
+        .map(|file| {
+            let display_path = PathBuf::from(format!("{rev}:{}", file.display()));
+            if state.is_stopped() {
+                state.finish_file();
+                return file_outcome(&display_path, Ok(0));
+            }
+
+            state.start_file(&display_path);
+            let result = git::show_file(rev, file).and_then(|content| {
+                process_content(&display_path, &content, config, options, state)
+                    .map(|replacements| replacements.len())
+            });
+            state.finish_file();
+            file_outcome(&display_path, result)
+        })
+        .collect();
+
+    outcomes.into()
+}
+
+/// Outcome of a file, logging the errors without a failure class as the other
+/// ones were logged block by block.
+pub fn file_outcome(path: &Path, result: anyhow::Result<usize>) -> FileOutcome {
+    if let Err(e) = &result {
+        if Failure::of(e) == Failure::Config {
+            error!("{e:#}");
+        }
     }
 
-    Ok(())
+    FileOutcome::new(path, result)
 }
 
 fn process_markdown_file(
+    root: &Path,
     path: &Path,
     config: &AppSettings,
-    check_only: bool,
-) -> anyhow::Result<()> {
+    options: &RunOptions,
+    state: &RunState,
+) -> anyhow::Result<usize> {
+    if state.is_stopped() {
+        debug!("Skipping file `{}` (`--fail-fast`)", path.display());
+        return Ok(0);
+    }
+    let options = &*options.for_file(path);
+    let nested = config.nested_for(path)?;
+    let config = nested.as_deref().unwrap_or(config);
+
     let content = fs::read_to_string(path)?;
-    let blocks = crate::codeblock::parse_code_blocks(path, &content);
+    let replacements = process_content(path, &content, config, options, state)?;
+    check_freshness(path, &content, config, options)?;
+
+    write_document(root, path, &content, replacements, config, options, state)
+}
+
+/// Writes the processed document to the configured output, `root` being the
+/// path given on the command line that `path` was found in. Returns the number
+/// of replaced code blocks.
+fn write_document(
+    root: &Path,
+    path: &Path,
+    content: &str,
+    replacements: Vec<CodeBlock>,
+    config: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> anyhow::Result<usize> {
+    if options.check_only {
+        return Ok(0);
+    }
+    let replacements = if options.interactive && !replacements.is_empty() {
+        interactive::approve(path, content, replacements, state)?
+    } else {
+        replacements
+    };
+    let replaced = replacements.len();
+
+    match &options.output {
+        OutputTarget::InPlace => {
+            if replacements.is_empty() {
+                debug!("No changes needed for file `{}`", path.display());
+                return Ok(0);
+            }
+
+            let document = updated_document(content, replacements, config);
+            fs::write(path, &document)?;
+            info!("Updated: {}", path.display());
+            if let Ok(mut updated_files) = state.updated_files.lock() {
+                updated_files.push(Document {
+                    path: path.to_path_buf(),
+                    original: content.to_string(),
+                    processed: document,
+                });
+            }
+        }
+        OutputTarget::Stdout => {
+            let document = if replacements.is_empty() {
+                content.to_string()
+            } else {
+                updated_document(content, replacements, config)
+            };
+            state.collect_document(path, content, document);
+        }
+        OutputTarget::DryRun => {
+            if replacements.is_empty() {
+                debug!("No changes needed for file `{}`", path.display());
+                return Ok(0);
+            }
+
+            info!("Would update: {}", path.display());
+            state.collect_document(
+                path,
+                content,
+                updated_document(content, replacements, config),
+            );
+        }
+        OutputTarget::Directory(dir) => {
+            let destination = dir.join(mirrored_path(root, path));
+            let document = if replacements.is_empty() {
+                content.to_string()
+            } else {
+                updated_document(content, replacements, config)
+            };
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&destination, document)
+                .with_context(|| format!("Failed to write `{}`", destination.display()))?;
+            info!("Written: {}", destination.display());
+        }
+    }
+
+    Ok(replaced)
+}
+
+/// Applies the replacements, recording the date of the update in the front
+/// matter field configured with `verified_field`.
+pub fn updated_document(
+    content: &str,
+    replacements: Vec<CodeBlock>,
+    config: &AppSettings,
+) -> String {
+    let document = apply_replacements(content, replacements, config.settings.final_newline);
+
+    match &config.settings.verified_field {
+        Some(field) => {
+            frontmatter::set_field(&document, field, &frontmatter::today()).unwrap_or(document)
+        }
+        None => document,
+    }
+}
+
+/// Fails a file in check mode when the date of its `verified_field` is older
+/// than `max_verified_age_days`.
+fn check_freshness(
+    path: &Path,
+    content: &str,
+    config: &AppSettings,
+    options: &RunOptions,
+) -> anyhow::Result<()> {
+    let (Some(field), Some(max_age)) = (
+        &config.settings.verified_field,
+        config.settings.max_verified_age_days,
+    ) else {
+        return Ok(());
+    };
+    if !options.check_only {
+        return Ok(());
+    }
+    let Some(date) = frontmatter::field(content, field) else {
+        return Ok(());
+    };
+
+    match frontmatter::age_in_days(date) {
+        Some(age) if age > max_age => {
+            error!(
+                "`{}` was last verified on {date}, {age} days ago (more than {max_age} days)",
+                path.display()
+            );
+            Err(anyhow::Error::new(Failure::Mismatch)
+                .context(format!("Stale file `{}`", path.display())))
+        }
+        Some(_) => Ok(()),
+        None => {
+            warn!(
+                "Invalid date `{date}` in the `{field}` field of `{}`, expected YYYY-MM-DD",
+                path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Path of `file` relative to `root`, a single file being mirrored by its name
+/// and a glob pattern by the directory preceding its first wildcard.
+fn mirrored_path<'a>(root: &Path, file: &'a Path) -> &'a Path {
+    if root.is_file() {
+        return file.file_name().map(Path::new).unwrap_or(file);
+    }
+
+    if !root.exists() && is_glob(root) {
+        let base: PathBuf = root
+            .components()
+            .take_while(|component| !is_glob(Path::new(component.as_os_str())))
+            .collect();
+        return file.strip_prefix(base).unwrap_or(file);
+    }
+
+    file.strip_prefix(root).unwrap_or(file)
+}
+
+/// Runs the matching presets on every code block of `content` and returns the
+/// blocks that need to be replaced, without touching the filesystem.
+pub fn process_content(
+    path: &Path,
+    content: &str,
+    config: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> anyhow::Result<Vec<CodeBlock>> {
+    let result = run_content(path, content, config, options, state);
+    finish_content(path, result, options)
+}
+
+/// Like `process_content`, but returns the replacements of the blocks whose
+/// commands succeeded even when others failed, the failures and mismatches
+/// being only recorded in `state`.
+pub fn process_content_partially(
+    path: &Path,
+    content: &str,
+    config: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> Vec<CodeBlock> {
+    run_content(path, content, config, options, state).replacements
+}
+
+fn run_content(
+    path: &Path,
+    content: &str,
+    config: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> CodeBlockProcessingResult {
+    let (blocks, skip_annotations) = parse_blocks(path, content, options);
+    record_unmatched(&blocks, config, state);
 
     let results: Vec<CodeBlockProcessingResult> = blocks
         .iter()
         .rev()
-        .map(|block| process_block(path, config, block, check_only))
+        .map(|block| process_block(path, config, block, options, state))
         .collect();
 
-    let file_has_command_failures = results.iter().any(|r| r.had_command_failure);
-    let file_has_mismatches = results.iter().any(|r| r.had_mismatch);
-    let all_replacements: Vec<_> = results.into_iter().flat_map(|r| r.replacements).collect();
+    merge_results(results, skip_annotations, state)
+}
 
-    if file_has_command_failures {
-        return Err(anyhow!(
+/// Parses the code blocks of `content` selected by the options, setting aside
+/// those opted out with a skip marker.
+fn parse_blocks(
+    path: &Path,
+    content: &str,
+    options: &RunOptions,
+) -> (Vec<CodeBlock>, Vec<SkipAnnotation>) {
+    let (skipped_blocks, blocks): (Vec<_>, Vec<_>) =
+        crate::codeblock::parse_code_blocks(path, content)
+            .into_iter()
+            .filter(|block| options.selects(block))
+            .partition(|block| block.skip.is_some());
+
+    let skip_annotations = skipped_blocks
+        .iter()
+        .filter_map(|block| {
+            let marker = block.skip.as_ref()?;
+            debug!(
+                "Skipping code block in `{}:{}-{}` ({})",
+                path.display(),
+                block.start_line,
+                block.end_line,
+                marker.reason.as_deref().unwrap_or("no reason given")
+            );
+            Some(SkipAnnotation::new(block, marker))
+        })
+        .collect();
+
+    (blocks, skip_annotations)
+}
+
+/// Records the blocks with a language that no preset of the config matches,
+/// whether or not it runs during this run.
+fn record_unmatched(blocks: &[CodeBlock], config: &AppSettings, state: &RunState) {
+    let unmatched = blocks
+        .iter()
+        .filter(|block| !block.lang.is_empty() && !config.skips(block) && !config.covers(block));
+    if let Ok(mut report) = state.report.lock() {
+        report.unmatched.extend(unmatched.map(UnmatchedBlock::new));
+    }
+}
+
+/// Merges the results of the blocks of a file, recording them in the report.
+fn merge_results(
+    results: Vec<CodeBlockProcessingResult>,
+    skip_annotations: Vec<SkipAnnotation>,
+    state: &RunState,
+) -> CodeBlockProcessingResult {
+    let mut result = CodeBlockProcessingResult::default();
+    for block_result in results {
+        result.merge(block_result);
+    }
+    // Only the last replacement of a block is kept, which holds the output of
+    // them all when presets are chained
+    let mut replaced = HashSet::new();
+    result.replacements.reverse();
+    result
+        .replacements
+        .retain(|block| replaced.insert(block.start_line));
+    result.replacements.reverse();
+
+    if let Ok(mut report) = state.report.lock() {
+        report
+            .mismatches
+            .extend(std::mem::take(&mut result.mismatches));
+        report.failures.extend(std::mem::take(&mut result.failures));
+        report.timings.extend(std::mem::take(&mut result.timings));
+        report.skipped.extend(std::mem::take(&mut result.skipped));
+        report.skip_annotations.extend(skip_annotations);
+    }
+
+    result
+}
+
+/// Returns the replacements of a file, or the most severe failure.
+fn finish_content(
+    path: &Path,
+    result: CodeBlockProcessingResult,
+    options: &RunOptions,
+) -> anyhow::Result<Vec<CodeBlock>> {
+    if result.had_command_failure {
+        return Err(anyhow::Error::new(Failure::Command).context(format!(
             "One or more commands failed in file `{}`",
             path.display()
-        ));
+        )));
     }
 
-    if check_only && file_has_mismatches {
-        return Err(anyhow!(
-            "Checking some files failed, see the logs for details.",
-        ));
+    if options.check_only && result.had_mismatch {
+        return Err(anyhow::Error::new(Failure::Mismatch)
+            .context("Checking some files failed, see the logs for details."));
     }
 
-    if all_replacements.is_empty() {
-        debug!("No changes needed for file `{}`", path.display());
-        return Ok(());
+    Ok(result.replacements)
+}
+
+/// Processes the markdown files of all `paths` preset by preset: all the
+/// blocks of a preset, across all files, are executed together before moving
+/// on to the next preset.
+pub fn process_grouped_by_preset(
+    paths: &[PathBuf],
+    config: &AppSettings,
+    options: &RunOptions,
+    state: &RunState,
+) -> RunReport {
+    // Paths and files that cannot be read do not prevent processing the others
+    let mut outcomes = Vec::new();
+    let mut files = Vec::new();
+    for path in paths {
+        match collect_markdown_files(path, &options.files) {
+            Ok(found) => files.extend(found.into_iter().map(|file| (path, file))),
+            Err(e) => outcomes.push(file_outcome(path, Err(e))),
+        }
     }
 
-    apply_replacements(all_replacements)
+    // Files with their own presets, from nested configs, are processed apart
+    let (nested, files): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|(_, file)| !matches!(config.nested_for(file), Ok(None)));
+    state.add_files(nested.len());
+    outcomes.par_extend(nested.par_iter().map(|(root, file)| {
+        let outcome = file_outcome(
+            file,
+            process_markdown_file(root, file, config, options, state),
+        );
+        state.finish_file();
+        outcome
+    }));
+
+    let mut roots: Vec<&PathBuf> = Vec::new();
+    let mut documents: Vec<(PathBuf, String)> = Vec::new();
+    for (root, file) in files {
+        match fs::read_to_string(&file) {
+            Ok(content) => {
+                roots.push(root);
+                documents.push((file, content));
+            }
+            Err(e) => outcomes.push(file_outcome(&file, Err(e.into()))),
+        }
+    }
+
+    // Files are only done once all the presets ran on them
+    state.add_files(documents.len());
+    let mut parsed: Vec<(Vec<CodeBlock>, Vec<SkipAnnotation>)> = documents
+        .iter()
+        .map(|(file, content)| parse_blocks(file, content, options))
+        .collect();
+    for (blocks, _) in &parsed {
+        record_unmatched(blocks, config, state);
+    }
+    let file_options: Vec<Cow<RunOptions>> = documents
+        .iter()
+        .map(|(file, _)| options.for_file(file))
+        .collect();
+
+    let mut results: Vec<Vec<CodeBlockProcessingResult>> =
+        parsed.iter().map(|_| Vec::new()).collect();
+    // Original code of the blocks, and the results settled once all the
+    // presets ran, see `settle_chain`
+    let originals: Vec<Vec<String>> = parsed
+        .iter()
+        .map(|(blocks, _)| blocks.iter().map(|block| block.code.clone()).collect())
+        .collect();
+    let mut replacing: HashMap<(usize, usize), Vec<CodeBlockProcessingResult>> = HashMap::new();
+
+    for (preset, preset_cfg) in &config.presets {
+        let group: Vec<(usize, usize, &CodeBlock)> = parsed
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (blocks, _))| {
+                blocks
+                    .iter()
+                    .enumerate()
+                    .map(move |(position, block)| (index, position, block))
+            })
+            .filter(|(_, _, block)| config.runs(preset_cfg, block))
+            .collect();
+
+        if group.is_empty() {
+            continue;
+        }
+
+        debug!(
+            "Executing preset `{}` on {} blocks across all files",
+            preset,
+            group.len()
+        );
+
+        let group_results: Vec<(usize, usize, CodeBlockProcessingResult)> = group
+            .par_iter()
+            .map(|(index, position, block)| {
+                let path = &documents[*index].0;
+                let options = &file_options[*index];
+                let result = process_block_with_preset(
+                    path, config, block, preset, preset_cfg, options, state,
+                );
+                (*index, *position, result)
+            })
+            .collect();
+
+        for (index, position, result) in group_results {
+            // The next presets run on the output of this one
+            if let Some(output) = result
+                .output
+                .as_ref()
+                .filter(|_| config.settings.chain_presets)
+            {
+                let block = &mut parsed[index].0[position];
+                *block = block.with_updated_code(output.clone());
+            }
+            if is_chained_check(config, preset_cfg, &file_options[index]) {
+                replacing.entry((index, position)).or_default().push(result);
+            } else {
+                results[index].push(result);
+            }
+        }
+    }
+    for ((index, position), chain) in replacing {
+        let current = &parsed[index].0[position].code;
+        results[index].extend(settle_chain(&originals[index][position], current, chain));
+    }
+
+    outcomes.extend(
+        documents
+            .iter()
+            .zip(roots)
+            .zip(parsed)
+            .zip(results)
+            .zip(&file_options)
+            .map(
+                |(((((path, content), root), (_, skip_annotations)), results), options)| {
+                    let result = merge_results(results, skip_annotations, state);
+                    let result =
+                        finish_content(path, result, options).and_then(|mut replacements| {
+                            // Replacements are applied from the last block to the first
+                            replacements.sort_by_key(|block| std::cmp::Reverse(block.start_line));
+                            check_freshness(path, content, config, options)?;
+                            write_document(
+                                root,
+                                path,
+                                content,
+                                replacements,
+                                config,
+                                options,
+                                state,
+                            )
+                        });
+                    state.finish_file();
+                    file_outcome(path, result)
+                },
+            ),
+    );
+
+    outcomes.into()
 }
 
 fn process_block(
     path: &Path,
     config: &AppSettings,
     block: &CodeBlock,
-    check_only: bool,
+    options: &RunOptions,
+    state: &RunState,
 ) -> CodeBlockProcessingResult {
-    let mut replacements = Vec::new();
-    let mut had_command_failure = false;
-    let mut had_mismatch = false;
+    let mut result = CodeBlockProcessingResult::default();
+    // With `chain_presets`, each preset runs on the output of the previous ones
+    let mut current = Cow::Borrowed(block);
+    // In check mode, the results of the presets replacing the code, which
+    // only mismatch when the output of the whole chain differs from the block
+    let mut replacing = Vec::new();
 
     for (preset, preset_cfg) in &config.presets {
-        if !preset_cfg.languages.iter().any(|l| l.trim() == block.lang) {
+        if state.is_stopped() {
+            break;
+        }
+
+        if !config.runs(preset_cfg, block) {
             debug!(
                 "Skipping preset `{}` for language `{}` in `{}`",
                 preset,
@@ -89,105 +1061,455 @@ fn process_block(
             continue;
         }
 
+        let preset_result =
+            process_block_with_preset(path, config, &current, preset, preset_cfg, options, state);
+        if let Some(output) = preset_result
+            .output
+            .as_ref()
+            .filter(|_| config.settings.chain_presets)
+        {
+            current = Cow::Owned(block.with_updated_code(output.clone()));
+        }
+        if is_chained_check(config, preset_cfg, options) {
+            replacing.push(preset_result);
+        } else {
+            result.merge(preset_result);
+        }
+    }
+
+    for preset_result in settle_chain(&block.code, &current.code, replacing) {
+        result.merge(preset_result);
+    }
+
+    result
+}
+
+/// Whether the result of `preset_cfg` only mismatches along with the whole
+/// chain of presets, see `settle_chain`.
+fn is_chained_check(config: &AppSettings, preset_cfg: &PresetConfig, options: &RunOptions) -> bool {
+    config.settings.chain_presets
+        && options.check_only
+        && matches!(preset_cfg.output_mode, OutputMode::Replace)
+}
+
+/// In check mode, the presets replacing the code of a block only mismatch when
+/// the output of the whole chain, `current`, differs from the `original` code.
+fn settle_chain(
+    original: &str,
+    current: &str,
+    replacing: Vec<CodeBlockProcessingResult>,
+) -> Vec<CodeBlockProcessingResult> {
+    let changed = current.trim() != original.trim();
+
+    replacing
+        .into_iter()
+        .map(|mut result| {
+            if !changed {
+                result.mismatches.clear();
+                result.had_mismatch = false;
+            }
+            result
+        })
+        .collect()
+}
+
+fn process_block_with_preset(
+    path: &Path,
+    config: &AppSettings,
+    block: &CodeBlock,
+    preset: &String,
+    preset_cfg: &PresetConfig,
+    options: &RunOptions,
+    state: &RunState,
+) -> CodeBlockProcessingResult {
+    let mut result = CodeBlockProcessingResult::default();
+
+    if state.is_stopped() {
+        return result;
+    }
+
+    debug!(
+        "Processing file `{}` and preset `{}` for language `{}` in `{:?}` mode...",
+        block.path.display(),
+        preset,
+        block.lang,
+        preset_cfg.output_mode
+    );
+
+    if is_ignored(config, block, preset) {
         debug!(
-            "Processing file `{}` and preset `{}` for language `{}` in `{:?}` mode...",
-            block.path.display(),
+            "Skipping preset `{}` in `{}:{}-{}`, ignored in the config",
             preset,
-            block.lang,
-            preset_cfg.output_mode
+            path.display(),
+            block.start_line,
+            block.end_line
         );
+        result
+            .skipped
+            .push(SkippedBlock::new(preset, "ignored in the config"));
+        state.emit(|| {
+            BlockEvent::new(block, preset, BlockStatus::Skipped)
+                .with_details("ignored in the config")
+        });
+        return result;
+    }
 
-        match run_command(preset_cfg, &block.code, &block.lang) {
-            Ok((command, output)) => {
-                if !output.status.success() {
-                    error!(
-                        "The command `{}` returned a non-zero exit status ({}) for preset `{}` in `{}:{}-{}`, `{}`",
+    if let Some(max_failures) = config.settings.max_failures_per_preset {
+        if state.failures(preset) >= max_failures {
+            debug!(
+                "Skipping preset `{}` in `{}:{}-{}` due to earlier failures",
+                preset,
+                path.display(),
+                block.start_line,
+                block.end_line
+            );
+            result
+                .skipped
+                .push(SkippedBlock::new(preset, "skipped due to earlier failures"));
+            state.emit(|| {
+                BlockEvent::new(block, preset, BlockStatus::Skipped)
+                    .with_details("skipped due to earlier failures")
+            });
+            return result;
+        }
+    }
+
+    if let Some(reason) = state.exceeded_quota(options) {
+        debug!(
+            "Skipping preset `{}` in `{}:{}-{}`, {}",
+            preset,
+            path.display(),
+            block.start_line,
+            block.end_line,
+            reason
+        );
+        result
+            .skipped
+            .push(SkippedBlock::over_quota(preset, reason));
+        state.emit(|| BlockEvent::new(block, preset, BlockStatus::Skipped).with_details(reason));
+        return result;
+    }
+
+    state.emit(|| BlockEvent::new(block, preset, BlockStatus::Running));
+    let started = Instant::now();
+    let artifact = match preset_cfg.output_mode {
+        OutputMode::Artifact => Artifact::new(preset_cfg).map(Some),
+        _ => Ok(None),
+    };
+    let project = ProjectPaths::of(path, options.project_root.as_deref());
+    let command_result = artifact.and_then(|artifact| {
+        state.start_preset(preset, preset_cfg)?;
+        let artifact_path = artifact.as_ref().map(Artifact::path);
+        let (command, output, substituted) = if preset_cfg.substitute_variables {
+            let substituted = substitute(&block.code, &config.variables)?;
+            let (command, output) = run_command(
+                preset_cfg,
+                &substituted.code,
+                &block.lang,
+                artifact_path,
+                &project,
+            )?;
+            (command, output, Some(substituted))
+        } else {
+            let (command, output) = run_command(
+                preset_cfg,
+                &block.code,
+                &block.lang,
+                artifact_path,
+                &project,
+            )?;
+            (command, output, None)
+        };
+
+        let mut fresh_artifact = None;
+        if let Some(artifact) = &artifact {
+            if output.status.success() {
+                // Assets are only written along with the Markdown files
+                let write = !options.check_only && matches!(options.output, OutputTarget::InPlace);
+                fresh_artifact = Some(artifact.store(block, preset_cfg, write)?);
+            }
+        }
+
+        Ok((command, output, substituted, fresh_artifact))
+    });
+    let duration = started.elapsed();
+    result.timings.push(Timing::new(block, preset, duration));
+
+    let fail = |result: &mut CodeBlockProcessingResult, e: &anyhow::Error| {
+        let severity = preset_cfg.severity;
+        log!(
+            severity.log_level(),
+            "Error executing command for preset `{}` in `{}:{}-{}`: {:#}",
+            preset,
+            path.display(),
+            block.start_line,
+            block.end_line,
+            e
+        );
+        result.had_command_failure = severity.is_error();
+        result.failures.push(CommandFailure::new(
+            block,
+            preset,
+            severity,
+            format!("Error executing command: {e:#}"),
+        ));
+        state.emit(|| {
+            BlockEvent::new(block, preset, BlockStatus::Failed)
+                .with_duration(duration)
+                .with_details(format!("{e:#}"))
+        });
+        state.record_failure(preset);
+        if severity.is_error() {
+            state.stop(options);
+        }
+        if options.debug_env {
+            state.report_environment_changes(preset);
+        }
+    };
+
+    match command_result {
+        Ok((command, output, substituted, fresh_artifact)) => {
+            if options.debug_env {
+                state.record_environment(preset, &command);
+            }
+
+            if !output.status.success() && preset_cfg.replace_on == ReplaceOn::Success {
+                let severity = preset_cfg.severity;
+                log!(
+                    severity.log_level(),
+                    "The command `{}` returned a non-zero exit status ({}) for preset `{}` in `{}:{}-{}`, `{}`",
+                    command_to_string(&command),
+                    output.status.code().unwrap_or(-1),
+                    preset,
+                    path.display(),
+                    block.start_line,
+                    block.end_line,
+                    preset_cfg.decode_output(&output.stderr).trim()
+                );
+                let stderr = preset_cfg.decode_output(&output.stderr);
+                // Failures of `warning` presets are reported without failing the run
+                result.had_command_failure = severity.is_error();
+                result.failures.push(CommandFailure::new(
+                    block,
+                    preset,
+                    severity,
+                    format!(
+                        "The command `{}` returned a non-zero exit status ({}): {}",
                         command_to_string(&command),
                         output.status.code().unwrap_or(-1),
-                        preset,
-                        path.display(),
-                        block.start_line,
-                        block.end_line,
-                        String::from_utf8_lossy(&output.stderr).trim()
-                    );
-                    had_command_failure = true;
-                    continue;
+                        stderr.trim()
+                    ),
+                ));
+                state.emit(|| {
+                    BlockEvent::new(block, preset, BlockStatus::Failed)
+                        .with_duration(duration)
+                        .with_details(stderr.trim())
+                });
+                state.record_failure(preset);
+                if severity.is_error() {
+                    state.stop(options);
                 }
-
-                match handle_preset_result(&output, preset, preset_cfg, block, check_only) {
-                    Ok(Some(replacement)) => {
-                        had_mismatch = true;
-                        replacements.push(replacement);
-                    }
-                    Ok(None) => {}
-                    Err(_) => {
-                        had_mismatch = true;
-                    }
+                if options.debug_env {
+                    state.report_environment_changes(preset);
                 }
+                return result;
             }
-            Err(e) => {
-                error!(
-                    "Error executing command for preset `{}` in `{}`: {}",
+
+            let mut stdout = preset_cfg.decode_output(&output.stdout);
+            if !output.status.success() {
+                debug!(
+                    "The command returned a non-zero exit status ({}) for preset `{}` in `{}:{}-{}`, capturing its output",
+                    output.status.code().unwrap_or(-1),
                     preset,
                     path.display(),
-                    e
+                    block.start_line,
+                    block.end_line
                 );
-                had_command_failure = true;
+                let stderr = preset_cfg.decode_output(&output.stderr);
+                // The last line of stdout is not merged with the first of stderr
+                if !stdout.is_empty() && !stdout.ends_with('\n') && !stderr.is_empty() {
+                    stdout.to_mut().push('\n');
+                }
+                stdout.to_mut().push_str(&stderr);
+            }
+            let stdout = match &substituted {
+                Some(substituted) => substituted.restore(&stdout).into(),
+                None => stdout,
+            };
+            let stdout = match preset_cfg.wrap_output_at {
+                Some(width) => wrap_lines(&stdout, width, &preset_cfg.wrap_marker).into(),
+                None => stdout,
+            };
+
+            // Artifacts are compared with their stored asset, not the output
+            let matches = match fresh_artifact
+                .map_or_else(|| output_matches(&stdout, preset_cfg, block), Ok)
+            {
+                Ok(matches) => matches,
+                Err(e) => {
+                    fail(&mut result, &e);
+                    return result;
+                }
+            };
+
+            match handle_preset_result(&stdout, matches, preset, preset_cfg, block, options) {
+                Ok(Some(replacement)) => {
+                    result.had_mismatch = true;
+                    if matches!(preset_cfg.output_mode, OutputMode::Replace) {
+                        result.output = Some(stdout.trim().to_string());
+                    }
+                    result
+                        .mismatches
+                        .push(Mismatch::new(block, preset, preset_cfg.severity));
+                    state.emit(|| {
+                        BlockEvent::new(block, preset, BlockStatus::Updated)
+                            .with_duration(duration)
+                            .with_details(block_diff(&block.code, &stdout))
+                            .with_replacement(replacement.code.as_str())
+                    });
+                    result.replacements.push(replacement);
+                }
+                Ok(None) => {
+                    state.emit(|| {
+                        BlockEvent::new(block, preset, BlockStatus::Unchanged)
+                            .with_duration(duration)
+                    });
+                    return result;
+                }
+                Err(_) => {
+                    if matches!(preset_cfg.output_mode, OutputMode::Replace) {
+                        result.output = Some(stdout.trim().to_string());
+                    }
+                    result.had_mismatch = preset_cfg.severity.is_error();
+                    result
+                        .mismatches
+                        .push(Mismatch::new(block, preset, preset_cfg.severity));
+                    state.emit(|| {
+                        let event = BlockEvent::new(block, preset, BlockStatus::Mismatch)
+                            .with_duration(duration)
+                            .with_details(block_diff(&block.code, &stdout));
+                        // Artifact references are not derived from the output
+                        match preset_cfg.output_mode {
+                            OutputMode::Replace => {
+                                event.with_replacement(block.fenced(stdout.trim()))
+                            }
+                            _ => event,
+                        }
+                    });
+                    if preset_cfg.severity.is_error() {
+                        state.stop(options);
+                    }
+                }
+            }
+
+            if options.debug_env {
+                state.report_environment_changes(preset);
             }
         }
+        Err(e) => fail(&mut result, &e),
     }
 
-    CodeBlockProcessingResult {
-        replacements,
-        had_command_failure,
-        had_mismatch,
-    }
+    result
 }
 
-fn apply_replacements(replacements: Vec<CodeBlock>) -> Result<()> {
-    let mut replacements_by_file: HashMap<PathBuf, Vec<CodeBlock>> = HashMap::new();
+/// Splices the replacement blocks into `content`. Replacements must be sorted
+/// from the last block to the first so that line numbers stay valid.
+pub fn apply_replacements(
+    content: &str,
+    replacements: Vec<CodeBlock>,
+    final_newline: bool,
+) -> String {
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
 
-    for block in replacements {
-        replacements_by_file
-            .entry(block.path.clone())
-            .or_default()
-            .push(block);
+    for codeblock in replacements {
+        let bounded_end = codeblock.end_line.min(lines.len());
+        let bounded_start = codeblock.start_line.min(bounded_end);
+        debug!(
+            "Applying replacement lines `{}:{}-{}`",
+            codeblock.path.display(),
+            bounded_start,
+            bounded_end
+        );
+        lines.splice(
+            bounded_start..bounded_end,
+            codeblock.code.lines().map(|l| l.to_string()),
+        );
     }
 
-    replacements_by_file
-        .into_par_iter()
-        .map(|(file_path, codeblocks)| -> Result<_> {
-            let mut file_lines: Vec<String> = fs::read_to_string(&file_path)?
-                .lines()
-                .map(String::from)
-                .collect();
+    // Documents without final newline round-trip as is, unless it is enforced
+    let mut document = lines.join("\n");
+    if final_newline || content.ends_with('\n') {
+        document.push('\n');
+    }
 
-            for codeblock in codeblocks {
-                let bounded_end = codeblock.end_line.min(file_lines.len());
-                let bounded_start = codeblock.start_line.min(bounded_end);
-                debug!(
-                    "Applying replacement lines `{}:{}-{}`",
-                    bounded_start,
-                    bounded_end,
-                    file_path.display()
-                );
-                file_lines.splice(
-                    bounded_start..bounded_end,
-                    codeblock.code.lines().map(|l| l.to_string()),
-                );
-            }
+    document
+}
 
-            fs::write(&file_path, file_lines.join("\n") + "\n")?;
-            info!("Updated: {}", file_path.display());
+/// Selects the Markdown files found when walking directories and expanding
+/// glob patterns.
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    /// Extensions of the Markdown files, without leading dot
+    extensions: Vec<String>,
+    /// Patterns of the skipped files and directories
+    exclude: Vec<glob::Pattern>,
+}
 
-            Ok(())
+impl Default for FileFilter {
+    fn default() -> Self {
+        Self {
+            extensions: vec!["md".to_string()],
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl FileFilter {
+    pub fn new(extensions: &[String], exclude: &[String]) -> Result<Self> {
+        let exclude = exclude
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern.trim_end_matches('/'))
+                    .with_context(|| format!("Invalid exclude pattern: {pattern}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            extensions: extensions
+                .iter()
+                .map(|extension| extension.trim().trim_start_matches('.').to_string())
+                .collect(),
+            exclude,
         })
-        .collect::<Result<(), _>>()?;
+    }
+
+    pub fn is_markdown(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|s| s.to_str())
+            .is_some_and(|extension| self.extensions.iter().any(|e| e == extension))
+    }
 
-    Ok(())
+    /// Patterns without a `/` match the name of any file or directory, other
+    /// patterns match the path relative to `root`.
+    pub fn is_excluded(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let name = path.file_name().map(Path::new).unwrap_or(path);
+
+        self.exclude.iter().any(|pattern| {
+            if pattern.as_str().contains('/') {
+                pattern.matches_path(relative)
+            } else {
+                pattern.matches_path(name)
+            }
+        })
+    }
 }
 
-fn collect_markdown_files(path: &Path) -> Result<Vec<PathBuf>> {
+pub fn collect_markdown_files(path: &Path, filter: &FileFilter) -> Result<Vec<PathBuf>> {
+    if !path.try_exists()? && is_glob(path) {
+        return collect_glob(path, filter);
+    }
+
     if !path.try_exists()? {
         return Err(anyhow!(
             "Path does not exist or is not accessible: {}",
@@ -207,30 +1529,98 @@ fn collect_markdown_files(path: &Path) -> Result<Vec<PathBuf>> {
     }
 
     let entries = WalkDir::new(path)
+        .sort_by_file_name()
         .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !filter.is_excluded(path, e.path()))
         .collect::<Result<Vec<_>, _>>()
         .with_context(|| format!("Failed to read directory: {}", path.display()))?
         .into_iter()
-        .filter(|e| e.path().extension().and_then(|s| s.to_str()) == Some("md"))
+        .filter(|e| e.file_type().is_file() && filter.is_markdown(e.path()))
         .map(|e| e.into_path())
         .collect();
 
     Ok(entries)
 }
 
+/// Expands a glob pattern (e.g. `docs/**/*.md`), directories it matches are
+/// walked like directories given on the command line.
+fn collect_glob(pattern: &Path, filter: &FileFilter) -> Result<Vec<PathBuf>> {
+    let pattern = pattern.to_string_lossy();
+    let mut files = Vec::new();
+
+    for entry in glob::glob(&pattern).with_context(|| format!("Invalid glob pattern: {pattern}"))? {
+        let path = entry.with_context(|| format!("Failed to expand glob pattern: {pattern}"))?;
+        if path
+            .ancestors()
+            .any(|ancestor| filter.is_excluded(Path::new(""), ancestor))
+        {
+            continue;
+        }
+        files.extend(collect_markdown_files(&path, filter)?);
+    }
+
+    if files.is_empty() {
+        return Err(anyhow!("Glob pattern matched no Markdown files: {pattern}"));
+    }
+
+    Ok(files)
+}
+
+fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Breaks the lines longer than `width` characters, after their last space
+/// when possible, ending the broken lines with `marker`.
+fn wrap_lines(text: &str, width: usize, marker: &str) -> String {
+    let room = width.saturating_sub(marker.chars().count()).max(1);
+    let mut wrapped = Vec::new();
+
+    for line in text.lines() {
+        let mut rest = line;
+        while rest.chars().count() > width {
+            let limit = rest.char_indices().nth(room).map_or(rest.len(), |(i, _)| i);
+            let split = rest[..limit]
+                .rfind(' ')
+                .filter(|&i| i > 0)
+                .map_or(limit, |i| i + 1);
+            wrapped.push(format!("{}{marker}", &rest[..split]));
+            rest = &rest[split..];
+        }
+        wrapped.push(rest.to_string());
+    }
+
+    wrapped.join("\n")
+}
+
+/// Whether the output of a `replace` preset matches the code of `block`, as
+/// decided by the `comparator` of the preset when it has one.
+fn output_matches(
+    stdout: &str,
+    preset_cfg: &PresetConfig,
+    block: &CodeBlock,
+) -> anyhow::Result<bool> {
+    match (&preset_cfg.output_mode, &preset_cfg.comparator) {
+        (OutputMode::Replace, Some(comparator)) => run_comparator(comparator, &block.code, stdout),
+        _ => Ok(stdout.trim() == block.code.trim()),
+    }
+}
+
 fn handle_preset_result(
-    output: &Output,
+    stdout: &str,
+    matches: bool,
     preset: &str,
     preset_cfg: &PresetConfig,
     block: &CodeBlock,
-    check_only: bool,
+    options: &RunOptions,
 ) -> anyhow::Result<Option<CodeBlock>> {
     match preset_cfg.output_mode {
         OutputMode::Check => Ok(None),
+        OutputMode::Artifact => {
+            reference_block(preset, preset_cfg, block, matches, options.check_only)
+        }
         OutputMode::Replace => {
-            let mismatch = String::from_utf8_lossy(&output.stdout).trim() != block.code.trim();
-
-            if !mismatch {
+            if matches {
                 debug!(
                     "Skipping code block, content matches output ({})",
                     block.path.display()
@@ -247,8 +1637,8 @@ fn handle_preset_result(
                 block.lang
             );
 
-            if check_only {
-                error!("{msg}");
+            if options.check_only {
+                log!(preset_cfg.severity.log_level(), "{msg}");
                 return Err(anyhow!(msg));
             }
 
@@ -257,21 +1647,7 @@ fn handle_preset_result(
                 block.path.display()
             );
 
-            let updated_code = std::iter::once(format!("```{}", block.headers))
-                .chain(
-                    String::from_utf8_lossy(&output.stdout)
-                        .trim()
-                        .lines()
-                        .map(|l| l.to_string()),
-                )
-                .chain(std::iter::once("```".to_string()))
-                .map(|l| {
-                    format!("{:indent$}{}", "", l, indent = block.indent)
-                        .trim_end()
-                        .to_string()
-                })
-                .collect::<Vec<String>>()
-                .join("\n");
+            let updated_code = block.fenced(stdout.trim());
 
             Ok(Some(block.with_updated_code(updated_code)))
         }
@@ -0,0 +1,254 @@
+use crate::events::{BlockEvent, BlockStatus};
+use crate::runner::RunState;
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// Shows a live table of the code blocks of a run until the user quits.
+/// Quitting before the end of the run cancels the remaining blocks.
+pub fn dashboard(
+    events: Receiver<BlockEvent>,
+    finished: &AtomicBool,
+    state: &RunState,
+) -> Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = Dashboard::default().run(&mut terminal, &events, finished, state);
+    ratatui::try_restore()?;
+
+    result
+}
+
+#[derive(Default)]
+struct Dashboard {
+    rows: Vec<BlockEvent>,
+    /// Row of each block, by path, start line and preset
+    index: HashMap<(PathBuf, usize, String), usize>,
+    table: TableState,
+    /// Whether the details of the selected row are displayed
+    details: bool,
+    scroll: u16,
+}
+
+impl Dashboard {
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        events: &Receiver<BlockEvent>,
+        finished: &AtomicBool,
+        state: &RunState,
+    ) -> Result<()> {
+        loop {
+            while let Ok(event) = events.try_recv() {
+                self.update(event);
+            }
+
+            let finished = finished.load(Ordering::Relaxed);
+            terminal.draw(|frame| self.draw(frame, finished))?;
+
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Esc if self.details => self.details = false,
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    if !finished {
+                        state.cancel();
+                    }
+                    return Ok(());
+                }
+                KeyCode::Enter => self.open_details(),
+                KeyCode::Char('f') => self.next_failure(),
+                KeyCode::Down | KeyCode::Char('j') if self.details => {
+                    self.scroll = self.scroll.saturating_add(1)
+                }
+                KeyCode::Up | KeyCode::Char('k') if self.details => {
+                    self.scroll = self.scroll.saturating_sub(1)
+                }
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+                KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+                KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+                _ => {}
+            }
+        }
+    }
+
+    fn update(&mut self, event: BlockEvent) {
+        let key = (event.path.clone(), event.start_line, event.preset.clone());
+        match self.index.get(&key) {
+            Some(&row) => self.rows[row] = event,
+            None => {
+                self.index.insert(key, self.rows.len());
+                self.rows.push(event);
+            }
+        }
+    }
+
+    fn open_details(&mut self) {
+        if self.table.selected().is_some() {
+            self.details = true;
+            self.scroll = 0;
+        }
+    }
+
+    /// Selects the next failed or mismatching block and opens its details.
+    fn next_failure(&mut self) {
+        let start = self.table.selected().map_or(0, |row| row + 1);
+        let failure = (0..self.rows.len())
+            .map(|offset| (start + offset) % self.rows.len())
+            .find(|&row| {
+                matches!(
+                    self.rows[row].status,
+                    BlockStatus::Failed | BlockStatus::Mismatch
+                )
+            });
+
+        if let Some(row) = failure {
+            self.table.select(Some(row));
+            self.open_details();
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, finished: bool) {
+        let [table_area, details_area, help_area] = if self.details {
+            Layout::vertical([
+                Constraint::Percentage(40),
+                Constraint::Fill(1),
+                Constraint::Length(1),
+            ])
+        } else {
+            Layout::vertical([
+                Constraint::Fill(1),
+                Constraint::Length(0),
+                Constraint::Length(1),
+            ])
+        }
+        .areas(frame.area());
+
+        let done = self
+            .rows
+            .iter()
+            .filter(|row| row.status != BlockStatus::Running)
+            .count();
+        let title = if finished {
+            format!(" mdcr: finished, {done} blocks ")
+        } else {
+            format!(" mdcr: running, {done}/{} blocks done ", self.rows.len())
+        };
+
+        let rows = self.rows.iter().map(|row| {
+            Row::new(vec![
+                Cell::from(row.path.display().to_string()),
+                Cell::from(format!("{}-{}", row.start_line, row.end_line)),
+                Cell::from(row.preset.clone()),
+                Cell::from(status_label(row.status)).style(status_style(row.status)),
+                Cell::from(
+                    row.duration
+                        .map(|duration| format!("{duration:.2?}"))
+                        .unwrap_or_default(),
+                ),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(3),
+                Constraint::Length(11),
+                Constraint::Fill(1),
+                Constraint::Length(10),
+                Constraint::Length(10),
+            ],
+        )
+        .header(
+            Row::new(["File", "Lines", "Preset", "Status", "Duration"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::new().borders(Borders::ALL).title(title))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, table_area, &mut self.table);
+
+        if self.details {
+            let selected = self.table.selected().and_then(|row| self.rows.get(row));
+            let title = selected
+                .map(|row| {
+                    format!(
+                        " {}:{}-{} ",
+                        row.path.display(),
+                        row.start_line,
+                        row.end_line
+                    )
+                })
+                .unwrap_or_default();
+            let text: Vec<Line> = match selected {
+                Some(row) if row.details.is_empty() => {
+                    vec![Line::from("No details for this block")]
+                }
+                Some(row) => row.details.lines().map(diff_line).collect(),
+                None => Vec::new(),
+            };
+            let details = Paragraph::new(text)
+                .block(Block::new().borders(Borders::ALL).title(title))
+                .scroll((self.scroll, 0));
+            frame.render_widget(details, details_area);
+        }
+
+        let help = if self.details {
+            "Esc: close  ↑/↓ PgUp/PgDn: scroll  f: next failure  q: quit"
+        } else {
+            "↑/↓: select  Enter: details  f: next failure  q: quit"
+        };
+        frame.render_widget(
+            Paragraph::new(help).style(Style::new().fg(Color::DarkGray)),
+            help_area,
+        );
+    }
+}
+
+fn status_label(status: BlockStatus) -> &'static str {
+    match status {
+        BlockStatus::Running => "running",
+        BlockStatus::Unchanged => "ok",
+        BlockStatus::Updated => "updated",
+        BlockStatus::Mismatch => "mismatch",
+        BlockStatus::Failed => "failed",
+        BlockStatus::Skipped => "skipped",
+    }
+}
+
+fn status_style(status: BlockStatus) -> Style {
+    match status {
+        BlockStatus::Running => Style::new().fg(Color::Yellow),
+        BlockStatus::Unchanged => Style::new().fg(Color::Green),
+        BlockStatus::Updated => Style::new().fg(Color::Cyan),
+        BlockStatus::Mismatch => Style::new().fg(Color::Red),
+        BlockStatus::Failed => Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+        BlockStatus::Skipped => Style::new().fg(Color::DarkGray),
+    }
+}
+
+fn diff_line(line: &str) -> Line<'_> {
+    let style = match line.chars().next() {
+        Some('+') => Style::new().fg(Color::Green),
+        Some('-') => Style::new().fg(Color::Red),
+        Some('@') => Style::new().fg(Color::Cyan),
+        _ => Style::new(),
+    };
+
+    Line::styled(line, style)
+}
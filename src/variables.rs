@@ -0,0 +1,106 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// Code with its `{{var}}` placeholders resolved, along with the substituted
+/// values so that the command output can be mapped back to the placeholders.
+pub struct Substituted {
+    pub code: String,
+    substitutions: Vec<(String, String)>,
+}
+
+impl Substituted {
+    /// Replaces the substituted values in `output` with their placeholders,
+    /// so that the document keeps showing `{{var}}` instead of real values.
+    ///
+    /// Only the values found in the code where they were substituted and
+    /// nowhere else are restored, and only where they are not part of a
+    /// longer word, so that the rest of the output is left as is.
+    pub fn restore(&self, output: &str) -> String {
+        let mut restorable: Vec<&(String, String)> = Vec::new();
+        for substitution @ (placeholder, value) in &self.substitutions {
+            if value.is_empty() || restorable.iter().any(|(_, v)| v == value) {
+                continue;
+            }
+
+            let same_value = self.substitutions.iter().filter(|(_, v)| v == value);
+            let unambiguous = same_value.clone().all(|(p, _)| p == placeholder);
+            if unambiguous && words(&self.code, value).count() == same_value.count() {
+                restorable.push(substitution);
+            }
+        }
+        // Longest values first, so that a value containing another one wins
+        restorable.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+
+        let mut restored = output.to_string();
+        for (placeholder, value) in restorable {
+            restored = replace_words(&restored, value, placeholder);
+        }
+        restored
+    }
+}
+
+/// Offsets of the occurrences of `value` in `text` which are not part of a
+/// longer word.
+fn words<'a>(text: &'a str, value: &'a str) -> impl Iterator<Item = usize> + 'a {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let starts_word = value.starts_with(is_word);
+    let ends_word = value.ends_with(is_word);
+
+    text.match_indices(value)
+        .map(|(start, _)| start)
+        .filter(move |&start| {
+            (!starts_word || !text[..start].ends_with(is_word))
+                && (!ends_word || !text[start + value.len()..].starts_with(is_word))
+        })
+}
+
+/// Replaces the occurrences of `value` in `text` which are not part of a
+/// longer word with `placeholder`.
+fn replace_words(text: &str, value: &str, placeholder: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for start in words(text, value) {
+        replaced.push_str(&text[last..start]);
+        replaced.push_str(placeholder);
+        last = start + value.len();
+    }
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+/// Resolves the `{{var}}` placeholders of `code` from the `variables` table,
+/// falling back to the environment.
+pub fn substitute(code: &str, variables: &HashMap<String, String>) -> Result<Substituted> {
+    let mut resolved = String::with_capacity(code.len());
+    let mut substitutions = Vec::new();
+    let mut rest = code;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let placeholder = &rest[start..start + 2 + end + 2];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+
+        resolved.push_str(&rest[..start]);
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            // Not a variable, e.g. a template expression of the documented language
+            resolved.push_str(placeholder);
+        } else {
+            let value = variables
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok())
+                .ok_or_else(|| anyhow!("Undefined variable `{name}`"))?;
+            resolved.push_str(&value);
+            substitutions.push((placeholder.to_string(), value));
+        }
+        rest = &rest[start + placeholder.len()..];
+    }
+    resolved.push_str(rest);
+
+    Ok(Substituted {
+        code: resolved,
+        substitutions,
+    })
+}
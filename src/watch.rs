@@ -0,0 +1,166 @@
+use crate::runner::{collect_markdown_files, FileFilter};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use notify::event::ModifyKind;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+/// Events arriving within this delay are handled together, as editors often
+/// save a file in several steps.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// What needs to be processed again after a change.
+pub enum Change {
+    Config,
+    /// Markdown files, as paths relative to the paths given on the command line
+    Files(Vec<PathBuf>),
+}
+
+/// Watches the paths given on the command line and the config files.
+pub struct Watcher {
+    // Stops watching on drop
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    /// Canonical path of each watched path, along with the path as given
+    roots: Vec<(PathBuf, PathBuf)>,
+    configs: Vec<PathBuf>,
+    /// Content of the files after they were last processed, so that the
+    /// files rewritten by mdcr itself are not processed again
+    contents: HashMap<PathBuf, String>,
+}
+
+impl Watcher {
+    pub fn new(paths: &[PathBuf], configs: &[PathBuf]) -> Result<Self> {
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+
+        let mut roots = Vec::new();
+        for path in paths {
+            let canonical = path
+                .canonicalize()
+                .with_context(|| format!("Cannot watch `{}`", path.display()))?;
+            watch(&mut watcher, &canonical)?;
+            roots.push((canonical, path.clone()));
+        }
+        let mut canonical_configs = Vec::new();
+        for config in configs {
+            let canonical = config
+                .canonicalize()
+                .with_context(|| format!("Cannot watch `{}`", config.display()))?;
+            watch(&mut watcher, &canonical)?;
+            canonical_configs.push(canonical);
+        }
+
+        info!("Watching {} paths for changes", paths.len());
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            roots,
+            configs: canonical_configs,
+            contents: HashMap::new(),
+        })
+    }
+
+    /// Blocks until the config or some Markdown files changed.
+    pub fn next_change(&mut self, filter: &FileFilter) -> Result<Change> {
+        loop {
+            let mut paths = Vec::new();
+            while paths.is_empty() {
+                let event = self.events.recv().context("The file watcher stopped")?;
+                paths.extend(changed_paths(event?));
+            }
+            // Other events, e.g. files being read, do not delay the processing
+            let mut deadline = Instant::now() + DEBOUNCE;
+            while let Ok(event) = self
+                .events
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                let changed = changed_paths(event?);
+                if !changed.is_empty() {
+                    deadline = Instant::now() + DEBOUNCE;
+                    paths.extend(changed);
+                }
+            }
+
+            if paths.iter().any(|path| self.configs.contains(path)) {
+                debug!("The config changed");
+                return Ok(Change::Config);
+            }
+
+            let mut files = Vec::new();
+            for path in paths {
+                let Some(file) = self.relative_file(&path, filter) else {
+                    continue;
+                };
+                // Rewrites by mdcr itself leave the file as it was processed
+                let content = fs::read_to_string(&path).ok();
+                if content.is_some() && content.as_ref() == self.contents.get(&file) {
+                    continue;
+                }
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+
+            if !files.is_empty() {
+                return Ok(Change::Files(files));
+            }
+        }
+    }
+
+    /// Records the content of the Markdown files of `paths` once processed.
+    pub fn processed(&mut self, paths: &[PathBuf], filter: &FileFilter) {
+        for path in paths {
+            for file in collect_markdown_files(path, filter).unwrap_or_default() {
+                if let Ok(content) = fs::read_to_string(&file) {
+                    self.contents.insert(file, content);
+                }
+            }
+        }
+    }
+
+    /// The changed file as found when processing the watched paths, if it is
+    /// one of their Markdown files.
+    fn relative_file(&self, path: &Path, filter: &FileFilter) -> Option<PathBuf> {
+        self.roots.iter().find_map(|(canonical, given)| {
+            if path == canonical {
+                return Some(given.clone());
+            }
+
+            let relative = path.strip_prefix(canonical).ok()?;
+            let file = given.join(relative);
+            let excluded = relative
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| filter.is_excluded(given, &given.join(ancestor)));
+
+            (filter.is_markdown(&file) && !excluded && path.is_file()).then_some(file)
+        })
+    }
+}
+
+/// Watches directories recursively, and files through their parent directory
+/// so that editors replacing the file on save are supported.
+fn watch(watcher: &mut RecommendedWatcher, path: &Path) -> Result<()> {
+    let (path, mode) = match path.parent() {
+        Some(parent) if !path.is_dir() => (parent, RecursiveMode::NonRecursive),
+        _ => (path, RecursiveMode::Recursive),
+    };
+
+    watcher
+        .watch(path, mode)
+        .with_context(|| format!("Cannot watch `{}`", path.display()))
+}
+
+fn changed_paths(event: Event) -> Vec<PathBuf> {
+    match event.kind {
+        EventKind::Modify(ModifyKind::Metadata(_)) => Vec::new(),
+        EventKind::Create(_) | EventKind::Modify(_) => event.paths,
+        _ => Vec::new(),
+    }
+}
@@ -7,7 +7,24 @@ mod helpers {
         pub cfg_path: PathBuf,
     }
 
+    /// Preset replacing the output of the `sh` blocks with `hello`.
+    const ECHO_HELLO: &str = r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#;
+
     impl TestEnv {
+        /// A `sh` block running `echo outdated`, with `config`.
+        pub fn outdated(config: &str) -> Self {
+            Self::new("echo outdated", "sh", config)
+        }
+
+        /// An `outdated` block, that the `ECHO_HELLO` preset replaces.
+        pub fn stale() -> Self {
+            Self::outdated(ECHO_HELLO)
+        }
+
         pub fn new(markdown: &str, lang: &str, config_json: &str) -> Self {
             let markdown = format!(
                 r#"```{lang}
@@ -44,11 +61,43 @@ mod helpers {
                 .output()
                 .unwrap()
         }
+
+        pub fn run_in(&self, dir: &std::path::Path, args: &[&str]) -> std::process::Output {
+            std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+                .args(args)
+                .current_dir(dir)
+                .output()
+                .unwrap()
+        }
+
+        pub fn run_with_stdin(&self, args: &[&str], input: &str) -> std::process::Output {
+            use std::io::Write;
+            use std::process::Stdio;
+
+            let mut full_args = vec!["run", "--quiet", "--"];
+            full_args.extend_from_slice(args);
+            let mut child = std::process::Command::new("cargo")
+                .args(full_args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            child.wait_with_output().unwrap()
+        }
     }
 }
 
 use helpers::TestEnv;
 
+// Running presets -------------------------------------------------------------
+
 #[test]
 fn test_rewrites_code_block() {
     let env = TestEnv::new(
@@ -523,3 +572,4154 @@ echo leg
     let updated = std::fs::read_to_string(&env.md_path).unwrap();
     assert!(updated.contains("worked"));
 }
+
+// Paths -----------------------------------------------------------------------
+
+#[test]
+fn test_current_directory_is_the_default_path() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+
+    let output = env.run_in(dir, &["--config", env.cfg_path.to_str().unwrap()]);
+
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(updated.contains("hello"));
+}
+
+#[test]
+fn test_without_arguments_the_current_directory_is_processed() {
+    let env = TestEnv::from_raw_markdown("```sh\necho hello\n```\n", "");
+    let dir = env.md_path.parent().unwrap();
+    std::fs::write(
+        dir.join("mdcr.toml"),
+        "[presets.shell]\nlanguage = \"sh\"\ncommand = [\"sh\"]\n",
+    )
+    .unwrap();
+
+    let output = env.run_in(dir, &[]);
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+}
+
+#[test]
+fn test_dash_path_reads_markdown_from_stdin() {
+    let env = TestEnv::stale();
+
+    let output = env.run_with_stdin(
+        &["-", "--config", env.cfg_path.to_str().unwrap()],
+        "```sh\necho outdated\n```\n",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "```sh\nhello\n```\n"
+    );
+
+    let output = env.run_with_stdin(
+        &["-", "--check", "--config", env.cfg_path.to_str().unwrap()],
+        "```sh\necho outdated\n```\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    // The report would be mixed with the document
+    let args = ["-", "--format", "checkstyle", "--config"];
+    let config = env.cfg_path.to_str().unwrap();
+    let output = env.run_with_stdin(&[&args[..], &[config]].concat(), "");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+
+    let output = env.run_with_stdin(
+        &[&args[..], &[config, "--check"]].concat(),
+        "```sh\necho outdated\n```\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("<?xml"));
+}
+
+#[test]
+fn test_glob_patterns_are_expanded() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    fs::create_dir_all(dir.path().join("docs/guide")).unwrap();
+    fs::write(dir.path().join("docs/guide/intro.md"), "```sh\nold\n```\n").unwrap();
+    fs::write(dir.path().join("docs/notes.txt"), "```sh\nold\n```\n").unwrap();
+    fs::write(dir.path().join("README.md"), "```sh\nold\n```\n").unwrap();
+    fs::write(
+        &config_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args(["docs/**/*.md", "--config", config_path.to_str().unwrap()])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let read = |path: &str| fs::read_to_string(dir.path().join(path)).unwrap();
+    assert_eq!(read("docs/guide/intro.md"), "```sh\nhello\n```\n");
+    assert_eq!(read("docs/notes.txt"), "```sh\nold\n```\n");
+    assert_eq!(read("README.md"), "```sh\nold\n```\n");
+}
+
+#[test]
+fn test_exclude_patterns_skip_directories() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    for file in [
+        "node_modules/pkg/README.md",
+        "docs/vendor/lib.md",
+        "docs/guide.md",
+    ] {
+        let path = dir.path().join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "```sh\nold\n```\n").unwrap();
+    }
+    fs::write(
+        &config_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            dir.path().to_str().unwrap(),
+            "--exclude",
+            "node_modules/",
+            "--exclude",
+            "docs/vendor",
+            "--config",
+            config_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let read = |path: &str| fs::read_to_string(dir.path().join(path)).unwrap();
+    assert_eq!(read("docs/guide.md"), "```sh\nhello\n```\n");
+    assert_eq!(read("docs/vendor/lib.md"), "```sh\nold\n```\n");
+    assert_eq!(read("node_modules/pkg/README.md"), "```sh\nold\n```\n");
+}
+
+#[test]
+fn test_extensions_are_configurable() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    for name in ["a.md", "b.markdown", "c.mdx"] {
+        fs::write(dir.path().join(name), "```sh\nold\n```\n").unwrap();
+    }
+    fs::write(
+        &config_path,
+        r#"
+        [settings]
+        extensions = ["markdown"]
+
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    )
+    .unwrap();
+
+    let run = |extra: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+            .arg(dir.path())
+            .args(extra)
+            .args(["--config", config_path.to_str().unwrap()])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    };
+    let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+
+    run(&[]);
+    assert_eq!(read("a.md"), "```sh\nold\n```\n");
+    assert_eq!(read("b.markdown"), "```sh\nhello\n```\n");
+
+    run(&["--ext", "md,.mdx"]);
+    assert_eq!(read("a.md"), "```sh\nhello\n```\n");
+    assert_eq!(read("c.mdx"), "```sh\nhello\n```\n");
+}
+
+#[test]
+fn test_unreadable_paths_do_not_prevent_processing_the_others() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+
+    let output = env.run_in(
+        dir,
+        &[
+            "test.md",
+            "missing.md",
+            "--schedule",
+            "preset",
+            "--format",
+            "checkstyle",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("<file name=\"missing.md\">"));
+    assert!(stdout.contains("source=\"mdcr.file\""));
+}
+
+#[test]
+fn test_check_and_write_paths_mix_modes() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let generated = dir.join("generated");
+    std::fs::create_dir(&generated).unwrap();
+    std::fs::copy(&env.md_path, generated.join("api.md")).unwrap();
+
+    let output = env.run_in(
+        dir,
+        &[
+            "--check-paths",
+            "test.md",
+            "--write-paths",
+            "./generated",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\necho outdated\n```\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(generated.join("api.md")).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+}
+
+#[test]
+fn test_check_and_write_paths_compare_canonical_paths() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let generated = dir.join("generated");
+    std::fs::create_dir(&generated).unwrap();
+    std::fs::copy(&env.md_path, generated.join("api.md")).unwrap();
+
+    // `./generated/api.md` is found from `.`, but written as inside the
+    // absolute `--write-paths`
+    let output = env.run_in(
+        dir,
+        &[
+            "--check-paths",
+            ".",
+            "--write-paths",
+            generated.to_str().unwrap(),
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\necho outdated\n```\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(generated.join("api.md")).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+}
+
+#[test]
+fn test_check_at_git_revision() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=mdcr", "-c", "user.email=mdcr@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+
+    git(&["init", "--quiet"]);
+    git(&["add", "test.md"]);
+    git(&["commit", "--quiet", "-m", "stale docs"]);
+    git(&["tag", "v1.2"]);
+    std::fs::write(&env.md_path, "```sh\nhello\n```\n").unwrap();
+
+    let cfg = env.cfg_path.to_str().unwrap();
+    let output = env.run_in(dir, &["check", "test.md", "--config", cfg]);
+    assert!(output.status.success());
+
+    let output = env.run_in(dir, &["check", "--at", "v1.2", ".", "--config", cfg]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Code block mismatch detected in `v1.2:test.md"));
+
+    let current = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(current, "```sh\nhello\n```\n");
+
+    let args = ["check", "--at", "v1.2", "--schedule", "preset", "."];
+    let output = env.run_in(dir, &[&args[..], &["--config", cfg]].concat());
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be used with"));
+}
+
+#[test]
+fn test_staged_processes_and_restages_staged_files_only() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let unstaged = dir.join("unstaged.md");
+    std::fs::write(&unstaged, "```sh\necho outdated\n```\n").unwrap();
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    git(&["init", "--quiet"]);
+    git(&["add", "test.md"]);
+
+    let cfg = env.cfg_path.to_str().unwrap();
+    let output = env.run_in(dir, &["--staged", "--config", cfg]);
+    assert!(output.status.success());
+
+    assert_eq!(git(&["show", ":test.md"]), "```sh\nhello\n```\n");
+    assert_eq!(
+        std::fs::read_to_string(&unstaged).unwrap(),
+        "```sh\necho outdated\n```\n"
+    );
+
+    // Unstaged changes of the rewritten files stay unstaged
+    std::fs::write(
+        &env.md_path,
+        "# Staged\n\n```sh\necho outdated\n```\n\nOne.\n\nTwo.\n\nStaged.\n",
+    )
+    .unwrap();
+    git(&["add", "test.md"]);
+    std::fs::write(
+        &env.md_path,
+        "# Staged\n\n```sh\necho outdated\n```\n\nOne.\n\nTwo.\n\nUnstaged.\n",
+    )
+    .unwrap();
+    let output = env.run_in(dir, &["--staged", "--config", cfg]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        git(&["show", ":test.md"]),
+        "# Staged\n\n```sh\nhello\n```\n\nOne.\n\nTwo.\n\nStaged.\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "# Staged\n\n```sh\nhello\n```\n\nOne.\n\nTwo.\n\nUnstaged.\n"
+    );
+}
+
+#[test]
+fn test_changed_since_processes_changed_files_only() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let unchanged = dir.join("unchanged.md");
+    std::fs::write(&unchanged, "```sh\necho outdated\n```\n").unwrap();
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=mdcr", "-c", "user.email=mdcr@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    };
+
+    git(&["init", "--quiet"]);
+    git(&["add", "test.md", "unchanged.md"]);
+    git(&["commit", "--quiet", "-m", "Initial commit"]);
+    git(&["branch", "upstream"]);
+    git(&["checkout", "--quiet", "-b", "feature"]);
+    let committed = dir.join("committed.md");
+    std::fs::write(&committed, "```sh\necho outdated\n```\n").unwrap();
+    git(&["add", "committed.md"]);
+    git(&["commit", "--quiet", "-m", "Add a file"]);
+    // Changes of the revision since the merge base are not processed
+    git(&["checkout", "--quiet", "upstream"]);
+    std::fs::write(&unchanged, "# Upstream\n\n```sh\necho outdated\n```\n").unwrap();
+    git(&["commit", "--quiet", "-am", "Change upstream"]);
+    git(&["checkout", "--quiet", "feature"]);
+    std::fs::write(&env.md_path, "# Title\n\n```sh\necho outdated\n```\n").unwrap();
+    let untracked = dir.join("untracked.md");
+    std::fs::write(&untracked, "```sh\necho outdated\n```\n").unwrap();
+
+    let cfg = env.cfg_path.to_str().unwrap();
+    let output = env.run_in(dir, &["--changed-since", "upstream", "--config", cfg]);
+    assert!(output.status.success());
+
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "# Title\n\n```sh\nhello\n```\n"
+    );
+    for updated in [&committed, &untracked] {
+        assert_eq!(
+            std::fs::read_to_string(updated).unwrap(),
+            "```sh\nhello\n```\n"
+        );
+    }
+    assert_eq!(
+        std::fs::read_to_string(&unchanged).unwrap(),
+        "```sh\necho outdated\n```\n"
+    );
+}
+
+// Selecting blocks ------------------------------------------------------------
+
+#[test]
+fn test_preset_option_runs_only_the_named_presets() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.slow]
+        language = "sh"
+        command = ["echo", "hello"]
+
+        [presets.other]
+        language = "sh"
+        command = ["false"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--preset",
+        "slow",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--preset",
+        "missing",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown preset `missing`"));
+}
+
+#[test]
+fn test_language_option_only_processes_those_blocks() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\nold\n```\n\n```python\nold\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "new"]
+
+        [presets.python]
+        language = "python"
+        command = ["echo", "new"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--language",
+        "sh",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nnew\n```\n\n```python\nold\n```\n"
+    );
+}
+
+#[test]
+fn test_lines_option_only_processes_overlapping_blocks() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\nold\n```\n\n```sh\nold\n```\n\n```sh\nold\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "new"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--lines",
+        "6:9",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nold\n```\n\n```sh\nnew\n```\n\n```sh\nnew\n```\n"
+    );
+}
+
+#[test]
+fn test_only_kind_skips_the_other_presets() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.format]
+        language = "sh"
+        command = ["echo", "hello"]
+        kind = "formatter"
+
+        [presets.execute]
+        language = "sh"
+        command = ["false"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--only-kind",
+        "formatter",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+}
+
+#[test]
+fn test_pandoc_attribute_blocks() {
+    let env = TestEnv::from_raw_markdown(
+        "```{.sh .numberLines #ex1}\necho outdated\n```\n\n```{.sh #ex2}\necho outdated\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        classes = ["numberLines"]
+        command = ["echo", "hello"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(
+        updated,
+        "```{.sh .numberLines #ex1}\nhello\n```\n\n```{.sh #ex2}\necho outdated\n```\n"
+    );
+}
+
+#[test]
+fn test_block_ids_survive_edits_around_blocks() {
+    let markdown =
+        "```sh\necho a\n```\n\n```sh\necho a\n```\n\n```sh name=greeting\necho hi\n```\n";
+    let env = TestEnv::from_raw_markdown(markdown, "");
+    let block_ids = |env: &TestEnv| {
+        let output = env.run(&[
+            "list-blocks",
+            "--format",
+            "json",
+            env.md_path.to_str().unwrap(),
+        ]);
+        let blocks: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        blocks
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|block| block["block_id"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let before = block_ids(&env);
+    assert!(before[0].starts_with("sha-"));
+    assert_eq!(before[1], format!("{}-2", before[0]));
+    assert_eq!(before[2], "greeting");
+
+    std::fs::write(&env.md_path, format!("# Title\n\nSome text.\n\n{markdown}")).unwrap();
+    assert_eq!(block_ids(&env), before);
+}
+
+#[test]
+fn test_ignore_add_exempts_a_block_from_a_preset() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho ignored\n```\n\n```sh\nhello\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+    let dir = env.md_path.parent().unwrap();
+    let config = env.cfg_path.to_str().unwrap();
+
+    let output = env.run_in(dir, &["test.md", "--check", "--config", config]);
+    assert_eq!(output.status.code(), Some(1));
+
+    // Entries are relative to the config, wherever they are added from
+    let parent = dir.parent().unwrap();
+    let file = std::path::Path::new(dir.file_name().unwrap()).join("test.md");
+    let location = format!("{}:2", file.display());
+    let output = env.run_in(
+        parent,
+        &[
+            "ignore", "add", &location, "--config", config, "--preset", "shell",
+        ],
+    );
+    assert!(output.status.success());
+    let config_content = std::fs::read_to_string(&env.cfg_path).unwrap();
+    assert!(config_content.contains("[[ignore]]\nfile = \"test.md\""));
+    assert!(config_content.contains("presets = [\"shell\"]"));
+
+    let output = env.run_in(dir, &["test.md", "--check", "--config", config]);
+    assert!(output.status.success());
+    let args = [file.to_str().unwrap(), "--check", "--config", config];
+    let output = env.run_in(parent, &args);
+    assert!(output.status.success());
+
+    // Changing the content of the block lifts the exemption
+    std::fs::write(
+        &env.md_path,
+        "```sh\necho changed\n```\n\n```sh\nhello\n```\n",
+    )
+    .unwrap();
+    let output = env.run_in(dir, &["test.md", "--check", "--config", config]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+// Matching presets ------------------------------------------------------------
+
+#[test]
+fn test_language_accepts_a_list() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho one\n```\n\n```bash\necho two\n```\n\n```zsh\necho three\n```\n",
+        r#"
+        [presets.shell]
+        language = ["sh", "bash"]
+        command = ["sh"]
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\none\n```\n\n```bash\ntwo\n```\n\n```zsh\necho three\n```\n"
+    );
+}
+
+#[test]
+fn test_language_pattern_matches_families_of_languages() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho one\n```\n\n```bash\necho two\n```\n\n```shell\necho three\n```\n",
+        r#"
+        [presets.shell]
+        language_pattern = "^(ba|z)?sh$"
+        command = ["sh"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let output = env.run(&["--config", config, env.md_path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\none\n```\n\n```bash\ntwo\n```\n\n```shell\necho three\n```\n"
+    );
+
+    std::fs::write(&env.cfg_path, "[presets.shell]\ncommand = [\"sh\"]\n").unwrap();
+    let output = env.run(&["--config", config, env.md_path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Preset `shell` has no `language` nor `language_pattern`"));
+}
+
+#[test]
+fn test_aliases_map_block_languages() {
+    let env = TestEnv::from_raw_markdown(
+        "```shell\necho one\n```\n\n```console\necho two\n```\n",
+        r#"
+        [aliases]
+        shell = "sh"
+
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```shell\none\n```\n\n```console\necho two\n```\n"
+    );
+}
+
+#[test]
+fn test_fallback_preset_runs_on_unclaimed_blocks() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho one\n```\n\n```text\nsome text\n```\n\n```\nno language\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+
+        [presets.upper]
+        language = "*"
+        command = ["tr", "a-z", "A-Z"]
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\none\n```\n\n```text\nSOME TEXT\n```\n\n```\nNO LANGUAGE\n```\n"
+    );
+}
+
+#[test]
+fn test_fallback_preset_leaves_the_blocks_of_the_presets_left_out() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho one\n```\n\n```text\nsome text\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        enabled = false
+
+        [presets.upper]
+        language = "*"
+        command = ["tr", "a-z", "A-Z"]
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\necho one\n```\n\n```text\nSOME TEXT\n```\n"
+    );
+}
+
+#[test]
+fn test_skip_languages_are_never_processed() {
+    let markdown = "```mermaid\ngraph TD\n```\n\n```text\nplain\n```\n";
+    let env = TestEnv::from_raw_markdown(
+        markdown,
+        r#"
+        [settings]
+        skip_languages = ["mermaid", "text"]
+
+        [presets.everything]
+        language = "*"
+        command = ["echo", "replaced"]
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        "--error-on-unmatched",
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(&env.md_path).unwrap(), markdown);
+}
+
+#[test]
+fn test_files_restrict_presets_to_matching_files() {
+    let env = TestEnv::from_raw_markdown("", "");
+    let dir = env.md_path.parent().unwrap();
+    let api = dir.join("docs/api");
+    let nested = dir.join("site/docs/api");
+    std::fs::create_dir_all(&api).unwrap();
+    std::fs::create_dir_all(&nested).unwrap();
+    let block = "```sh\necho run\n```\n";
+    for file in ["index.md", "CHANGELOG.md"] {
+        std::fs::write(api.join(file), block).unwrap();
+        std::fs::write(dir.join(file), block).unwrap();
+    }
+    std::fs::write(nested.join("index.md"), block).unwrap();
+    std::fs::write(
+        &env.cfg_path,
+        r#"
+        [presets.api]
+        language = "sh"
+        command = ["sh"]
+        files = ["docs/api/**"]
+        exclude_files = ["CHANGELOG.md"]
+        "#,
+    )
+    .unwrap();
+
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        dir.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let read = |path: std::path::PathBuf| std::fs::read_to_string(path).unwrap();
+    assert_eq!(read(api.join("index.md")), "```sh\nrun\n```\n");
+    assert_eq!(read(api.join("CHANGELOG.md")), block);
+    assert_eq!(read(dir.join("index.md")), block);
+    assert_eq!(read(nested.join("index.md")), block);
+}
+
+#[test]
+fn test_only_if_skips_presets_whose_probe_fails() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho available\n```\n\n```text\nunchanged\n```\n",
+        r#"
+        [presets.available]
+        language = "sh"
+        command = ["sh"]
+        only_if = ["true"]
+
+        [presets.unavailable]
+        language = "text"
+        command = ["echo", "replaced"]
+        only_if = ["sh", "-c", "exit 1"]
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\navailable\n```\n\n```text\nunchanged\n```\n"
+    );
+
+    // Other subcommands skip them too
+    let markdown = "```text\nunchanged\n```\n";
+    let config = env.cfg_path.to_str().unwrap();
+    let output = env.run_with_stdin(&["apply", "--stdin", "--config", config], markdown);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), markdown);
+}
+
+#[test]
+fn test_disabled_presets_only_run_when_enabled() {
+    let markdown = "```sh\necho nightly\n```\n";
+    let config = r#"
+        [presets.nightly]
+        language = "sh"
+        command = ["sh"]
+        enabled = false
+        "#;
+
+    let env = TestEnv::from_raw_markdown(markdown, config);
+    let args = [
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ];
+    assert!(env.run(&args).status.success());
+    assert_eq!(std::fs::read_to_string(&env.md_path).unwrap(), markdown);
+
+    let output = env.run(&[&args[..], &["--enable-preset", "nightly"]].concat());
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nnightly\n```\n"
+    );
+
+    // Other subcommands leave the disabled presets out too
+    std::fs::write(&env.md_path, markdown).unwrap();
+    let config = env.cfg_path.to_str().unwrap();
+    let output = env.run_with_stdin(&["apply", "--stdin", "--config", config], markdown);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), markdown);
+
+    let output = env.run(&["review", env.md_path.to_str().unwrap(), "--config", config]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "No mismatching blocks\n"
+    );
+}
+
+#[test]
+fn test_allow_missing_tools_skips_the_presets_of_missing_programs() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["cat"]
+
+        [presets.missing]
+        language = "sh"
+        command = ["mdcr-missing-tool"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&["--config", config, "--check", markdown]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = env.run(&[
+        "--config",
+        config,
+        "--check",
+        "--allow-missing-tools",
+        markdown,
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(
+        "Skipping 1 presets whose tool is not installed: `missing` (`mdcr-missing-tool`)"
+    ));
+}
+
+#[test]
+fn test_error_on_unmatched_fails_on_blocks_without_preset() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho ok\n```\n\n```python\nprint()\n```\n\n```\nplain text\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["cat"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&["--config", config, "--check", markdown]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = env.run(&[
+        "--config",
+        config,
+        "--check",
+        "--error-on-unmatched",
+        markdown,
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("test.md:5-7` has no preset for language `python`"));
+    assert_eq!(stderr.matches("has no preset").count(), 1);
+}
+
+#[test]
+fn test_error_on_unmatched_ignores_the_presets_left_out() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho ok\n```\n\n```text\nplain text\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["cat"]
+
+        [presets.text]
+        language = "text"
+        command = ["cat"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&[
+        "--config",
+        config,
+        "--check",
+        "--preset",
+        "text",
+        "--error-on-unmatched",
+        markdown,
+    ]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// Commands --------------------------------------------------------------------
+
+#[test]
+fn test_string_commands_run_through_the_shell() {
+    let env = TestEnv::new(
+        "echo hello",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = "cat | tr a-z A-Z"
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(&env.md_path)
+        .unwrap()
+        .contains("ECHO HELLO"));
+}
+
+#[test]
+fn test_shell_commands_quote_the_placeholders() {
+    let env = TestEnv::new(
+        "hi; echo INJECTED",
+        "text",
+        r#"
+        [presets.shell]
+        language = "text"
+        command = "printf '%s\\n' {code}"
+        input_mode = "arg"
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```text\nhi; echo INJECTED\n```\n"
+    );
+}
+
+#[test]
+fn test_shell_array_commands_quote_their_arguments() {
+    let env = TestEnv::new(
+        "hello",
+        "text",
+        r#"
+        [presets.shell]
+        language = "text"
+        command = ["printf", "%s %s\\n", "a b; echo INJECTED", "x{lang}"]
+        shell = true
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```text\na b; echo INJECTED xtext\n```\n"
+    );
+}
+
+#[test]
+fn test_preset_shell_picks_the_interpreter() {
+    let env = TestEnv::new(
+        "echo hello",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = "echo \"${0##*/} $(cat)\""
+        shell = "bash"
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(&env.md_path)
+        .unwrap()
+        .contains("bash echo hello"));
+}
+
+#[test]
+fn test_arg_input_mode_falls_back_when_too_long() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    let small = dir.path().join("small.md");
+    let large = dir.path().join("large.md");
+    fs::write(&small, "```txt\nsmall {lang}\n```\n").unwrap();
+    fs::write(&large, format!("```txt\n{}\n```\n", "x".repeat(300_000))).unwrap();
+    fs::write(
+        &config_path,
+        r#"
+        [presets.printf]
+        language = "txt"
+        command = ["printf", "%s", "{code}"]
+        input_mode = "arg"
+        fallback_command = ["cat"]
+        "#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            small.to_str().unwrap(),
+            large.to_str().unwrap(),
+            "--check",
+            "--config",
+            config_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr
+            .matches("falling back to the stdin input mode")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_arg_input_mode_passes_a_file_in_place_of_the_code() {
+    let env = TestEnv::from_raw_markdown(
+        &format!(
+            "```txt\nsmall\n```\n\n```txt\n{}\n```\n",
+            "x".repeat(300_000)
+        ),
+        r#"
+        [presets.print]
+        language = "txt"
+        command = ["sh", "-c", "if [ -f \"$0\" ]; then cat \"$0\"; else printf '%s' \"$0\"; fi", "{code}"]
+        input_mode = "arg"
+        "#,
+    );
+
+    let output = env.run(&[
+        "--check",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert_eq!(
+        stderr
+            .matches("falling back to the file input mode")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_repo_root_and_relpath_placeholders() {
+    let env = TestEnv::new(
+        "outdated",
+        "text",
+        r#"
+        [presets.location]
+        language = "text"
+        command = ["echo", "{repo_root}|{relpath}"]
+        "#,
+    );
+    let root = env.md_path.parent().unwrap().canonicalize().unwrap();
+    std::fs::create_dir(root.join(".git")).unwrap();
+    std::fs::create_dir(root.join("docs")).unwrap();
+    let doc = root.join("docs").join("guide.md");
+    std::fs::write(&doc, "```text\noutdated\n```\n").unwrap();
+
+    let output = env.run(&[
+        doc.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&doc).unwrap(),
+        format!("```text\n{}|docs/guide.md\n```\n", root.display())
+    );
+}
+
+#[test]
+fn test_variables_are_substituted_before_execution() {
+    let env = TestEnv::new(
+        "echo   {{REGION}}",
+        "sh",
+        r#"
+        [variables]
+        REGION = "eu-west-1"
+
+        [presets.squeeze]
+        language = "sh"
+        # Fails unless the variable was substituted, then squeezes the spaces
+        command = ["sh", "-c", "input=$(cat); case $input in *eu-west-1*) echo \"$input\" | tr -s ' ';; *) exit 1;; esac"]
+        substitute_variables = true
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(updated.contains("echo {{REGION}}"));
+}
+
+#[test]
+fn test_variables_are_restored_only_where_they_were_substituted() {
+    let env = TestEnv::new(
+        "echo   {{VERSION}} 10 {{NAME}}",
+        "sh",
+        r#"
+        [variables]
+        VERSION = "1"
+        NAME = "echo"
+
+        [presets.squeeze]
+        language = "sh"
+        command = ["tr", "-s", " "]
+        substitute_variables = true
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(updated.contains("echo {{VERSION}} 10 echo\n"), "{updated}");
+}
+
+#[test]
+fn test_fixtures_are_copied_into_the_working_directory() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("docs/data")).unwrap();
+    fs::write(dir.path().join("docs/data/sample.csv"), "a,b\n1,2\n").unwrap();
+    fs::write(
+        dir.path().join("doc.md"),
+        "```sh\nwc -l < sample.csv\n```\n",
+    )
+    .unwrap();
+    // Fixtures are relative to the config declaring them, not to the
+    // directory mdcr runs in
+    fs::write(
+        dir.path().join("docs/config.toml"),
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "sh | tr -d ' '"]
+        fixtures = ["data/sample.csv"]
+        "#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args(["doc.md", "--stdout", "--config", "docs/config.toml"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "```sh\n2\n```\n");
+}
+
+#[test]
+fn test_tmpdir_is_unique_per_execution() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    for index in 0..8 {
+        fs::write(
+            dir.path().join(format!("doc{index}.md")),
+            format!("```sh\nblock {index}\n```\n"),
+        )
+        .unwrap();
+    }
+    fs::write(
+        &config_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "test -z \"$(ls {tmpdir})\" && cat > {tmpdir}/out && sleep 0.2 && cat {tmpdir}/out"]
+        "#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            dir.path().to_str().unwrap(),
+            "--check",
+            "--config",
+            config_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_preset_env_is_set_on_the_command() {
+    let env = TestEnv::new(
+        "echo $GREETING",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        env = { GREETING = "hello from env" }
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(&env.md_path)
+        .unwrap()
+        .contains("hello from env"));
+}
+
+#[test]
+fn test_clear_env_only_passes_the_allowed_variables() {
+    let env = TestEnv::new(
+        "echo \"[$MDCR_TEST_SECRET] [$MDCR_TEST_KEPT]\"",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        clear_env = true
+        pass_env = ["PATH", "MDCR_TEST_KEPT"]
+        "#,
+    );
+    let output = std::process::Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+            env.md_path.to_str().unwrap(),
+        ])
+        .env("MDCR_TEST_SECRET", "secret")
+        .env("MDCR_TEST_KEPT", "kept")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(&env.md_path)
+        .unwrap()
+        .contains("[] [kept]"));
+}
+
+#[test]
+fn test_preset_timeout_kills_the_command() {
+    let env = TestEnv::new(
+        "sleep 10",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        timeout = "200ms"
+        "#,
+    );
+    let started = std::time::Instant::now();
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("timed out after 200ms"));
+}
+
+#[test]
+fn test_preset_timeout_kills_the_children_of_the_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("marker");
+    let env = TestEnv::new(
+        &format!("(sleep 1; touch {}) &\nwait", marker.display()),
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        timeout = "200ms"
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    assert!(!marker.exists());
+}
+
+#[test]
+fn test_global_timeout_applies_to_presets_without_one() {
+    let env = TestEnv::new(
+        "sleep 10",
+        "sh",
+        r#"
+        [settings]
+        timeout = "200ms"
+
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&["--config", config, markdown]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("timed out after 200ms"));
+
+    let output = env.run(&["--config", config, "--timeout", "300ms", markdown]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("timed out after 300ms"));
+}
+
+#[test]
+fn test_before_and_after_hooks_run_once_per_run() {
+    let env = TestEnv::from_raw_markdown("", "");
+    let dir = env.md_path.parent().unwrap();
+    let block = format!("```sh\ncat {}/setup.log\n```\n", dir.display());
+    std::fs::write(&env.md_path, format!("{block}\n{block}")).unwrap();
+    std::fs::write(
+        &env.cfg_path,
+        format!(
+            r#"
+            [presets.shell]
+            language = "sh"
+            command = ["sh"]
+            before = ["sh", "-c", "echo ready >> {dir}/setup.log"]
+            after = ["sh", "-c", "rm {dir}/setup.log && touch {dir}/cleaned"]
+            "#,
+            dir = dir.display()
+        ),
+    )
+    .unwrap();
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    // Both blocks saw the setup of a single `before` run
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nready\n```\n\n```sh\nready\n```\n"
+    );
+    assert!(!dir.join("setup.log").exists());
+    assert!(dir.join("cleaned").exists());
+}
+
+#[test]
+fn test_after_hooks_run_from_every_subcommand() {
+    let env = TestEnv::new("echo outdated", "sh", "");
+    let dir = env.md_path.parent().unwrap();
+    std::fs::write(
+        &env.cfg_path,
+        format!(
+            r#"
+            [presets.shell]
+            language = "sh"
+            command = ["echo", "hello"]
+            after = ["touch", "{}/cleaned"]
+            "#,
+            dir.display()
+        ),
+    )
+    .unwrap();
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&["impact", "--preset", "shell", "--config", config, markdown]);
+    assert!(output.status.success());
+    assert!(dir.join("cleaned").exists());
+
+    std::fs::remove_file(dir.join("cleaned")).unwrap();
+    let output = env.run_with_stdin(
+        &["apply", "--stdin", "--config", config],
+        "```sh\necho outdated\n```\n",
+    );
+    assert!(output.status.success());
+    assert!(dir.join("cleaned").exists());
+
+    std::fs::remove_file(dir.join("cleaned")).unwrap();
+    let output = env.run_with_stdin(&["review", "--config", config, markdown], "q");
+    assert!(output.status.success());
+    assert!(dir.join("cleaned").exists());
+}
+
+#[test]
+fn test_debug_env_diffs_against_last_successful_run() {
+    let env = TestEnv::new(
+        "hello",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "echo ${MDCR_TEST_GREETING:-hello}"]
+        "#,
+    );
+    let cache_dir = env.md_path.parent().unwrap().join("cache");
+    let args = [
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--debug-env",
+        "--cache-dir",
+        cache_dir.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ];
+
+    let run = |greeting: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+            .args(args)
+            .env("MDCR_TEST_GREETING", greeting)
+            .output()
+            .unwrap()
+    };
+
+    let output = run("hello");
+    assert!(output.status.success());
+    assert!(cache_dir.join("environments.json").exists());
+
+    // All the variables are captured, as hashes
+    let cache = std::fs::read_to_string(cache_dir.join("environments.json")).unwrap();
+    assert!(cache.contains("PATH"));
+    assert!(cache.contains("MDCR_TEST_GREETING"));
+    assert!(!cache.contains("\"hello\""));
+
+    // Inherited variables show up in the diff
+    let output = run("bye");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("env: ~MDCR_TEST_GREETING\n"));
+
+    // So do the variables of the preset
+    let output = env.run(
+        &[
+            &args[..],
+            &["--set", "presets.shell.env.MDCR_TEST_GREETING=bye"],
+        ]
+        .concat(),
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Environment of preset `shell` changed since its last successful run"));
+    assert!(stderr.contains("env: ~MDCR_TEST_GREETING\n"));
+}
+
+// Chained presets -------------------------------------------------------------
+
+#[test]
+fn test_presets_run_in_config_order() {
+    let env = TestEnv::new(
+        "echo hello",
+        "sh",
+        r#"
+        [presets.zeta]
+        language = "sh"
+        command = ["sh"]
+
+        [presets.alpha]
+        language = "sh"
+        command = ["tr", "a-z", "A-Z"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&["explain", "--config", config, markdown]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.find("zeta").unwrap() < stdout.find("alpha").unwrap());
+
+    let output = env.run(&[
+        "--config",
+        config,
+        "--set",
+        "settings.match_strategy=first",
+        markdown,
+    ]);
+    assert!(output.status.success());
+    let content = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(content.contains("hello"));
+    assert!(!content.contains("HELLO"));
+}
+
+#[test]
+fn test_presets_run_on_the_output_of_the_previous_ones() {
+    let env = TestEnv::new(
+        "hello",
+        "text",
+        r#"
+        [settings]
+        chain_presets = true
+
+        [presets.upper]
+        language = "text"
+        command = ["tr", "a-z", "A-Z"]
+
+        [presets.exclaim]
+        language = "text"
+        command = ["sed", "s/$/!/"]
+
+        [presets.verify]
+        language = "text"
+        command = ["grep", "-q", "HELLO!"]
+        output_mode = "check"
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    for schedule in ["file", "preset"] {
+        std::fs::write(&env.md_path, "```text\nhello\n```\n").unwrap();
+        let output = env.run(&["--config", config, "--schedule", schedule, markdown]);
+        assert!(output.status.success(), "{schedule}");
+        assert_eq!(
+            std::fs::read_to_string(&env.md_path).unwrap(),
+            "```text\nHELLO!\n```\n",
+            "{schedule}"
+        );
+    }
+}
+
+#[test]
+fn test_presets_are_only_chained_when_enabled() {
+    let env = TestEnv::new(
+        "hello",
+        "text",
+        r#"
+        [presets.upper]
+        language = "text"
+        command = ["tr", "a-z", "A-Z"]
+
+        [presets.exclaim]
+        language = "text"
+        command = ["sed", "s/$/!/"]
+        "#,
+    );
+    let dir = env.md_path.parent().unwrap();
+    let config = env.cfg_path.to_str().unwrap();
+    let chained = ["--set", "settings.chain_presets=true"];
+
+    // Both presets run on the code of the block, the last one wins
+    let output = env.run_in(dir, &["test.md", "--config", config]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```text\nhello!\n```\n"
+    );
+
+    // Ignore entries match the code of the block, not the output of the chain
+    std::fs::write(&env.md_path, "```text\nhello\n```\n").unwrap();
+    let output = env.run_in(
+        dir,
+        &[
+            "ignore",
+            "add",
+            "test.md:1",
+            "--config",
+            config,
+            "--preset",
+            "exclaim",
+        ],
+    );
+    assert!(output.status.success());
+    let mut args = vec!["test.md", "--config", config];
+    args.extend(chained);
+    let output = env.run_in(dir, &args);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```text\nHELLO\n```\n"
+    );
+}
+
+#[test]
+fn test_check_mode_compares_the_output_of_the_whole_chain() {
+    let env = TestEnv::new(
+        "hello",
+        "text",
+        r#"
+        [settings]
+        chain_presets = true
+
+        [presets.upper]
+        language = "text"
+        command = ["tr", "a-z", "A-Z"]
+
+        [presets.verify]
+        language = "text"
+        command = ["grep", "-q", "HELLO"]
+        output_mode = "check"
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    for schedule in ["file", "preset"] {
+        // The verifier runs on the output of `upper`, the block mismatches
+        let output = env.run(&[
+            "--config",
+            config,
+            "--schedule",
+            schedule,
+            "--check",
+            markdown,
+        ]);
+        assert_eq!(output.status.code(), Some(1), "{schedule}");
+
+        std::fs::write(&env.md_path, "```text\nHELLO\n```\n").unwrap();
+        let output = env.run(&[
+            "--config",
+            config,
+            "--schedule",
+            schedule,
+            "--check",
+            markdown,
+        ]);
+        assert!(output.status.success(), "{schedule}");
+        std::fs::write(&env.md_path, "```text\nhello\n```\n").unwrap();
+    }
+}
+
+#[test]
+fn test_commands_are_piped_into_each_other() {
+    let env = TestEnv::new(
+        "echo hello",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        commands = [["sh"], ["tr", "a-z", "A-Z"], ["sed", "s/$/!/"]]
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(&env.md_path)
+        .unwrap()
+        .contains("HELLO!"));
+
+    let output = env.run(&[
+        "explain",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("sh | tr a-z A-Z | sed"));
+}
+
+#[test]
+fn test_empty_commands_are_config_errors() {
+    for (preset, error) in [
+        (
+            r#"commands = [["sh"], []]"#,
+            "Preset `shell` has an empty command in `commands`",
+        ),
+        (
+            r#"command = ["sh"]
+            fallback_command = []"#,
+            "Preset `shell` has an empty `fallback_command`",
+        ),
+    ] {
+        let env = TestEnv::outdated(&format!("[presets.shell]\nlanguage = \"sh\"\n{preset}\n"));
+        let output = env.run(&[
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+            env.md_path.to_str().unwrap(),
+        ]);
+        assert_eq!(output.status.code(), Some(3));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(error));
+    }
+}
+
+// Command output --------------------------------------------------------------
+
+#[test]
+fn test_output_encoding_is_converted() {
+    let env = TestEnv::new(
+        "outdated",
+        "txt",
+        r#"
+        [presets.latin1]
+        language = "txt"
+        command = ["printf", "caf\\351"]
+        output_encoding = "latin1"
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(updated.contains("café"));
+}
+
+#[test]
+fn test_unknown_output_encoding_is_a_config_error() {
+    let env = TestEnv::new(
+        "outdated",
+        "txt",
+        r#"
+        [presets.unknown]
+        language = "txt"
+        command = ["cat"]
+        output_encoding = "klingon"
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unknown output encoding `klingon`"));
+}
+
+#[test]
+fn test_replace_on_always_captures_failing_commands() {
+    let env = TestEnv::new(
+        "ls missing",
+        "console",
+        r#"
+        [presets.console]
+        language = "console"
+        command = ["sh", "-c", "echo '$ ls missing'; echo 'No such file' >&2; exit 2"]
+        replace_on = "always"
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(updated, "```console\n$ ls missing\nNo such file\n```\n");
+
+    // stderr starts on its own line
+    std::fs::write(
+        &env.cfg_path,
+        r#"
+        [presets.console]
+        language = "console"
+        command = ["sh", "-c", "printf 'partial'; echo 'No such file' >&2; exit 2"]
+        replace_on = "always"
+        "#,
+    )
+    .unwrap();
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(updated, "```console\npartial\nNo such file\n```\n");
+}
+
+#[test]
+fn test_wrap_output_at_breaks_long_lines() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "aaaa bbbb cccc dddd abcdefghijklmn"]
+        wrap_output_at = 10
+        "#,
+    );
+    let args = [
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ];
+
+    let output = env.run(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\naaaa \\\nbbbb \\\ncccc \\\ndddd \\\nabcdefghi\\\njklmn\n```\n"
+    );
+
+    // Wrapped blocks match the wrapped output
+    let output = env.run(&[args[0], "--check", args[1], args[2]]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_comparator_decides_whether_output_matches() {
+    let env = TestEnv::new(
+        "Date: 2020-01-01\nhello",
+        "txt",
+        r#"
+        [presets.report]
+        language = "txt"
+        command = ["sh", "-c", "echo 'Date: 2026-10-16'; echo hello"]
+        comparator = ["diff", "--ignore-matching-lines=^Date:", "{expected}", "{actual}"]
+        "#,
+    );
+    let args = [
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ];
+
+    let output = env.run(&args);
+    assert!(output.status.success());
+
+    std::fs::write(&env.md_path, "```txt\nDate: 2020-01-01\nhi\n```\n").unwrap();
+    let output = env.run(&args);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_broken_comparator_fails_the_block() {
+    let env = TestEnv::new(
+        "hello",
+        "txt",
+        r#"
+        [presets.report]
+        language = "txt"
+        command = ["echo", "hello"]
+        comparator = ["sh", "-c", "exit 2"]
+
+        [presets.missing]
+        language = "txt"
+        command = ["echo", "hello"]
+        comparator = ["mdcr-missing-comparator", "{expected}", "{actual}"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    for preset in ["report", "missing"] {
+        let output = env.run(&["--check", "--preset", preset, "--config", config, markdown]);
+        assert_eq!(output.status.code(), Some(2), "{preset}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("comparator"), "{stderr}");
+        assert!(!stderr.contains("mismatch"), "{stderr}");
+    }
+}
+
+#[test]
+fn test_artifact_is_copied_and_referenced() {
+    let env = TestEnv::new(
+        "plot data",
+        "plot",
+        r#"
+        [presets.plot]
+        language = "plot"
+        command = ["sh", "-c", "cat > {artifact}"]
+        output_mode = "artifact"
+        artifact_extension = "svg"
+        "#,
+    );
+    let args = [
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ];
+
+    let output = env.run_in(env.md_path.parent().unwrap(), &args);
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(
+        updated,
+        "```plot\nplot data\n```\n\n![plot](assets/test-1.svg)\n"
+    );
+    let asset = env.md_path.parent().unwrap().join("assets/test-1.svg");
+    assert_eq!(std::fs::read_to_string(&asset).unwrap(), "plot data\n");
+
+    // The reference is refreshed, not duplicated
+    let output = env.run_in(env.md_path.parent().unwrap(), &args);
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(&env.md_path).unwrap(), updated);
+
+    let mut check_args = args.to_vec();
+    check_args.push("--check");
+    let output = env.run_in(env.md_path.parent().unwrap(), &check_args);
+    assert!(output.status.success());
+
+    // The command now produces another artifact than the stored asset
+    std::fs::write(
+        &env.cfg_path,
+        r#"
+        [presets.plot]
+        language = "plot"
+        command = ["sh", "-c", "echo changed > {artifact}"]
+        output_mode = "artifact"
+        artifact_extension = "svg"
+        "#,
+    )
+    .unwrap();
+    let output = env.run_in(env.md_path.parent().unwrap(), &check_args);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Stale artifact `assets/test-1.svg`"));
+    assert_eq!(std::fs::read_to_string(&asset).unwrap(), "plot data\n");
+
+    std::fs::remove_file(&asset).unwrap();
+    let output = env.run_in(env.md_path.parent().unwrap(), &check_args);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Missing artifact `assets/test-1.svg`")
+    );
+}
+
+#[test]
+fn test_verified_field_is_updated_and_checked() {
+    let env = TestEnv::from_raw_markdown(
+        "---\ntitle: Doc\nlast_verified: 2000-01-01\n---\n\n```sh\nhello\n```\n",
+        r#"
+        [settings]
+        verified_field = "last_verified"
+        max_verified_age_days = 30
+
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+    let path = env.md_path.to_str().unwrap();
+    let cfg = env.cfg_path.to_str().unwrap();
+
+    let output = env.run(&[path, "--check", "--config", cfg]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("was last verified on 2000-01-01"));
+
+    std::fs::write(
+        &env.md_path,
+        "---\ntitle: Doc\nlast_verified: 2000-01-01\n---\n\n```sh\necho outdated\n```\n",
+    )
+    .unwrap();
+    let output = env.run(&[path, "--config", cfg]);
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(!updated.contains("2000-01-01"));
+    assert!(updated.contains("```sh\nhello\n```"));
+
+    let output = env.run(&[path, "--check", "--config", cfg]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_documents_without_final_newline_round_trip() {
+    let env = TestEnv::from_raw_markdown(
+        "Intro\n\n```sh\necho outdated\n```",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+    let args = [
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ];
+
+    let output = env.run(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "Intro\n\n```sh\nhello\n```"
+    );
+
+    std::fs::write(&env.md_path, "```sh\necho outdated\n```").unwrap();
+    let config = std::fs::read_to_string(&env.cfg_path).unwrap();
+    std::fs::write(
+        &env.cfg_path,
+        format!("[settings]\nfinal_newline = true\n{config}"),
+    )
+    .unwrap();
+    let output = env.run(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+}
+
+// Writing the results ---------------------------------------------------------
+
+#[test]
+fn test_stdout_prints_processed_markdown_without_editing() {
+    let env = TestEnv::stale();
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--stdout",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("```sh\nhello\n```"));
+    let current = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(current.contains("echo outdated"));
+
+    // Each document of several files follows a header
+    let dir = env.md_path.parent().unwrap();
+    std::fs::copy(&env.md_path, dir.join("other.md")).unwrap();
+    let output = env.run_in(
+        dir,
+        &["other.md", "test.md", "--stdout", "--config", "config.json"],
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "==> other.md <==\n```sh\nhello\n```\n\n==> test.md <==\n```sh\nhello\n```\n"
+    );
+}
+
+#[test]
+fn test_output_dir_mirrors_processed_files() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let docs = dir.path().join("docs");
+    let build = dir.path().join("build");
+    let config_path = dir.path().join("config.toml");
+
+    fs::create_dir_all(docs.join("guide")).unwrap();
+    fs::write(docs.join("guide/intro.md"), "```sh\necho outdated\n```\n").unwrap();
+    fs::write(docs.join("index.md"), "# Index\n").unwrap();
+    fs::write(
+        &config_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            docs.to_str().unwrap(),
+            "--output-dir",
+            build.to_str().unwrap(),
+            "--config",
+            config_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(build.join("guide/intro.md")).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+    assert_eq!(
+        fs::read_to_string(build.join("index.md")).unwrap(),
+        "# Index\n"
+    );
+    assert_eq!(
+        fs::read_to_string(docs.join("guide/intro.md")).unwrap(),
+        "```sh\necho outdated\n```\n"
+    );
+}
+
+#[test]
+fn test_dry_run_writes_patch() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let cfg = env.cfg_path.to_str().unwrap();
+
+    let output = env.run_in(
+        dir,
+        &[
+            "test.md",
+            "--dry-run",
+            "--patch",
+            "out.patch",
+            "--config",
+            cfg,
+        ],
+    );
+
+    assert!(output.status.success());
+    let patch = std::fs::read_to_string(dir.join("out.patch")).unwrap();
+    assert!(patch.contains("--- a/test.md\n+++ b/test.md\n"));
+    assert!(patch.contains("-echo outdated\n+hello\n"));
+    let current = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(current.contains("echo outdated"));
+}
+
+#[test]
+fn test_dry_run_paths_are_relative_to_the_project_root() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let docs = dir.join("docs");
+    std::fs::create_dir(&docs).unwrap();
+    std::fs::create_dir(dir.join(".git")).unwrap();
+    let markdown = docs.join("test.md");
+    std::fs::rename(&env.md_path, &markdown).unwrap();
+    let original = std::fs::read_to_string(&markdown).unwrap();
+    let cfg = env.cfg_path.to_str().unwrap();
+
+    let output = env.run_in(
+        &docs,
+        &["--dry-run", "--config", cfg, markdown.to_str().unwrap()],
+    );
+    assert!(output.status.success());
+    let patch = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        patch.contains("--- a/docs/test.md\n+++ b/docs/test.md\n"),
+        "{patch}"
+    );
+    assert!(patch.contains("-echo outdated\n+hello\n"));
+    assert_eq!(std::fs::read_to_string(&markdown).unwrap(), original);
+
+    std::fs::write(docs.join("out.patch"), patch.as_bytes()).unwrap();
+    let output = env.run_in(&docs, &["apply", "out.patch"]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&markdown).unwrap(),
+        original.replace("echo outdated", "hello")
+    );
+}
+
+#[test]
+fn test_apply_patch_validates_recorded_lines() {
+    let env = TestEnv::stale();
+    let dir = env.md_path.parent().unwrap();
+    let cfg = env.cfg_path.to_str().unwrap();
+    let original = std::fs::read_to_string(&env.md_path).unwrap();
+
+    let output = env.run_in(
+        dir,
+        &[
+            "test.md",
+            "--dry-run",
+            "--patch",
+            "out.patch",
+            "--config",
+            cfg,
+        ],
+    );
+    assert!(output.status.success());
+
+    std::fs::write(
+        &env.md_path,
+        original.replace("echo outdated", "echo edited"),
+    )
+    .unwrap();
+    let output = env.run_in(dir, &["apply", "out.patch"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("changed since the patch was generated"));
+
+    std::fs::write(&env.md_path, &original).unwrap();
+    let output = env.run_in(dir, &["apply", "out.patch"]);
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(updated, original.replace("echo outdated", "hello"));
+}
+
+#[test]
+fn test_apply_rejects_malformed_patches() {
+    let env = TestEnv::new("echo outdated", "sh", "");
+    let dir = env.md_path.parent().unwrap();
+    let original = std::fs::read_to_string(&env.md_path).unwrap();
+    let hunks = [
+        // More lines than the header counts
+        "@@ -2,1 +2,1 @@\n-echo outdated\n-echo extra\n+hello\n",
+        // Fewer lines than the header counts
+        "@@ -2,2 +2,1 @@\n-echo outdated\n",
+    ];
+
+    for hunk in hunks {
+        std::fs::write(
+            dir.join("out.patch"),
+            format!("--- a/test.md\n+++ b/test.md\n{hunk}"),
+        )
+        .unwrap();
+        let output = env.run_in(dir, &["apply", "out.patch"]);
+        assert_eq!(output.status.code(), Some(3));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Invalid patch: hunk `@@ -2,"), "{stderr}");
+        assert_eq!(std::fs::read_to_string(&env.md_path).unwrap(), original);
+    }
+}
+
+#[test]
+fn test_apply_stdin_prints_transformed_document() {
+    let env = TestEnv::stale();
+
+    let output = env.run_with_stdin(
+        &[
+            "apply",
+            "--stdin",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+        "# Title\n\n```sh\necho outdated\n```\n",
+    );
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout, "# Title\n\n```sh\nhello\n```\n");
+
+    let untouched = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(untouched.contains("echo outdated"));
+}
+
+#[test]
+fn test_interactive_applies_accepted_changes_only() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho first\n```\n\n```sh\necho second\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+
+    let output = env.run_with_stdin(
+        &[
+            env.md_path.to_str().unwrap(),
+            "--interactive",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+        "y\nn\n",
+    );
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("-echo first\n+hello"));
+    assert!(stderr.contains("Apply this change to"));
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(updated, "```sh\nhello\n```\n\n```sh\necho second\n```\n");
+}
+
+// Check mode ------------------------------------------------------------------
+
+#[test]
+fn test_force_check_checks_replace_presets() {
+    let env = TestEnv::stale();
+    let run = || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+            .args([
+                env.md_path.to_str().unwrap(),
+                "--config",
+                env.cfg_path.to_str().unwrap(),
+            ])
+            .env("MDCR_FORCE_CHECK", "1")
+            .output()
+            .unwrap()
+    };
+
+    // Mismatches fail the run as with `--check`
+    let output = run();
+    assert_eq!(output.status.code(), Some(1));
+    let current = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(current.contains("echo outdated"));
+
+    std::fs::write(&env.md_path, "```sh\nhello\n```\n").unwrap();
+    assert!(run().status.success());
+}
+
+#[test]
+fn test_exit_codes_distinguish_failure_classes() {
+    let mismatch = TestEnv::stale();
+    let output = mismatch.run(&[
+        mismatch.md_path.to_str().unwrap(),
+        "--check",
+        "--config",
+        mismatch.cfg_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let command_failure = TestEnv::new(
+        "echo hello",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "exit 42"]
+        "#,
+    );
+    let output = command_failure.run(&[
+        command_failure.md_path.to_str().unwrap(),
+        "--check",
+        "--config",
+        command_failure.cfg_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let config_error = TestEnv::new("echo hello", "sh", "not valid TOML");
+    let output = config_error.run(&[
+        config_error.md_path.to_str().unwrap(),
+        "--config",
+        config_error.cfg_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn test_warning_severity_does_not_fail_the_run() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.experimental]
+        language = "sh"
+        command = ["echo", "hello"]
+        severity = "warning"
+
+        [presets.linter]
+        language = "sh"
+        command = ["sh", "-c", "exit 1"]
+        output_mode = "check"
+        severity = "warning"
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--format",
+        "checkstyle",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("<error line=\"1\" severity=\"warning\""));
+    assert!(stdout.contains("source=\"mdcr.experimental\""));
+}
+
+#[test]
+fn test_check_summarizes_mismatches_by_preset() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\nold\n```\n\n```sh\nolder\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "new"]
+        "#,
+    );
+    let dir = env.md_path.parent().unwrap();
+    std::fs::copy(&env.md_path, dir.join("other.md")).unwrap();
+
+    let output = env.run(&[
+        dir.to_str().unwrap(),
+        "--check",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("shell: 4 blocks stale across 2 files"));
+}
+
+#[test]
+fn test_forbid_unexplained_skips() {
+    let env = TestEnv::from_raw_markdown(
+        r##"
+```sh mdcr-skip reason="flaky upstream" issue="#123"
+echo explained
+```
+
+```sh mdcr-skip
+echo unexplained
+```
+        "##,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--forbid-unexplained-skips",
+        "--format",
+        "checkstyle",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("is skipped without a reason").count(), 1);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Code block skipped: flaky upstream (issue: #123)"));
+}
+
+#[test]
+fn test_check_fails_when_the_quotas_leave_blocks_unchecked() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\nhello\n```\n\n```sh\nhello\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&[
+        "--check",
+        "--max-commands",
+        "1",
+        "--config",
+        config,
+        markdown,
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--allow-partial-check"));
+
+    let output = env.run(&[
+        "--check",
+        "--max-commands",
+        "1",
+        "--allow-partial-check",
+        "--config",
+        config,
+        markdown,
+    ]);
+    assert!(output.status.success());
+}
+
+// Reports ---------------------------------------------------------------------
+
+#[test]
+fn test_checkstyle_format_reports_mismatches() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        input_mode = "stdin"
+        output_mode = "replace"
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--format",
+        "checkstyle",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("<?xml"));
+    assert!(stdout.contains(&format!("<file name=\"{}\">", env.md_path.display())));
+    assert!(stdout.contains("<error line=\"1\" severity=\"error\""));
+    assert!(stdout.contains("source=\"mdcr.shell\""));
+
+    // Failing commands are reported too
+    std::fs::write(
+        &env.cfg_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["false"]
+        "#,
+    )
+    .unwrap();
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--format",
+        "checkstyle",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("<file name=\"{}\">", env.md_path.display())));
+    assert!(stdout.contains(
+        "<error line=\"1\" severity=\"error\" message=\"Command failure in lines 1-3 (preset: `shell`"
+    ));
+}
+
+#[test]
+fn test_reports_are_sorted_by_path_and_line() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    for name in ["c.md", "a.md", "b.md"] {
+        fs::write(
+            dir.path().join(name),
+            "```sh\none\n```\n\n```sh\ntwo\n```\n\n```sh\nthree\n```\n",
+        )
+        .unwrap();
+    }
+    fs::write(
+        &config_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    )
+    .unwrap();
+
+    let run = || {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+            .args([
+                dir.path().to_str().unwrap(),
+                "--check",
+                "--format",
+                "checkstyle",
+                "--config",
+                config_path.to_str().unwrap(),
+            ])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let report = run();
+    let files: Vec<&str> = report
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("<file name=\""))
+        .collect();
+    assert_eq!(files.len(), 3);
+    assert!(files.windows(2).all(|pair| pair[0] < pair[1]));
+    let lines: Vec<&str> = report
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("<error line=\""))
+        .map(|line| line.split('"').next().unwrap())
+        .collect();
+    assert_eq!(lines, ["1", "5", "9", "1", "5", "9", "1", "5", "9"]);
+    assert_eq!(run(), report);
+}
+
+#[test]
+fn test_annotate_only_prints_github_annotations_and_summary() {
+    let env = TestEnv::stale();
+    let summary = env.md_path.with_file_name("summary.md");
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            env.md_path.to_str().unwrap(),
+            "--annotate-only",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ])
+        .env("GITHUB_STEP_SUMMARY", &summary)
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with(&format!(
+        "::error file={},line=1,endLine=3,title=mdcr (shell)::",
+        env.md_path.display()
+    )));
+    let summary = std::fs::read_to_string(summary).unwrap();
+    assert!(summary.contains("| Mismatching | 1 |"));
+    let current = std::fs::read_to_string(&env.md_path).unwrap();
+    assert!(current.contains("echo outdated"));
+}
+
+#[test]
+fn test_meta_is_embedded_into_reports() {
+    let env = TestEnv::stale();
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--format",
+        "checkstyle",
+        "--meta",
+        "git_sha=abc123",
+        "--meta",
+        "job=https://ci.example.com/jobs/1?a=b&c=d",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(
+        "  <properties>\n    <property name=\"git_sha\" value=\"abc123\"/>\n    <property name=\"job\" value=\"https://ci.example.com/jobs/1?a=b&amp;c=d\"/>\n  </properties>\n"
+    ));
+
+    // `|` would end the cells of the table of the step summary
+    let summary = env.md_path.with_file_name("summary.md");
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            env.md_path.to_str().unwrap(),
+            "--annotate-only",
+            "--meta",
+            "command=a | b",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ])
+        .env("GITHUB_STEP_SUMMARY", &summary)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let summary = std::fs::read_to_string(summary).unwrap();
+    assert!(summary.contains("| command | a \\| b |\n"));
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--meta",
+        "no-separator",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn test_events_log_block_results_with_replacements() {
+    let env = TestEnv::stale();
+    let events = env.md_path.with_file_name("events.jsonl");
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--check",
+        "--events",
+        events.to_str().unwrap(),
+        "--include-content",
+        "--meta",
+        "git_sha=abc123",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let log = std::fs::read_to_string(&events).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines[0]["type"], "run");
+    assert_eq!(lines[0]["metadata"]["git_sha"], "abc123");
+    let mismatch = lines.last().unwrap();
+    assert_eq!(mismatch["type"], "block");
+    assert_eq!(mismatch["status"], "mismatch");
+    assert_eq!(mismatch["start_line"], 1);
+    assert_eq!(mismatch["end_line"], 3);
+    assert_eq!(mismatch["replacement"], "```sh\nhello\n```");
+}
+
+#[test]
+fn test_timings_lists_the_slowest_presets_and_blocks() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        "--check",
+        "--timings",
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Slowest presets:"));
+    assert!(stderr.contains("s  shell (1 blocks)"));
+    assert!(stderr.contains("test.md:1-3 (shell, block: `sha-"));
+}
+
+#[test]
+fn test_estimate_uses_previous_timings() {
+    let env = TestEnv::new(
+        "hello",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+    let cache_dir = env.md_path.parent().unwrap().join("cache");
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--estimate",
+        "--cache-dir",
+        cache_dir.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Commands to execute: 1"));
+    assert!(stdout.contains("No timing data for preset `shell`"));
+    assert!(!cache_dir.exists(), "Estimating must not run anything");
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--cache-dir",
+        cache_dir.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(!cache_dir.join("timings.json").exists());
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--record-timings",
+        "--cache-dir",
+        cache_dir.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert!(cache_dir.join("timings.json").exists());
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--estimate",
+        "--cache-dir",
+        cache_dir.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Commands to execute: 1"));
+    assert!(!stdout.contains("No timing data"));
+}
+
+// Scheduling and limits -------------------------------------------------------
+
+#[test]
+fn test_schedule_grouped_by_preset() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let first = dir.path().join("first.md");
+    let second = dir.path().join("second.md");
+    let config_path = dir.path().join("config.toml");
+
+    fs::write(&first, "```sh\necho one\n```\n\n```py\nprint(1)\n```\n").unwrap();
+    fs::write(&second, "```sh\necho two\n```\n").unwrap();
+    fs::write(
+        &config_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+
+        [presets.python]
+        language = "py"
+        command = ["echo", "python"]
+        "#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            dir.path().to_str().unwrap(),
+            "--schedule",
+            "preset",
+            "--config",
+            config_path.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(&first).unwrap(),
+        "```sh\nhello\n```\n\n```py\npython\n```\n"
+    );
+    assert_eq!(fs::read_to_string(&second).unwrap(), "```sh\nhello\n```\n");
+}
+
+#[test]
+fn test_jobs_caps_the_worker_threads() {
+    let env = TestEnv::new(
+        "echo hello",
+        "sh",
+        r#"
+        [settings]
+        jobs = 3
+
+        [presets.shell]
+        language = "sh"
+        command = ["cat"]
+        "#,
+    );
+    let args = [
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        "--estimate",
+    ];
+
+    let output = env.run(&args);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("across 3 threads"));
+
+    let mut with_jobs = args.to_vec();
+    with_jobs.extend(["--jobs", "1"]);
+    let output = env.run(&with_jobs);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("across 1 threads"));
+}
+
+#[test]
+fn test_invalid_jobs_and_log_settings_are_config_errors() {
+    for (settings, error) in [
+        ("jobs = 0", "`settings.jobs` must be at least 1"),
+        ("log = \"loud\"", "Unknown `settings.log` level `loud`"),
+    ] {
+        let env = TestEnv::outdated(&format!(
+            r#"
+            [settings]
+            {settings}
+
+            [presets.shell]
+            language = "sh"
+            command = ["echo", "hello"]
+            "#
+        ));
+        let output = env.run(&[
+            env.md_path.to_str().unwrap(),
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ]);
+        assert_eq!(output.status.code(), Some(3));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(error));
+    }
+}
+
+#[test]
+fn test_max_failures_per_preset_skips_remaining_blocks() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\none\n```\n\n```sh\ntwo\n```\n\n```sh\nthree\n```\n",
+        r#"
+        [settings]
+        max_failures_per_preset = 1
+
+        [presets.broken]
+        language = "sh"
+        command = ["sh", "-c", "exit 1"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("returned a non-zero exit status").count(), 1);
+    assert!(stderr.contains("2 blocks of preset `broken` were skipped due to earlier failures"));
+}
+
+#[test]
+fn test_max_commands_skips_the_remaining_blocks() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho a\n```\n\n```sh\necho b\n```\n\n```sh\necho c\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--max-commands",
+        "1",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let updated = std::fs::read_to_string(&env.md_path).unwrap();
+    assert_eq!(updated.matches("hello").count(), 1);
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("2 blocks of preset `shell` were skipped after reaching `--max-commands`"));
+}
+
+#[test]
+fn test_fail_fast_stops_at_first_failure() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\none\n```\n\n```sh\ntwo\n```\n\n```sh\nthree\n```\n",
+        r#"
+        [presets.broken]
+        language = "sh"
+        command = ["sh", "-c", "exit 1"]
+        "#,
+    );
+
+    let output = env.run(&[
+        env.md_path.to_str().unwrap(),
+        "--fail-fast",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("returned a non-zero exit status").count(), 1);
+}
+
+// Configuration ---------------------------------------------------------------
+
+#[test]
+fn test_config_is_discovered_upward_from_each_path() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    for file in ["docs/guide/intro.md", "other/readme.md"] {
+        let path = dir.path().join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "```sh\nold\n```\n").unwrap();
+    }
+    fs::write(
+        dir.path().join("mdcr.toml"),
+        "[presets.shell]\nlanguage = \"sh\"\ncommand = [\"echo\", \"root\"]\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.path().join("other/.mdcr.toml"),
+        "[presets.shell]\nlanguage = \"sh\"\ncommand = [\"echo\", \"other\"]\n",
+    )
+    .unwrap();
+    let env = TestEnv::from_raw_markdown("", "");
+
+    let output = env.run_in(&dir.path().join("docs/guide"), &[".", "../../other"]);
+
+    assert!(output.status.success());
+    let read = |path: &str| fs::read_to_string(dir.path().join(path)).unwrap();
+    assert_eq!(read("docs/guide/intro.md"), "```sh\nroot\n```\n");
+    assert_eq!(read("other/readme.md"), "```sh\nother\n```\n");
+
+    // The paths of both configs are reported together
+    for file in ["docs/guide/intro.md", "other/readme.md"] {
+        fs::write(dir.path().join(file), "```sh\nold\n```\n").unwrap();
+    }
+    let output = env.run_in(
+        &dir.path().join("docs/guide"),
+        &[".", "../../other", "--check", "--format", "checkstyle"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("<?xml").count(), 1, "{stdout}");
+    assert_eq!(stdout.matches("<file ").count(), 2, "{stdout}");
+}
+
+#[test]
+fn test_config_discovery_stops_at_the_git_root() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    fs::create_dir_all(repo.join(".git")).unwrap();
+    fs::write(repo.join("a.md"), "```sh\nold\n```\n").unwrap();
+    for (config, output) in [(dir.path(), "outside"), (repo.as_path(), "repo")] {
+        fs::write(
+            config.join("mdcr.toml"),
+            format!("[presets.shell]\nlanguage = \"sh\"\ncommand = [\"echo\", \"{output}\"]\n"),
+        )
+        .unwrap();
+    }
+    let env = TestEnv::from_raw_markdown("", "");
+
+    let output = env.run_in(&repo, &[]);
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        fs::read_to_string(repo.join("a.md")).unwrap(),
+        "```sh\nrepo\n```\n"
+    );
+}
+
+#[test]
+fn test_nested_configs_override_the_root_one_in_their_directory() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    for schedule in ["file", "preset"] {
+        let dir = tempdir().unwrap();
+        for file in ["docs/intro.md", "docs/api/reference.md"] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "```sh\nold\n```\n\n```py\nold\n```\n").unwrap();
+        }
+        fs::write(
+            dir.path().join("mdcr.toml"),
+            "[presets.shell]\nlanguage = \"sh\"\ncommand = [\"echo\", \"root\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("docs/api/mdcr.toml"),
+            "[presets.shell]\ncommand = [\"echo\", \"api\"]\n\n[presets.python]\nlanguage = \"py\"\ncommand = [\"echo\", \"python\"]\n",
+        )
+        .unwrap();
+        let env = TestEnv::from_raw_markdown("", "");
+
+        let output = env.run_in(
+            &dir.path().join("docs/api"),
+            &["..", "--schedule", schedule],
+        );
+
+        assert!(output.status.success(), "{output:?}");
+        let read = |path: &str| fs::read_to_string(dir.path().join(path)).unwrap();
+        assert_eq!(
+            read("docs/intro.md"),
+            "```sh\nroot\n```\n\n```py\nold\n```\n"
+        );
+        assert_eq!(
+            read("docs/api/reference.md"),
+            "```sh\napi\n```\n\n```py\npython\n```\n"
+        );
+    }
+}
+
+#[test]
+fn test_yaml_and_json_configs() {
+    let env = TestEnv::new("echo outdated", "sh", "");
+    let dir = env.md_path.parent().unwrap();
+    std::fs::write(
+        dir.join("config.yaml"),
+        "presets:\n  shell:\n    language: sh\n    command: [sh]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("config.json"),
+        r#"{"presets": {"shell": {"language": "sh", "command": ["sh"]}}}"#,
+    )
+    .unwrap();
+    // TOML is still read from configs named `.json`
+    std::fs::write(
+        dir.join("legacy.json"),
+        "[presets.shell]\nlanguage = \"sh\"\ncommand = [\"sh\"]\n",
+    )
+    .unwrap();
+
+    for config in ["config.yaml", "config.json", "legacy.json"] {
+        let output = env.run(&[
+            "--config",
+            dir.join(config).to_str().unwrap(),
+            "--check",
+            env.md_path.to_str().unwrap(),
+        ]);
+        assert_eq!(output.status.code(), Some(1), "{config}");
+    }
+}
+
+#[test]
+fn test_later_configs_override_and_add_presets() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\nold\n```\n\n```py\nold\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "base"]
+        "#,
+    );
+    let local = env.cfg_path.with_file_name("local.toml");
+    std::fs::write(
+        &local,
+        "[presets.shell]\ncommand = [\"echo\", \"local\"]\n\n[presets.python]\nlanguage = \"py\"\ncommand = [\"echo\", \"python\"]\n",
+    )
+    .unwrap();
+
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        "--config",
+        local.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nlocal\n```\n\n```py\npython\n```\n"
+    );
+}
+
+#[test]
+fn test_presets_extend_other_presets() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\nold\n```\n\n```bash\nold\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "echo shell"]
+        output_mode = "replace"
+
+        [presets.bash]
+        extends = "shell"
+        language = "bash"
+        "#,
+    );
+
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nshell\n```\n\n```bash\nshell\n```\n"
+    );
+
+    std::fs::write(
+        &env.cfg_path,
+        "[presets.a]\nextends = \"b\"\n\n[presets.b]\nextends = \"a\"\n",
+    )
+    .unwrap();
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Presets extend each other: a -> b -> a")
+    );
+}
+
+#[test]
+fn test_profiles_override_the_config() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+
+        [profiles.lenient.presets.shell]
+        severity = "warning"
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&["--config", config, "--check", markdown]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = env.run(&[
+        "--config",
+        config,
+        "--check",
+        "--profile",
+        "lenient",
+        markdown,
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = env.run(&["--config", config, "--profile", "missing", markdown]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown profile `missing`"));
+}
+
+#[test]
+fn test_set_overrides_config_values() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&[
+        "--config",
+        config,
+        "--set",
+        r#"presets.shell.command=["echo", "echo set"]"#,
+        "--set",
+        "presets.shell.severity=warning",
+        "--check",
+        markdown,
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+
+    env.run(&[
+        "--config",
+        config,
+        "--set",
+        r#"presets.shell.command=["echo", "echo set"]"#,
+        markdown,
+    ]);
+    assert!(std::fs::read_to_string(&env.md_path)
+        .unwrap()
+        .contains("echo set"));
+
+    let output = env.run(&[
+        "--config",
+        config,
+        "--set",
+        "presets.shell.command.program=sh",
+        markdown,
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Cannot set `presets.shell.command.program`: `command` is not a table"));
+}
+
+#[test]
+fn test_settings_exclude_and_log_are_overridden_by_the_flags() {
+    use std::fs;
+    use tempfile::tempdir;
+
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    for file in ["docs/vendor/lib.md", "docs/guide.md"] {
+        let path = dir.path().join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "```sh\nold\n```\n").unwrap();
+    }
+    fs::write(
+        &config_path,
+        r#"
+        [settings]
+        exclude = ["docs/vendor"]
+        log = "info"
+
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    )
+    .unwrap();
+    let run = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+            .args([
+                dir.path().to_str().unwrap(),
+                "--config",
+                config_path.to_str().unwrap(),
+            ])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let read = |path: &str| fs::read_to_string(dir.path().join(path)).unwrap();
+
+    let output = run(&[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("INFO "));
+    assert_eq!(read("docs/guide.md"), "```sh\nhello\n```\n");
+    assert_eq!(read("docs/vendor/lib.md"), "```sh\nold\n```\n");
+
+    let output = run(&["--exclude", "docs/guide.md", "--log", "warn"]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("INFO "));
+    assert_eq!(read("docs/vendor/lib.md"), "```sh\nhello\n```\n");
+}
+
+#[test]
+fn test_unknown_config_keys_are_rejected() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        ouput_mode = "replace"
+        "#,
+    );
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Unknown key `presets.shell.ouput_mode`, did you mean `output_mode`?"));
+}
+
+#[test]
+fn test_schema_describes_the_config() {
+    let env = TestEnv::new("echo hi", "sh", "");
+    let output = env.run(&["schema"]);
+    assert!(output.status.success());
+
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(schema["properties"]["presets"].is_object());
+    assert!(schema["properties"]["profiles"].is_object());
+    let preset = &schema["$defs"]["PresetConfig"]["properties"];
+    for key in ["language", "languages", "command", "output_mode", "extends"] {
+        assert!(preset[key].is_object(), "missing `{key}`");
+    }
+    assert_eq!(
+        schema["$defs"]["PresetConfig"]["additionalProperties"],
+        false
+    );
+}
+
+// Subcommands -----------------------------------------------------------------
+
+#[test]
+fn test_run_and_validate_subcommands() {
+    let env = TestEnv::stale();
+    let path = env.md_path.to_str().unwrap();
+    let cfg = env.cfg_path.to_str().unwrap();
+
+    let output = env.run(&["validate", path, "--config", cfg]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 code blocks in 1 Markdown files"));
+    assert!(stdout.contains("  shell: 1 code blocks"));
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\necho outdated\n```\n"
+    );
+
+    for subcommand in ["run", "check"] {
+        let output = env.run(&[subcommand, "--check", path, "--config", cfg]);
+        assert_eq!(output.status.code(), Some(3), "{subcommand}");
+    }
+
+    let output = env.run(&["run", path, "--config", cfg]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n"
+    );
+}
+
+#[test]
+fn test_validate_config_reports_placeholders_and_shared_languages() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "cat {fil}"]
+
+        [presets.missing]
+        language = "sh"
+        command = ["mdcr-missing-tool", "{code}"]
+        "#,
+    );
+
+    let output = env.run(&[
+        "validate-config",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[  error] Preset `shell`: unknown placeholder `{fil}`"));
+    assert!(stdout.contains(
+        "[  error] Preset `missing`: `{code}` is only expanded with `input_mode = \"arg\"`"
+    ));
+    assert!(stdout.contains("[  error] Preset `missing`: `mdcr-missing-tool` not found in PATH"));
+    assert!(stdout.contains("[warning] Presets `missing` and `shell` both run on `sh` blocks"));
+}
+
+#[test]
+fn test_explain_prints_the_commands_without_running_them() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho outdated\n```\n\n```python\nprint()\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "cat {file} > ran.txt"]
+        input_mode = "file"
+        "#,
+    );
+
+    let output = env.run(&[
+        "explain",
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(":1-3 `sh`\n  shell: sh -c 'cat <file> > ran.txt' (code in <file>)"));
+    assert!(stdout.contains(":5-7 `python`: no preset"));
+    assert!(!env.md_path.with_file_name("ran.txt").exists());
+
+    // Printed commands can be pasted into a shell
+    std::fs::write(
+        &env.cfg_path,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["run", "{relpath}", "<", "{file}"]
+        input_mode = "file"
+        "#,
+    )
+    .unwrap();
+    let dir = env.md_path.parent().unwrap();
+    let output = env.run(&[
+        "explain",
+        "--project-root",
+        dir.parent().unwrap().to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let relpath = std::path::Path::new(dir.file_name().unwrap()).join("test.md");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "  shell: run {} '<' '<file>' (code in <file>)",
+        relpath.display()
+    )));
+}
+
+#[test]
+fn test_list_blocks_prints_blocks_as_json() {
+    let env = TestEnv::from_raw_markdown(
+        "Intro\n\n- item\n\n  ```{.python #ex1}\n  print()\n  ```\n",
+        "",
+    );
+
+    let output = env.run(&[
+        "list-blocks",
+        "--format",
+        "json",
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    let blocks: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let block = &blocks[0];
+    assert_eq!(block["start_line"], 5);
+    assert_eq!(block["end_line"], 7);
+    assert_eq!(block["language"], "python");
+    assert_eq!(block["id"], "ex1");
+    assert_eq!(block["indent"], 2);
+}
+
+#[test]
+fn test_doctor_reports_missing_programs() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+
+        [presets.missing]
+        language = "sh"
+        command = ["mdcr-missing-tool", "--fix"]
+        "#,
+    );
+    let dir = env.md_path.parent().unwrap();
+
+    let output = env.run_in(
+        dir,
+        &[
+            "doctor",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+            "test.md",
+        ],
+    );
+
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[     ok] Preset `shell`: `echo` found"));
+    assert!(stdout.contains("[  error] Preset `missing`: `mdcr-missing-tool` not found in PATH"));
+    assert!(stdout.contains("[     ok] 1 Markdown files in `test.md` are writable"));
+}
+
+#[test]
+fn test_impact_lists_the_files_a_preset_would_change() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+
+        [presets.other]
+        language = "sh"
+        command = ["echo", "other"]
+        "#,
+    );
+    let dir = env.md_path.parent().unwrap();
+    std::fs::write(dir.join("unchanged.md"), "```sh\nhello\n```\n").unwrap();
+
+    let output = env.run_in(
+        dir,
+        &[
+            "impact",
+            "--preset",
+            "shell",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+    );
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("test.md: 1 code blocks would change"));
+    assert!(!stdout.contains("unchanged.md"));
+    assert!(stdout.contains("1 of 2 files would change with preset `shell`"));
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\necho outdated\n```\n"
+    );
+}
+
+#[test]
+fn test_completions_and_man_pages_are_generated() {
+    let env = TestEnv::from_raw_markdown("", "");
+
+    let output = env.run(&["completions", "bash"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("_mdcr()"));
+    assert!(stdout.contains("list-blocks"));
+
+    let dir = env.md_path.with_file_name("man");
+    let output = env.run(&["man", "--out-dir", dir.to_str().unwrap()]);
+    assert!(output.status.success());
+    let page = std::fs::read_to_string(dir.join("mdcr-check.1")).unwrap();
+    assert!(page.contains(".TH mdcr-check 1"));
+}
+
+#[test]
+fn test_watch_reprocesses_changed_files() {
+    use std::time::{Duration, Instant};
+
+    let env = TestEnv::stale();
+    let wait_for = |expected: &str| {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(10) {
+            if std::fs::read_to_string(&env.md_path).unwrap() == expected {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    };
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            env.md_path.parent().unwrap().to_str().unwrap(),
+            "--watch",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ])
+        .spawn()
+        .unwrap();
+
+    let processed = wait_for("```sh\nhello\n```\n");
+    // Lets the watcher settle after the rewrite of the first run
+    std::thread::sleep(Duration::from_millis(500));
+    std::fs::write(&env.md_path, "```sh\necho changed\n```\n").unwrap();
+    let reprocessed = wait_for("```sh\nhello\n```\n");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(processed);
+    assert!(reprocessed);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_processes_buffers_sent_on_its_socket() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::time::{Duration, Instant};
+
+    let env = TestEnv::stale();
+    let socket = env.md_path.with_file_name("daemon.sock");
+    let mut daemon = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args([
+            "daemon",
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+            "--socket",
+            socket.to_str().unwrap(),
+        ])
+        .spawn()
+        .unwrap();
+
+    let started = Instant::now();
+    let stream = loop {
+        match UnixStream::connect(&socket) {
+            Ok(stream) => break Some(stream),
+            Err(_) if started.elapsed() < Duration::from_secs(10) => {
+                std::thread::sleep(Duration::from_millis(50))
+            }
+            Err(_) => break None,
+        }
+    };
+    let responses = stream.map(|mut stream| {
+        stream
+            .write_all(b"{\"id\": 1, \"content\": \"```sh\\necho outdated\\n```\\n\"}\n{\"id\": 2, \"content\": \"```sh\\necho outdated\\n```\\n\", \"check\": true}\n")
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut next = || serde_json::from_str::<serde_json::Value>(&lines.next().unwrap().unwrap()).unwrap();
+        (next(), next())
+    });
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+    let (processed, checked) = responses.expect("the daemon did not listen on its socket");
+    assert_eq!(processed["id"], 1);
+    assert_eq!(processed["status"], 0);
+    assert_eq!(processed["content"], "```sh\nhello\n```\n");
+    assert_eq!(checked["id"], 2);
+    assert_eq!(checked["status"], 1);
+    assert!(std::fs::read_to_string(&env.md_path)
+        .unwrap()
+        .contains("outdated"));
+}
+
+#[test]
+fn test_lsp_publishes_diagnostics_and_code_actions() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let env = TestEnv::stale();
+    let uri = "file:///docs/test.md";
+    let messages = [
+        serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+        serde_json::json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": {"uri": uri, "languageId": "markdown", "version": 1, "text": "# Doc\n```sh\necho outdated\n```\n"}
+        }}),
+        serde_json::json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/codeAction", "params": {
+            "textDocument": {"uri": uri},
+            "range": {"start": {"line": 2, "character": 0}, "end": {"line": 2, "character": 0}},
+            "context": {"diagnostics": []}
+        }}),
+        serde_json::json!({"jsonrpc": "2.0", "id": 3, "method": "shutdown"}),
+        serde_json::json!({"jsonrpc": "2.0", "method": "exit"}),
+    ];
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_mdcr"))
+        .args(["lsp", "--config", env.cfg_path.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for message in messages {
+        let body = message.to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+    }
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let responses: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout)
+        .split("Content-Length: ")
+        .filter_map(|message| message.split_once("\r\n\r\n"))
+        .map(|(_, body)| serde_json::from_str(body).unwrap())
+        .collect();
+    let diagnostics = responses
+        .iter()
+        .find(|r| r["method"] == "textDocument/publishDiagnostics")
+        .unwrap();
+    assert_eq!(
+        diagnostics["params"]["diagnostics"][0]["range"]["start"]["line"],
+        1
+    );
+    assert_eq!(diagnostics["params"]["diagnostics"][0]["code"], "shell");
+
+    let actions = responses.iter().find(|r| r["id"] == 2).unwrap();
+    assert_eq!(
+        actions["result"][0]["edit"]["changes"][uri][0]["newText"],
+        "```sh\nhello\n```\n"
+    );
+}
+
+// Review ----------------------------------------------------------------------
+
+#[test]
+fn test_review_without_mismatching_blocks() {
+    let env = TestEnv::new(
+        "hello",
+        "sh",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+
+    let output = env.run(&[
+        "review",
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "No mismatching blocks\n"
+    );
+}
+
+#[test]
+fn test_review_applies_and_skips_the_changes_from_the_keys() {
+    let markdown = "```sh\necho first\n```\n\n```sh\necho second\n```\n";
+    let env = TestEnv::from_raw_markdown(
+        markdown,
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+        "#,
+    );
+    let args = [
+        "review",
+        env.md_path.to_str().unwrap(),
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+    ];
+
+    // Quitting with `Esc` writes nothing
+    let output = env.run_with_stdin(&args, "a\x1b");
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_to_string(&env.md_path).unwrap(), markdown);
+
+    let output = env.run_with_stdin(&args, "asq");
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n\n```sh\necho second\n```\n"
+    );
+    let screen = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        screen.contains("1 to apply, 1 skipped, 0 pending"),
+        "{screen}"
+    );
+    assert!(screen.contains("apply"));
+    assert!(screen.contains("skip"));
+}
+
+#[test]
+fn test_review_shows_the_block_and_the_output_side_by_side() {
+    let env = TestEnv::stale();
+
+    let output = env.run_with_stdin(
+        &[
+            "review",
+            env.md_path.to_str().unwrap(),
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+        "",
+    );
+
+    assert!(output.status.success());
+    let screen = String::from_utf8_lossy(&output.stdout);
+    // Whether a line of the screen shows `left` on the left of `right`
+    let row = |left: &str, right: &str| {
+        screen.lines().any(|line| {
+            line.find(left)
+                .is_some_and(|start| line.rfind(right).is_some_and(|end| end > start))
+        })
+    };
+    assert!(row("Current", "Command output"), "{screen}");
+    assert!(row("```sh", "```sh"));
+    assert!(row("echo outdated", "hello"));
+}
+
+#[test]
+fn test_review_keeps_the_changes_of_the_blocks_that_did_not_fail() {
+    let env = TestEnv::from_raw_markdown(
+        "```sh\necho outdated\n```\n\n```fail\nbroken\n```\n",
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["echo", "hello"]
+
+        [presets.fail]
+        language = "fail"
+        command = ["false"]
+        "#,
+    );
+
+    let output = env.run_with_stdin(
+        &[
+            "review",
+            env.md_path.to_str().unwrap(),
+            "--config",
+            env.cfg_path.to_str().unwrap(),
+        ],
+        "aq",
+    );
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 failed"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Preset `fail` failed in"));
+    assert_eq!(
+        std::fs::read_to_string(&env.md_path).unwrap(),
+        "```sh\nhello\n```\n\n```fail\nbroken\n```\n"
+    );
+}
+
+// Terminal output and logs ----------------------------------------------------
+
+#[test]
+fn test_color_policy_colors_findings_only_when_asked() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh", "-c", "cat {fil}"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+
+    let output = env.run(&["validate-config", "--config", config, "--color", "always"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[\x1b[31m  error\x1b[0m] Preset `shell`"));
+
+    let output = env.run(&["validate-config", "--config", config]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains('\x1b'));
+}
+
+#[test]
+fn test_color_policy_colors_diffs_and_summaries() {
+    let env = TestEnv::stale();
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let root = env.md_path.parent().unwrap().to_str().unwrap();
+    let output = env.run(&[
+        "--dry-run",
+        "--project-root",
+        root,
+        "--color",
+        "always",
+        "--config",
+        config,
+        markdown,
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\x1b[31m-echo outdated\x1b[0m\n"));
+    assert!(stdout.contains("\x1b[32m+hello\x1b[0m\n"));
+    assert!(stdout.contains("\n+++ b/"));
+
+    let args = ["impact", "--preset", "shell", "--config", config, markdown];
+    let output = env.run(&[&args[..], &["--color", "always"]].concat());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\x1b[33m1\x1b[0m of 1 files would change"));
+
+    let output = env.run(&args);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains('\x1b'));
+}
+
+#[test]
+fn test_progress_bar_is_hidden_when_stderr_is_not_a_terminal() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        "--check",
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("1/1 files"));
+}
+
+#[test]
+fn test_json_log_format_writes_one_object_per_line() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        "--check",
+        "--log-format",
+        "json",
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(lines
+        .iter()
+        .any(|line| line["level"] == "WARN"
+            && line["message"] == "shell: 1 blocks stale across 1 files"));
+    assert!(lines.iter().all(|line| line["timestamp"].is_string()));
+}
+
+#[test]
+fn test_log_file_holds_the_trace_log() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+    let log_file = env.md_path.with_file_name("mdcr.log");
+
+    let output = env.run(&[
+        "--config",
+        env.cfg_path.to_str().unwrap(),
+        "--check",
+        "--log-file",
+        log_file.to_str().unwrap(),
+        env.md_path.to_str().unwrap(),
+    ]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("DEBUG"));
+    let log = std::fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains("DEBUG mdcr::runner] Processing file"));
+    assert!(log.contains("WARN  mdcr] shell: 1 blocks stale across 1 files"));
+}
+
+#[test]
+fn test_verbosity_short_flags() {
+    let env = TestEnv::outdated(
+        r#"
+        [presets.shell]
+        language = "sh"
+        command = ["sh"]
+        "#,
+    );
+    let config = env.cfg_path.to_str().unwrap();
+    let markdown = env.md_path.to_str().unwrap();
+
+    let output = env.run(&["--config", config, "--check", "-vv", markdown]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("DEBUG mdcr::runner] Processing file"));
+
+    let output = env.run(&["--config", config, "--check", "-q", markdown]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("WARN"));
+}